@@ -1,4 +1,5 @@
 pub mod common;
 pub mod series;
+pub mod tags;
 pub mod user;
 pub mod work;
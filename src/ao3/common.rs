@@ -1,13 +1,22 @@
-use crate::{ao3::user::User, config::Config};
+use crate::ao3::tags::TagResolver;
+use crate::ao3::user::User;
+use crate::config::{Config, CrossoverPolicy};
 
 use anyhow::{Error, Result};
 use enum_iterator::Sequence;
+use regex::{Regex, RegexBuilder};
 use reqwest;
 use scraper::{Html, Selector};
-use std::collections::HashSet;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use strum_macros::{Display, EnumString};
+use unicode_normalization::UnicodeNormalization;
 
-#[derive(Debug, EnumString, PartialEq, Eq, Hash, Display, Sequence, Clone, Copy)]
+// AO3's names for the formats, which configs and saved libraries are written with
+#[allow(clippy::upper_case_acronyms)]
+#[derive(
+    Debug, EnumString, PartialEq, Eq, Hash, Display, Sequence, Clone, Copy, Serialize, Deserialize,
+)]
 pub enum DownloadFormat {
     AZW3,
     EPUB,
@@ -16,6 +25,35 @@ pub enum DownloadFormat {
     HTML,
 }
 
+/// AO3's ratings, as shown on works and used in config
+#[derive(Debug, EnumString, PartialEq, Eq, Display, Clone, Copy, Serialize, Deserialize)]
+pub enum Rating {
+    #[strum(serialize = "General Audiences")]
+    #[serde(rename = "General Audiences")]
+    General,
+    #[strum(serialize = "Teen And Up Audiences")]
+    #[serde(rename = "Teen And Up Audiences")]
+    Teen,
+    Mature,
+    Explicit,
+    #[strum(serialize = "Not Rated")]
+    #[serde(rename = "Not Rated")]
+    NotRated,
+}
+
+impl Rating {
+    /// Whether the rating is no higher than `max`, works that aren't rated could be anything so count as Explicit
+    pub fn is_within(self, max: Rating) -> bool {
+        let level = |rating| match rating {
+            Rating::General => 0,
+            Rating::Teen => 1,
+            Rating::Mature => 2,
+            Rating::Explicit | Rating::NotRated => 3,
+        };
+        level(self) <= level(max)
+    }
+}
+
 pub fn get_page(id: &str, page: Option<u8>, user: Option<&User>) -> Result<Html> {
     let url = if let Some(i) = page {
         format!("https://archiveofourown.org/series/{}?page={}", id, i)
@@ -53,45 +91,338 @@ pub fn get_page(id: &str, page: Option<u8>, user: Option<&User>) -> Result<Html>
     Ok(html_content)
 }
 
-pub fn filter_fandoms(fandoms: &Vec<String>, config: &Config) -> String {
-    let mut mapped_fandoms: HashSet<String> = HashSet::from_iter(fandoms.to_owned());
+const GLOB_PREFIX: &str = "glob:";
+const DEFAULT_CROSSOVER_FOLDER: &str = "Multiple";
+const DEFAULT_CROSSOVER_SEPARATOR: &str = " x ";
+const REGEX_PREFIX: &str = "regex:";
 
-    for fandom in fandoms {
-        if config.fandom_map.contains_key(fandom) {
-            mapped_fandoms.remove(fandom);
-            mapped_fandoms.insert(config.fandom_map.get(fandom).unwrap().to_string());
+/// How a `fandom_map` key is matched, listed in the order they are tried
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum FandomPatternKind {
+    Exact,
+    Glob,
+    Regex,
+}
+
+#[derive(Debug)]
+pub struct FandomRule {
+    kind: FandomPatternKind,
+    key: String,
+    pattern: Regex,
+    pub fandom: String,
+}
+
+/// `fandom_map` compiled into rules once when the config is read, an invalid `regex:` key is a config error
+#[derive(Debug, Default, Deserialize)]
+#[serde(try_from = "HashMap<String, String>")]
+pub struct FandomMap {
+    rules: Vec<FandomRule>,
+}
+
+impl FandomMap {
+    /// The rules in the order they are tried: exact names, then `glob:` patterns, then `regex:` patterns.
+    /// Within a kind longer keys go first so the more specific rule wins, ties go alphabetically
+    pub fn rules(&self) -> &[FandomRule] {
+        &self.rules
+    }
+}
+
+impl TryFrom<HashMap<String, String>> for FandomMap {
+    type Error = String;
+
+    fn try_from(fandom_map: HashMap<String, String>) -> Result<FandomMap, String> {
+        let mut rules = Vec::new();
+        for (key, fandom) in fandom_map {
+            let (kind, pattern) = if let Some(glob) = key.strip_prefix(GLOB_PREFIX) {
+                let pattern = regex::escape(&normalize_fandom(glob))
+                    .replace(r"\*", ".*")
+                    .replace(r"\?", ".");
+                (FandomPatternKind::Glob, format!("^{}$", pattern))
+            } else if let Some(pattern) = key.strip_prefix(REGEX_PREFIX) {
+                // Lowercasing would turn classes like \D into \d, case is ignored by the regex instead
+                (FandomPatternKind::Regex, pattern.nfkc().collect())
+            } else {
+                let pattern = regex::escape(&normalize_fandom(&key));
+                (FandomPatternKind::Exact, format!("^{}$", pattern))
+            };
+
+            let pattern = RegexBuilder::new(&pattern)
+                .case_insensitive(true)
+                .build()
+                .map_err(|error| format!("invalid fandom_map rule \"{}\": {}", key, error))?;
+            rules.push(FandomRule {
+                kind,
+                key,
+                pattern,
+                fandom,
+            });
+        }
+
+        rules.sort_by(|a, b| {
+            a.kind
+                .cmp(&b.kind)
+                .then(b.key.len().cmp(&a.key.len()))
+                .then(a.key.cmp(&b.key))
+        });
+        Ok(FandomMap { rules })
+    }
+}
+
+/// Unicode compatibility forms and case are ignored when matching fandoms, so "Ｐｅｒｓｏｎａ" matches "persona"
+pub fn normalize_fandom(fandom: &str) -> String {
+    fandom.nfkc().collect::<String>().to_lowercase()
+}
+
+/// One thing `filter_fandoms` did on the way to a work's fandom folder
+#[derive(Debug, Clone, PartialEq)]
+pub enum FandomStep {
+    /// `rule` is the `fandom_map` key that matched
+    Mapped {
+        fandom: String,
+        rule: String,
+        mapped_fandom: String,
+    },
+    /// AO3's tag wrangling led from the fandom through `chain` to `mapped_fandom`
+    Wrangled {
+        fandom: String,
+        chain: Vec<String>,
+        mapped_fandom: String,
+    },
+    /// `fandom_filter` took the fandom out because the work also has `because_of`
+    Filtered { fandom: String, because_of: String },
+    /// The fandoms filtered each other out, so all of them were kept
+    FiltersIgnored,
+    /// More than one fandom was left, so `[crossovers]` chose the folder
+    Crossover {
+        fandoms: Vec<String>,
+        policy: CrossoverPolicy,
+    },
+}
+
+impl std::fmt::Display for FandomStep {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            FandomStep::Mapped {
+                fandom,
+                rule,
+                mapped_fandom,
+            } => write!(
+                f,
+                "mapped {} to {} by fandom_map rule \"{}\"",
+                fandom, mapped_fandom, rule
+            ),
+            FandomStep::Wrangled {
+                fandom,
+                chain,
+                mapped_fandom,
+            } => write!(
+                f,
+                "looked up {} on AO3 ({}), giving {}",
+                fandom,
+                chain.join(" -> "),
+                mapped_fandom
+            ),
+            FandomStep::Filtered { fandom, because_of } => write!(
+                f,
+                "removed {} by fandom_filter, because the work is also in {}",
+                fandom, because_of
+            ),
+            FandomStep::FiltersIgnored => write!(
+                f,
+                "fandom_filter would have removed every fandom, so none were removed"
+            ),
+            FandomStep::Crossover { fandoms, policy } => write!(
+                f,
+                "crossover of {}, decided by the {:?} policy",
+                fandoms.join(", "),
+                policy
+            ),
+        }
+    }
+}
+
+/// How `filter_fandoms` chose a work's fandom folder
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct FandomTrace {
+    /// The fandoms as AO3 lists them
+    pub fandoms: Vec<String>,
+    pub steps: Vec<FandomStep>,
+    /// The folder the work goes in
+    pub fandom: String,
+    /// More folders a copy goes in, only with `policy = "every"`
+    pub crossover_fandoms: Vec<String>,
+    /// Every fandom after `fandom_map` and `fandom_filter`, more than one for a crossover
+    pub mapped_fandoms: Vec<String>,
+}
+
+impl std::fmt::Display for FandomTrace {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "fandoms on AO3: {}", self.fandoms.join(", "))?;
+        for step in &self.steps {
+            write!(f, "\n  {}", step)?;
+        }
+        write!(f, "\nfandom folder: {}", self.fandom)?;
+        for fandom in &self.crossover_fandoms {
+            write!(f, "\n  and a copy in {}", fandom)?;
         }
+        Ok(())
     }
+}
+
+/// The first `fandom_map` rule that matches the fandom
+pub fn map_fandom<'a>(fandom: &str, rules: &'a [FandomRule]) -> Option<&'a FandomRule> {
+    let normalized = normalize_fandom(fandom);
+    rules.iter().find(|rule| rule.pattern.is_match(&normalized))
+}
 
-    let mut mapped_and_filtered_fandoms = mapped_fandoms.clone();
+/// Falls back to AO3's tag wrangling for a fandom `fandom_map` doesn't cover, if `[tag_wrangling]` is set.
+/// The first tag up the chain `fandom_map` does cover is mapped, otherwise the most general one is used
+fn resolve_fandom(
+    fandom: &str,
+    rules: &[FandomRule],
+    config: &Config,
+    resolver: &mut TagResolver,
+) -> Option<(Vec<String>, String)> {
+    let options = config.tag_wrangling.as_ref()?;
+    let chain = resolver.resolve(fandom, options);
+    let mapped_fandom = chain
+        .iter()
+        .find_map(|tag| map_fandom(tag, rules))
+        .map(|rule| rule.fandom.clone())
+        .or_else(|| chain.last().cloned())?;
+    Some((chain, mapped_fandom))
+}
+
+/// The fandoms left after `fandom_map` and `fandom_filter`, in the order AO3 lists them
+fn get_filtered_fandoms(
+    fandoms: &[String],
+    config: &Config,
+    resolver: &mut TagResolver,
+    steps: &mut Vec<FandomStep>,
+) -> Vec<String> {
+    let rules = config.fandom_map.rules();
+    let mut mapped_fandoms: Vec<String> = Vec::new();
 
+    for fandom in fandoms {
+        let mapped_fandom = if let Some(rule) = map_fandom(fandom, rules) {
+            steps.push(FandomStep::Mapped {
+                fandom: fandom.clone(),
+                rule: rule.key.clone(),
+                mapped_fandom: rule.fandom.clone(),
+            });
+            rule.fandom.clone()
+        } else if let Some((chain, mapped_fandom)) = resolve_fandom(fandom, rules, config, resolver)
+        {
+            if &mapped_fandom != fandom {
+                steps.push(FandomStep::Wrangled {
+                    fandom: fandom.clone(),
+                    chain,
+                    mapped_fandom: mapped_fandom.clone(),
+                });
+            }
+            mapped_fandom
+        } else {
+            fandom.clone()
+        };
+        if !mapped_fandoms.contains(&mapped_fandom) {
+            mapped_fandoms.push(mapped_fandom);
+        }
+    }
+
+    // Every fandom that gets removed, along with the first fandom that removes it
+    let mut removals: Vec<(&String, &String)> = Vec::new();
     for fandom in &mapped_fandoms {
-        if config.fandom_filter.contains_key(fandom) {
-            if let Some(filter) = config.fandom_filter.get(fandom) {
-                for fandom_to_remove in filter {
-                    if mapped_fandoms.contains(fandom_to_remove) {
-                        mapped_and_filtered_fandoms.remove(fandom_to_remove);
-                    }
-                }
+        for fandom_to_remove in config.fandom_filter.get(fandom).into_iter().flatten() {
+            if mapped_fandoms.contains(fandom_to_remove)
+                && !removals
+                    .iter()
+                    .any(|(removed, _)| *removed == fandom_to_remove)
+            {
+                removals.push((fandom_to_remove, fandom));
             }
         }
     }
+    let filtered_fandoms: Vec<String> = mapped_fandoms
+        .iter()
+        .filter(|fandom| !removals.iter().any(|(removed, _)| removed == fandom))
+        .cloned()
+        .collect();
 
-    if mapped_and_filtered_fandoms.len() > 1 {
-        "Multiple".to_string()
-    } else {
-        mapped_and_filtered_fandoms
+    // Fandoms that filter each other out would leave nothing, so keep them all and let the crossover policy decide
+    if filtered_fandoms.is_empty() {
+        steps.push(FandomStep::FiltersIgnored);
+        return mapped_fandoms;
+    }
+    steps.extend(
+        removals
+            .into_iter()
+            .map(|(removed, because_of)| FandomStep::Filtered {
+                fandom: removed.clone(),
+                because_of: because_of.clone(),
+            }),
+    );
+    filtered_fandoms
+}
+
+/// The fandom folder a work goes in, with crossovers placed by `[crossovers]`, and how it was chosen.
+/// `resolver` is only asked when `[tag_wrangling]` is set, the caller saves it once it's done
+pub fn filter_fandoms(
+    fandoms: &[String],
+    config: &Config,
+    resolver: &mut TagResolver,
+) -> FandomTrace {
+    let mut steps = Vec::new();
+    let filtered_fandoms = get_filtered_fandoms(fandoms, config, resolver, &mut steps);
+    let mut trace = FandomTrace {
+        fandoms: fandoms.to_vec(),
+        ..Default::default()
+    };
+
+    trace.mapped_fandoms = filtered_fandoms.clone();
+    if filtered_fandoms.len() <= 1 {
+        trace.steps = steps;
+        trace.fandom = filtered_fandoms.into_iter().next().unwrap_or_default();
+        return trace;
+    }
+
+    let options = config.crossovers.clone().unwrap_or_default();
+    let policy = options.policy.unwrap_or_default();
+    trace.fandom = match policy {
+        CrossoverPolicy::Folder => options
+            .folder
+            .unwrap_or_else(|| DEFAULT_CROSSOVER_FOLDER.to_owned()),
+        CrossoverPolicy::Priority => options
+            .fandom_priority
             .iter()
-            .next()
-            .unwrap()
-            .to_string()
+            .find_map(|priority| {
+                filtered_fandoms
+                    .iter()
+                    .find(|fandom| normalize_fandom(fandom) == normalize_fandom(priority))
+            })
+            .unwrap_or(&filtered_fandoms[0])
+            .clone(),
+        CrossoverPolicy::First | CrossoverPolicy::Every => filtered_fandoms[0].clone(),
+        CrossoverPolicy::Joined => filtered_fandoms.join(
+            options
+                .separator
+                .as_deref()
+                .unwrap_or(DEFAULT_CROSSOVER_SEPARATOR),
+        ),
+    };
+    if policy == CrossoverPolicy::Every {
+        trace.crossover_fandoms = filtered_fandoms[1..].to_vec();
     }
+    steps.push(FandomStep::Crossover {
+        fandoms: filtered_fandoms,
+        policy,
+    });
+    trace.steps = steps;
+    trace
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::collections::HashMap;
 
     #[test]
     fn map() {
@@ -100,22 +431,26 @@ mod tests {
             ao3_username: Some("test".to_owned()),
             ao3_password: Some("test".to_owned()),
             devices: Vec::new(),
-            fandom_map: HashMap::from([
+            fandom_map: FandomMap::try_from(HashMap::from([
                 ("Fandom 1 the big boy".to_owned(), "Fandom 1".to_owned()),
                 ("Fandom 1 TBB".to_owned(), "Fandom 1".to_owned()),
                 (
                     "Fandom 2 the big boy returns".to_owned(),
                     "Fandom 2".to_owned(),
                 ),
-            ]),
+            ]))
+            .unwrap(),
             fandom_filter: HashMap::new(),
+            ..Default::default()
         };
 
         assert_eq!(
             filter_fandoms(
-                &vec!["Fandom 1 the big boy".to_owned(), "Fandom 1 TBB".to_owned()],
-                &config
-            ),
+                &["Fandom 1 the big boy".to_owned(), "Fandom 1 TBB".to_owned()],
+                &config,
+                &mut TagResolver::default()
+            )
+            .fandom,
             "Fandom 1"
         );
     }
@@ -127,22 +462,26 @@ mod tests {
             ao3_username: Some("test".to_owned()),
             ao3_password: Some("test".to_owned()),
             devices: Vec::new(),
-            fandom_map: HashMap::from([
+            fandom_map: FandomMap::try_from(HashMap::from([
                 ("Fandom 1 the big boy".to_owned(), "Fandom 1".to_owned()),
                 ("Fandom 1 TBB".to_owned(), "Fandom 1".to_owned()),
                 (
                     "Fandom 2 the big boy returns".to_owned(),
                     "Fandom 2".to_owned(),
                 ),
-            ]),
+            ]))
+            .unwrap(),
             fandom_filter: HashMap::new(),
+            ..Default::default()
         };
 
         assert_eq!(
             filter_fandoms(
-                &vec!["Fandom 4 how is big boy possibly back once again".to_owned()],
-                &config
-            ),
+                &["Fandom 4 how is big boy possibly back once again".to_owned()],
+                &config,
+                &mut TagResolver::default()
+            )
+            .fandom,
             "Fandom 4 how is big boy possibly back once again"
         );
     }
@@ -154,15 +493,21 @@ mod tests {
             ao3_username: Some("test".to_owned()),
             ao3_password: Some("test".to_owned()),
             devices: Vec::new(),
-            fandom_map: HashMap::new(),
+            fandom_map: FandomMap::default(),
             fandom_filter: HashMap::from([
                 ("Fandom 1".to_owned(), vec!["Fandom 2".to_owned()]),
                 ("Fandom 2".to_owned(), vec!["Fandom 3".to_owned()]),
             ]),
+            ..Default::default()
         };
 
         assert_eq!(
-            filter_fandoms(&vec!["Fandom 1".to_owned(), "Fandom 2".to_owned()], &config),
+            filter_fandoms(
+                &["Fandom 1".to_owned(), "Fandom 2".to_owned()],
+                &config,
+                &mut TagResolver::default()
+            )
+            .fandom,
             "Fandom 1"
         );
     }
@@ -174,22 +519,25 @@ mod tests {
             ao3_username: Some("test".to_owned()),
             ao3_password: Some("test".to_owned()),
             devices: Vec::new(),
-            fandom_map: HashMap::new(),
+            fandom_map: FandomMap::default(),
             fandom_filter: HashMap::from([
                 ("Fandom 1".to_owned(), vec!["Fandom 2".to_owned()]),
                 ("Fandom 2".to_owned(), vec!["Fandom 3".to_owned()]),
             ]),
+            ..Default::default()
         };
 
         assert_eq!(
             filter_fandoms(
-                &vec![
+                &[
                     "Fandom 1".to_owned(),
                     "Fandom 2".to_owned(),
                     "Fandom 3".to_owned()
                 ],
-                &config
-            ),
+                &config,
+                &mut TagResolver::default()
+            )
+            .fandom,
             "Fandom 1"
         );
     }
@@ -201,29 +549,33 @@ mod tests {
             ao3_username: Some("test".to_owned()),
             ao3_password: Some("test".to_owned()),
             devices: Vec::new(),
-            fandom_map: HashMap::from([
+            fandom_map: FandomMap::try_from(HashMap::from([
                 ("Fandom 1 the big boy".to_owned(), "Fandom 1".to_owned()),
                 ("Fandom 1 TBB".to_owned(), "Fandom 1".to_owned()),
                 (
                     "Fandom 2 the big boy returns".to_owned(),
                     "Fandom 2".to_owned(),
                 ),
-            ]),
+            ]))
+            .unwrap(),
             fandom_filter: HashMap::from([
                 ("Fandom 1".to_owned(), vec!["Fandom 2".to_owned()]),
                 ("Fandom 2".to_owned(), vec!["Fandom 3".to_owned()]),
             ]),
+            ..Default::default()
         };
 
         assert_eq!(
             filter_fandoms(
-                &vec![
+                &[
                     "Fandom 1 the big boy".to_owned(),
                     "Fandom 1 TBB".to_owned(),
                     "Fandom 2 the big boy returns".to_owned()
                 ],
-                &config
-            ),
+                &config,
+                &mut TagResolver::default()
+            )
+            .fandom,
             "Fandom 1"
         );
     }
@@ -235,7 +587,7 @@ mod tests {
             ao3_username: Some("test".to_owned()),
             ao3_password: Some("test".to_owned()),
             devices: Vec::new(),
-            fandom_map: HashMap::from([
+            fandom_map: FandomMap::try_from(HashMap::from([
                 ("Fandom 1 the big boy".to_owned(), "Fandom 1".to_owned()),
                 ("Fandom 1 TBB".to_owned(), "Fandom 1".to_owned()),
                 (
@@ -246,24 +598,226 @@ mod tests {
                     "Fandom 3 god lord big boy is back".to_owned(),
                     "Fandom 3".to_owned(),
                 ),
-            ]),
+            ]))
+            .unwrap(),
             fandom_filter: HashMap::from([
                 ("Fandom 1".to_owned(), vec!["Fandom 2".to_owned()]),
                 ("Fandom 2".to_owned(), vec!["Fandom 3".to_owned()]),
             ]),
+            ..Default::default()
         };
 
         assert_eq!(
             filter_fandoms(
-                &vec![
+                &[
                     "Fandom 1 the big boy".to_owned(),
                     "Fandom 1 TBB".to_owned(),
                     "Fandom 2 the big boy returns".to_owned(),
                     "Fandom 3 god lord big boy is back".to_owned()
                 ],
-                &config
-            ),
+                &config,
+                &mut TagResolver::default()
+            )
+            .fandom,
             "Fandom 1"
         );
     }
+
+    #[test]
+    fn map_patterns() {
+        let config = Config {
+            download_path: "some folder/some file".to_owned(),
+            ao3_username: Some("test".to_owned()),
+            ao3_password: Some("test".to_owned()),
+            devices: Vec::new(),
+            fandom_map: FandomMap::try_from(HashMap::from([
+                ("glob:Persona *".to_owned(), "Persona".to_owned()),
+                (
+                    r"regex:^Persona \d".to_owned(),
+                    "Persona (regex)".to_owned(),
+                ),
+                ("Persona 5 Royal".to_owned(), "Persona 5".to_owned()),
+                ("glob:Persona 4*".to_owned(), "Persona 4".to_owned()),
+                (r"regex:\(Video Games?\)$".to_owned(), "Games".to_owned()),
+            ]))
+            .unwrap(),
+            fandom_filter: HashMap::new(),
+            ..Default::default()
+        };
+
+        // Exact names beat patterns
+        assert_eq!(
+            filter_fandoms(
+                &["Persona 5 Royal".to_owned()],
+                &config,
+                &mut TagResolver::default()
+            )
+            .fandom,
+            "Persona 5"
+        );
+        // Globs beat regexes, and the longer glob is tried first
+        assert_eq!(
+            filter_fandoms(
+                &["Persona 4 Golden".to_owned()],
+                &config,
+                &mut TagResolver::default()
+            )
+            .fandom,
+            "Persona 4"
+        );
+        assert_eq!(
+            filter_fandoms(
+                &["Persona 3 Portable".to_owned()],
+                &config,
+                &mut TagResolver::default()
+            )
+            .fandom,
+            "Persona"
+        );
+        assert_eq!(
+            filter_fandoms(
+                &["Hades (Video Game)".to_owned()],
+                &config,
+                &mut TagResolver::default()
+            )
+            .fandom,
+            "Games"
+        );
+    }
+
+    #[test]
+    fn map_ignores_case_and_unicode_forms() {
+        let config = Config {
+            download_path: "some folder/some file".to_owned(),
+            ao3_username: Some("test".to_owned()),
+            ao3_password: Some("test".to_owned()),
+            devices: Vec::new(),
+            fandom_map: FandomMap::try_from(HashMap::from([
+                ("Pokémon".to_owned(), "Pokemon".to_owned()),
+                ("glob:ＰＥＲＳＯＮＡ*".to_owned(), "Persona".to_owned()),
+            ]))
+            .unwrap(),
+            fandom_filter: HashMap::new(),
+            ..Default::default()
+        };
+
+        // "e" followed by a combining accent is the same as "é" once normalized
+        assert_eq!(
+            filter_fandoms(
+                &["POKE\u{301}MON".to_owned()],
+                &config,
+                &mut TagResolver::default()
+            )
+            .fandom,
+            "Pokemon"
+        );
+        assert_eq!(
+            filter_fandoms(
+                &["persona 5".to_owned()],
+                &config,
+                &mut TagResolver::default()
+            )
+            .fandom,
+            "Persona"
+        );
+    }
+
+    #[test]
+    fn invalid_regex_rules_are_a_config_error() {
+        let error = toml::from_str::<Config>(
+            r#"
+            download_path = "downloads"
+
+            [fandom_map]
+            "regex:[" = "Broken"
+
+            [fandom_filter]
+            "#,
+        )
+        .unwrap_err();
+
+        assert!(error
+            .to_string()
+            .contains("invalid fandom_map rule \"regex:[\""));
+    }
+
+    #[test]
+    fn crossover_policies() {
+        let fandoms = [
+            "Fandom 2".to_owned(),
+            "Fandom 1".to_owned(),
+            "Fandom 3".to_owned(),
+        ];
+        let config_with = |crossovers: &str| Config {
+            download_path: "some folder/some file".to_owned(),
+            crossovers: Some(toml::from_str(crossovers).unwrap()),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            filter_fandoms(&fandoms, &Config::default(), &mut TagResolver::default()).fandom,
+            "Multiple"
+        );
+        assert_eq!(
+            filter_fandoms(
+                &fandoms,
+                &config_with("folder = \"Crossovers\""),
+                &mut TagResolver::default()
+            )
+            .fandom,
+            "Crossovers"
+        );
+        let priority = config_with(
+            r#"
+            policy = "priority"
+            fandom_priority = ["Fandom 4", "fandom 3"]
+            "#,
+        );
+        assert_eq!(
+            filter_fandoms(&fandoms, &priority, &mut TagResolver::default()).fandom,
+            "Fandom 3"
+        );
+        assert_eq!(
+            filter_fandoms(
+                &fandoms,
+                &config_with("policy = \"priority\""),
+                &mut TagResolver::default()
+            )
+            .fandom,
+            "Fandom 2"
+        );
+        assert_eq!(
+            filter_fandoms(
+                &fandoms,
+                &config_with("policy = \"first\""),
+                &mut TagResolver::default()
+            )
+            .fandom,
+            "Fandom 2"
+        );
+        assert_eq!(
+            filter_fandoms(
+                &fandoms,
+                &config_with("policy = \"joined\""),
+                &mut TagResolver::default()
+            )
+            .fandom,
+            "Fandom 2 x Fandom 1 x Fandom 3"
+        );
+
+        let every = config_with("policy = \"every\"");
+        assert_eq!(
+            filter_fandoms(&fandoms, &every, &mut TagResolver::default()).fandom,
+            "Fandom 2"
+        );
+        assert_eq!(
+            filter_fandoms(&fandoms, &every, &mut TagResolver::default()).crossover_fandoms,
+            vec!["Fandom 1".to_owned(), "Fandom 3".to_owned()]
+        );
+        assert!(
+            filter_fandoms(&fandoms, &Config::default(), &mut TagResolver::default())
+                .crossover_fandoms
+                .is_empty()
+        );
+    }
 }
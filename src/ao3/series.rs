@@ -1,13 +1,15 @@
 use crate::ao3::common::{filter_fandoms, get_page, DownloadFormat};
+use crate::ao3::tags::TagResolver;
 use crate::ao3::user::User;
 use crate::ao3::work::Work;
 use crate::config::Config;
+use crate::layout::Layout;
 
 use anyhow::Result;
 use scraper::Selector;
 use std::collections::HashSet;
-use std::fs::create_dir;
-use std::path::Path;
+use std::fs::create_dir_all;
+use std::path::{Path, PathBuf};
 
 pub struct Series {
     pub id: String,
@@ -24,7 +26,8 @@ pub struct Series {
     //These are gotten from parsing all the works in the series
     pub works: Vec<Work>,
     authors: HashSet<String>,
-    fandoms: HashSet<String>,
+    /// In the order they were first seen, so crossover policies pick the same fandom every time
+    fandoms: Vec<String>,
     pub filtered_fandom: String,
 }
 
@@ -52,7 +55,12 @@ impl std::fmt::Display for Series {
 }
 
 impl Series {
-    pub fn parse_series(id: &str, user: Option<&User>, config: &Config) -> Result<Series> {
+    pub fn parse_series(
+        id: &str,
+        user: Option<&User>,
+        config: &Config,
+        resolver: &mut TagResolver,
+    ) -> Result<Series> {
         println!("Loading series {}", id);
         let mut document = get_page(id, Some(1), user).expect("Failed to get the requested page");
 
@@ -155,7 +163,7 @@ impl Series {
 
         let mut works = Vec::new();
         let mut authors = HashSet::new();
-        let mut fandoms = HashSet::new();
+        let mut fandoms: Vec<String> = Vec::new();
 
         for page in 1..=num_series_pages {
             if page > 1 {
@@ -170,8 +178,12 @@ impl Series {
                     .skip(5)
                     .collect::<String>();
                 println!("  Found work {}", work_id);
-                let parsed_work = Work::parse_work_from_blurb(work, &title, config)?;
-                fandoms.extend(parsed_work.fandoms.clone());
+                let parsed_work = Work::parse_work_from_blurb(work, &title, config, resolver)?;
+                for fandom in &parsed_work.fandoms {
+                    if !fandoms.contains(fandom) {
+                        fandoms.push(fandom.clone());
+                    }
+                }
                 authors.insert(parsed_work.author.clone());
                 works.push(parsed_work);
             }
@@ -190,18 +202,29 @@ impl Series {
             num_bookmarks,
             works,
             authors,
-            fandoms: fandoms.clone(),
-            filtered_fandom: filter_fandoms(&Vec::from_iter(fandoms), config),
+            filtered_fandom: filter_fandoms(&fandoms, config, resolver).fandom,
+            fandoms,
         })
     }
 
-    pub fn download(&self, path: &Path, format: DownloadFormat) -> std::io::Result<()> {
+    /// Downloads every work in the series with `Work::download_for_devices`, returning the downloads each work got
+    pub fn download_for_devices(
+        &self,
+        path: &Path,
+        device_formats: &[Vec<DownloadFormat>],
+        layout: &Layout,
+    ) -> std::io::Result<Vec<Vec<(DownloadFormat, PathBuf)>>> {
         let series_path = path.join(&self.title);
-        create_dir(&series_path)?;
-        for work in &self.works {
-            let _ = work.download(&series_path, format, Some(&self.id));
-            println!()
-        };
-        Ok(())
+        create_dir_all(&series_path)?;
+        Ok(self
+            .works
+            .iter()
+            .map(|work| {
+                let downloads =
+                    work.download_for_devices(&series_path, device_formats, Some(&self.id), layout);
+                println!();
+                downloads
+            })
+            .collect())
     }
 }
@@ -0,0 +1,417 @@
+use crate::config::{Config, TagWranglingOptions};
+
+use anyhow::Result;
+use reqwest::header::RETRY_AFTER;
+use reqwest::{StatusCode, Url};
+use scraper::{Html, Selector};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const TAG_CACHE_FILENAME: &str = "tags.toml";
+const DEFAULT_CACHE_DAYS: u64 = 30;
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+const DEFAULT_REQUEST_DELAY_SECONDS: u64 = 2;
+/// How many times a request AO3 turned away with 429 Too Many Requests is tried again
+const MAX_RETRIES: u32 = 3;
+/// How long to wait after a 429 that doesn't say, doubled after each one
+const DEFAULT_RETRY_SECONDS: u64 = 30;
+/// Synonyms and metatags could point at each other, this is as many steps as we follow
+const MAX_STEPS: usize = 10;
+
+/// What AO3's tag wranglers say about a tag
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct TagInfo {
+    /// The canonical tag this one was made a synonym of
+    pub synonym_of: Option<String>,
+    /// More general tags this one belongs under, e.g. "Fallout (Video Games)" for "Fallout 4 (Video Game)"
+    pub metatags: Vec<String>,
+    /// Unix timestamp of when AO3 was last asked
+    pub fetched_at: u64,
+    /// AO3 had no page for the tag, so it isn't asked again until the entry is out of date
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub missing: bool,
+}
+
+/// Looks tags up on AO3, keeping what it finds in `download_path/tags.toml`
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct TagResolver {
+    tags: BTreeMap<String, TagInfo>,
+    #[serde(skip)]
+    changed: bool,
+    /// When the last request to AO3 was sent, so the next one can wait its turn
+    #[serde(skip)]
+    last_request: Option<Instant>,
+}
+
+impl TagResolver {
+    pub fn load(config: &Config) -> Result<TagResolver> {
+        let cache_path = Path::new(&config.download_path).join(TAG_CACHE_FILENAME);
+        if !cache_path.exists() {
+            return Ok(TagResolver::default());
+        }
+        Ok(toml::from_str(&fs::read_to_string(cache_path)?)?)
+    }
+
+    /// Only writes the cache when something new was looked up
+    pub fn save(&self, config: &Config) -> Result<()> {
+        if !self.changed {
+            return Ok(());
+        }
+        let cache_path = Path::new(&config.download_path).join(TAG_CACHE_FILENAME);
+        fs::write(cache_path, toml::to_string(self)?)?;
+        Ok(())
+    }
+
+    /// The tag followed by its canonical synonym and then each metatag above it, the most general last.
+    /// Stops early at any tag AO3 couldn't be asked about
+    pub fn resolve(&mut self, tag: &str, options: &TagWranglingOptions) -> Vec<String> {
+        let mut chain = vec![tag.to_owned()];
+        let mut seen = HashSet::from([tag.to_owned()]);
+
+        while chain.len() <= MAX_STEPS {
+            let Some(info) = self.get_tag_info(chain.last().unwrap(), options) else {
+                break;
+            };
+            let next = match (info.synonym_of, info.metatags.into_iter().next()) {
+                (Some(canonical), _) => canonical,
+                (None, Some(metatag)) if options.follow_metatags.unwrap_or(true) => metatag,
+                _ => break,
+            };
+            if !seen.insert(next.clone()) {
+                break;
+            }
+            chain.push(next);
+        }
+
+        chain
+    }
+
+    fn get_tag_info(&mut self, tag: &str, options: &TagWranglingOptions) -> Option<TagInfo> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |now| now.as_secs());
+        let max_age = options
+            .cache_days
+            .unwrap_or(DEFAULT_CACHE_DAYS)
+            .saturating_mul(SECONDS_PER_DAY);
+
+        let cached = self.tags.get(tag).cloned();
+        if let Some(info) = cached.as_ref() {
+            if info.fetched_at.saturating_add(max_age) > now {
+                return Some(info.clone()).filter(|info| !info.missing);
+            }
+        }
+
+        match self.fetch_tag_info(tag, options) {
+            Ok(info) => {
+                let info = TagInfo {
+                    fetched_at: now,
+                    ..info.unwrap_or(TagInfo {
+                        missing: true,
+                        ..Default::default()
+                    })
+                };
+                self.tags.insert(tag.to_owned(), info.clone());
+                self.changed = true;
+                Some(info).filter(|info| !info.missing)
+            }
+            Err(error) => {
+                eprintln!("Failed to look up the tag {} on AO3: {}", tag, error);
+                // Out of date is better than nothing
+                cached.filter(|info| !info.missing)
+            }
+        }
+    }
+
+    /// `None` when AO3 has no page for the tag, or the page isn't a tag's
+    fn fetch_tag_info(
+        &mut self,
+        tag: &str,
+        options: &TagWranglingOptions,
+    ) -> Result<Option<TagInfo>> {
+        let Some(page) = self.fetch_page(get_tag_url(tag), options)? else {
+            return Ok(None);
+        };
+        Ok(parse_tag_page(&Html::parse_document(&page)))
+    }
+
+    /// The page's HTML, or `None` for a 404. Waits `request_delay_seconds` between requests,
+    /// and backs off when AO3 answers 429 Too Many Requests
+    fn fetch_page(&mut self, url: Url, options: &TagWranglingOptions) -> Result<Option<String>> {
+        let request_delay = Duration::from_secs(
+            options
+                .request_delay_seconds
+                .unwrap_or(DEFAULT_REQUEST_DELAY_SECONDS),
+        );
+
+        let mut attempt = 0;
+        loop {
+            if let Some(last_request) = self.last_request {
+                thread::sleep(request_delay.saturating_sub(last_request.elapsed()));
+            }
+            self.last_request = Some(Instant::now());
+
+            let response = reqwest::blocking::get(url.clone())?;
+            match response.status() {
+                StatusCode::NOT_FOUND => return Ok(None),
+                StatusCode::TOO_MANY_REQUESTS if attempt < MAX_RETRIES => {
+                    let retry_after = get_retry_after(&response, attempt);
+                    eprintln!(
+                        "AO3 asked us to slow down, waiting {} seconds",
+                        retry_after.as_secs()
+                    );
+                    thread::sleep(retry_after);
+                    attempt += 1;
+                }
+                _ => return Ok(Some(response.error_for_status()?.text()?)),
+            }
+        }
+    }
+}
+
+/// AO3's `Retry-After` in seconds, or an exponential backoff when it doesn't send one
+fn get_retry_after(response: &reqwest::blocking::Response, attempt: u32) -> Duration {
+    let seconds = response
+        .headers()
+        .get(RETRY_AFTER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse::<u64>().ok())
+        .unwrap_or(DEFAULT_RETRY_SECONDS << attempt);
+    Duration::from_secs(seconds)
+}
+
+/// AO3 swaps characters that can't go in a URL for its own escapes before percent encoding
+fn get_tag_url(tag: &str) -> Url {
+    let escaped_tag = tag
+        .replace('/', "*s*")
+        .replace('&', "*a*")
+        .replace('.', "*d*")
+        .replace('?', "*q*")
+        .replace('#', "*h*");
+    let mut url = Url::parse("https://archiveofourown.org/tags").unwrap();
+    url.path_segments_mut().unwrap().push(&escaped_tag);
+    url
+}
+
+fn parse_tag_page(document: &Html) -> Option<TagInfo> {
+    let heading_selector = Selector::parse("div.tag.home h2.heading").unwrap();
+    let synonym_selector = Selector::parse("div.merger a.tag").unwrap();
+    let metatags_selector = Selector::parse("div.meta a.tag").unwrap();
+
+    document.select(&heading_selector).next()?;
+
+    let synonym_of = document
+        .select(&synonym_selector)
+        .next()
+        .map(|tag| tag.text().collect::<String>().trim().to_owned());
+    let metatags = document
+        .select(&metatags_selector)
+        .map(|tag| tag.text().collect::<String>().trim().to_owned())
+        .collect();
+
+    Some(TagInfo {
+        synonym_of,
+        metatags,
+        ..Default::default()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+
+    /// Answers each request to the listener with the next of `responses`, returning how many requests came in
+    fn http_server(
+        listener: TcpListener,
+        responses: Vec<&'static str>,
+    ) -> thread::JoinHandle<usize> {
+        thread::spawn(move || {
+            let mut requests = 0;
+            for response in responses {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream);
+                let mut line = String::new();
+                while reader.read_line(&mut line).unwrap() > 0 && line != "\r\n" {
+                    line.clear();
+                }
+                requests += 1;
+                reader.get_mut().write_all(response.as_bytes()).unwrap();
+            }
+            requests
+        })
+    }
+
+    #[test]
+    fn parses_tag_pages() {
+        let synonym = Html::parse_document(
+            r#"<div class="tag home profile">
+                <h2 class="heading">Fallout 4</h2>
+                <div class="merger module">
+                  <p>This tag has been made a synonym of <a class="tag" href="/tags/Fallout%204%20(Video%20Game)">Fallout 4 (Video Game)</a></p>
+                </div>
+              </div>"#,
+        );
+        let canonical = Html::parse_document(
+            r#"<div class="tag home profile">
+                <h2 class="heading">Fallout 4 (Video Game)</h2>
+                <div class="parent listbox group">
+                  <h3 class="heading">Parent tags (more general):</h3>
+                  <ul class="tags"><li><a class="tag" href="/tags/Video%20Games">Video Games</a></li></ul>
+                </div>
+                <div class="meta listbox group">
+                  <h3 class="heading">Metatags:</h3>
+                  <ul class="tags"><li><a class="tag" href="/tags/Fallout%20(Video%20Games)">Fallout (Video Games)</a></li></ul>
+                </div>
+              </div>"#,
+        );
+
+        assert_eq!(
+            parse_tag_page(&synonym).unwrap().synonym_of,
+            Some("Fallout 4 (Video Game)".to_owned())
+        );
+        assert_eq!(
+            parse_tag_page(&canonical).unwrap(),
+            TagInfo {
+                synonym_of: None,
+                metatags: vec!["Fallout (Video Games)".to_owned()],
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            parse_tag_page(&Html::parse_document("<p>Error 404</p>")),
+            None
+        );
+    }
+
+    #[test]
+    fn follows_synonyms_and_metatags() {
+        let fresh = u64::MAX / 2;
+        let tag = |synonym_of: Option<&str>, metatags: &[&str]| TagInfo {
+            synonym_of: synonym_of.map(str::to_owned),
+            metatags: metatags.iter().map(|tag| tag.to_string()).collect(),
+            fetched_at: fresh,
+            ..Default::default()
+        };
+        let mut resolver = TagResolver {
+            tags: BTreeMap::from([
+                (
+                    "Fallout 4".to_owned(),
+                    tag(Some("Fallout 4 (Video Game)"), &[]),
+                ),
+                (
+                    "Fallout 4 (Video Game)".to_owned(),
+                    tag(None, &["Fallout (Video Games)"]),
+                ),
+                ("Fallout (Video Games)".to_owned(), tag(None, &[])),
+                // Wrangling mistakes shouldn't send us round in circles
+                ("A".to_owned(), tag(None, &["B"])),
+                ("B".to_owned(), tag(None, &["A"])),
+            ]),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            resolver.resolve("Fallout 4", &TagWranglingOptions::default()),
+            vec![
+                "Fallout 4",
+                "Fallout 4 (Video Game)",
+                "Fallout (Video Games)"
+            ]
+        );
+        assert_eq!(
+            resolver.resolve(
+                "Fallout 4",
+                &TagWranglingOptions {
+                    follow_metatags: Some(false),
+                    ..Default::default()
+                }
+            ),
+            vec!["Fallout 4", "Fallout 4 (Video Game)"]
+        );
+        assert_eq!(
+            resolver.resolve("A", &TagWranglingOptions::default()),
+            vec!["A", "B"]
+        );
+        assert!(!resolver.changed);
+    }
+
+    #[test]
+    fn remembers_tags_ao3_doesnt_have() {
+        let fresh = u64::MAX / 2;
+        let mut resolver = TagResolver {
+            tags: BTreeMap::from([
+                (
+                    "Fallout 4".to_owned(),
+                    TagInfo {
+                        synonym_of: Some("Deleted Tag".to_owned()),
+                        fetched_at: fresh,
+                        ..Default::default()
+                    },
+                ),
+                (
+                    "Deleted Tag".to_owned(),
+                    TagInfo {
+                        fetched_at: fresh,
+                        missing: true,
+                        ..Default::default()
+                    },
+                ),
+            ]),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            resolver.resolve("Fallout 4", &TagWranglingOptions::default()),
+            vec!["Fallout 4", "Deleted Tag"]
+        );
+        assert!(!resolver.changed);
+
+        let loaded: TagResolver = toml::from_str(&toml::to_string(&resolver).unwrap()).unwrap();
+        assert!(loaded.tags["Deleted Tag"].missing);
+        assert!(!loaded.tags["Fallout 4"].missing);
+    }
+
+    #[test]
+    fn waits_out_too_many_requests() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = Url::parse(&format!(
+            "http://{}/tags/Fallout",
+            listener.local_addr().unwrap()
+        ))
+        .unwrap();
+        let server = http_server(
+            listener,
+            vec![
+                "HTTP/1.1 429 Too Many Requests\r\nRetry-After: 0\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                "HTTP/1.1 200 OK\r\nContent-Length: 8\r\nConnection: close\r\n\r\nthe page",
+                "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+            ],
+        );
+        let options = TagWranglingOptions {
+            request_delay_seconds: Some(0),
+            ..Default::default()
+        };
+        let mut resolver = TagResolver::default();
+
+        assert_eq!(
+            resolver.fetch_page(url.clone(), &options).unwrap(),
+            Some("the page".to_owned())
+        );
+        assert_eq!(resolver.fetch_page(url, &options).unwrap(), None);
+        assert_eq!(server.join().unwrap(), 3);
+    }
+
+    #[test]
+    fn escapes_tag_urls() {
+        assert_eq!(
+            get_tag_url("Dungeons & Dragons/Fate.exe").as_str(),
+            "https://archiveofourown.org/tags/Dungeons%20*a*%20Dragons*s*Fate*d*exe"
+        );
+    }
+}
@@ -1,10 +1,10 @@
+use anyhow::{Error, Result};
 use reqwest::blocking::Client;
 use scraper::{Html, Selector};
+use std::collections::HashSet;
 
 pub struct User {
     username: String,
-    password: String,
-    auth_token: String,
     pub client: Client,
 }
 
@@ -33,7 +33,7 @@ impl User {
             ("user[password]", password),
             ("authenticity_token", auth_token),
         ];
-        let login_response = client
+        let _login_response = client
             .post("https://archiveofourown.org/users/login")
             .form(&form_data)
             .send()
@@ -44,9 +44,123 @@ impl User {
 
         Self {
             username: username.to_owned(),
-            password: password.to_owned(),
-            auth_token: auth_token.to_owned(),
             client,
         }
     }
+
+    /// Fails rather than returning fewer bookmarks than there are, since works missing from the set can be deleted
+    pub fn get_bookmarked_work_ids(&self) -> Result<HashSet<String>> {
+        println!("Loading bookmarks for {}", self.username);
+        let mut work_ids = HashSet::new();
+
+        for page in 1.. {
+            let url = format!(
+                "https://archiveofourown.org/users/{}/bookmarks?page={}",
+                self.username, page
+            );
+            let response = self.client.get(url).send()?.error_for_status()?;
+            // AO3 sends anyone whose session has run out to the login page
+            if response.url().path().starts_with("/users/login") {
+                return Err(Error::msg("AO3 logged us out while loading bookmarks"));
+            }
+
+            let bookmarks_page = parse_bookmarks_page(&response.text()?)?;
+            work_ids.extend(bookmarks_page.work_ids);
+            if bookmarks_page.is_last {
+                break;
+            }
+        }
+
+        Ok(work_ids)
+    }
+}
+
+struct BookmarksPage {
+    work_ids: Vec<String>,
+    is_last: bool,
+}
+
+fn parse_bookmarks_page(html: &str) -> Result<BookmarksPage> {
+    let main_selector = Selector::parse("#main.bookmarks-index").unwrap();
+    let blurb_selector = Selector::parse("li.bookmark.blurb").unwrap();
+    let link_selector = Selector::parse("h4.heading>a").unwrap();
+    let next_selector = Selector::parse("ol.pagination a[rel=next]").unwrap();
+    let bookmarks_page = Html::parse_document(html);
+
+    // Maintenance and error pages can come back as 200 too
+    if bookmarks_page.select(&main_selector).next().is_none() {
+        return Err(Error::msg(
+            "AO3 didn't send a bookmarks page, it may be down for maintenance",
+        ));
+    }
+
+    let mut is_empty = true;
+    let mut work_ids = Vec::new();
+    for blurb in bookmarks_page.select(&blurb_selector) {
+        is_empty = false;
+        // Bookmarks of series and external works don't link to a work
+        work_ids.extend(
+            blurb
+                .select(&link_selector)
+                .filter_map(|link| link.value().attr("href"))
+                .filter(|href| href.starts_with("/works/"))
+                .filter_map(|href| href.split_terminator("/").nth(2))
+                .map(str::to_owned),
+        );
+    }
+
+    Ok(BookmarksPage {
+        work_ids,
+        is_last: is_empty || bookmarks_page.select(&next_selector).next().is_none(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn page(blurbs: &str, pagination: &str) -> String {
+        format!(
+            r#"<html><body><div id="main" class="bookmarks-index dashboard filtered region">
+            <ol class="bookmark index group">{}</ol>{}</div></body></html>"#,
+            blurbs, pagination
+        )
+    }
+
+    const BLURBS: &str = r#"
+        <li class="bookmark blurb group"><h4 class="heading">
+            <a href="/works/12">Some Work</a> by <a href="/users/someone/pseuds/someone">someone</a>
+        </h4></li>
+        <li class="bookmark blurb group"><h4 class="heading">
+            <a href="/series/345">Some Series</a>
+        </h4></li>"#;
+
+    #[test]
+    fn reads_bookmarks_until_the_last_page() {
+        let first_page = parse_bookmarks_page(&page(
+            BLURBS,
+            r#"<ol class="pagination actions"><li class="next"><a rel="next" href="?page=2">Next</a></li></ol>"#,
+        ))
+        .unwrap();
+        assert_eq!(first_page.work_ids, vec!["12"]);
+        assert!(!first_page.is_last);
+
+        let last_page = parse_bookmarks_page(&page(
+            BLURBS,
+            r#"<ol class="pagination actions"><li class="next"><span class="disabled">Next</span></li></ol>"#,
+        ))
+        .unwrap();
+        assert!(last_page.is_last);
+
+        let no_bookmarks = parse_bookmarks_page(&page("", "")).unwrap();
+        assert!(no_bookmarks.work_ids.is_empty() && no_bookmarks.is_last);
+    }
+
+    #[test]
+    fn fails_on_pages_that_arent_bookmarks() {
+        assert!(parse_bookmarks_page(
+            "<html><body><h1>The Archive is down for maintenance</h1></body></html>"
+        )
+        .is_err());
+    }
 }
@@ -1,27 +1,45 @@
-use crate::ao3::common::{filter_fandoms, get_page, DownloadFormat};
+use crate::ao3::common::{filter_fandoms, get_page, DownloadFormat, Rating};
+use crate::ao3::tags::TagResolver;
 use crate::ao3::user::User;
 use crate::config::Config;
+use crate::layout::Layout;
 
-use anyhow::Result;
+use anyhow::{Error, Result};
 use scraper::{ElementRef, Selector};
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::fs::File;
 use std::io::prelude::*;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct Work {
-    id: String,
-    title: String,
+    pub id: String,
+    pub title: String,
     pub author: String,
-    download_links: HashMap<DownloadFormat, String>,
+    pub download_links: HashMap<DownloadFormat, String>,
     pub fandoms: Vec<String>,
     pub filtered_fandom: String,
-    relationships: Vec<String>,
-    characters: Vec<String>,
-    additional_tags: Vec<String>,
-    series: HashMap<String, SeriesLink>,
+    /// More fandom folders a copy goes in, see `CrossoverPolicy::Every`
+    #[serde(default)]
+    pub crossover_fandoms: Vec<String>,
+    /// The fandoms behind the folders, see `FandomTrace::mapped_fandoms`.
+    /// Empty for works stored before they were tracked
+    #[serde(default)]
+    pub mapped_fandoms: Vec<String>,
+    pub relationships: Vec<String>,
+    pub characters: Vec<String>,
+    pub additional_tags: Vec<String>,
+    pub series: HashMap<String, SeriesLink>,
+    /// `None` for works stored before completion was tracked
+    #[serde(default)]
+    pub is_completed: Option<bool>,
+    /// `None` for works stored before ratings were tracked
+    #[serde(default)]
+    pub rating: Option<Rating>,
+    #[serde(default)]
+    pub warnings: Vec<String>,
 }
 
 impl std::fmt::Display for Work {
@@ -43,7 +61,7 @@ impl std::fmt::Display for Work {
     }
 }
 
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 pub struct SeriesLink {
     pub series_id: String,
     pub series_name: String,
@@ -51,26 +69,21 @@ pub struct SeriesLink {
 }
 
 impl Work {
-    pub fn get_series_link(&self, series_id: &String) -> Option<&SeriesLink> {
-        self.series.get(series_id)
+    /// Every fandom folder the work goes in on a device, `filtered_fandom` first
+    pub fn get_fandom_folders(&self) -> impl Iterator<Item = &String> {
+        std::iter::once(&self.filtered_fandom).chain(&self.crossover_fandoms)
     }
 
-    pub fn get_filename(&self, format: DownloadFormat, series_id: Option<&String>) -> String {
-        if series_id.is_some() && self.get_series_link(series_id.unwrap()).is_some() {
-            format!(
-                "{} - {}.{}",
-                self.get_series_link(series_id.unwrap())
-                    .unwrap()
-                    .part_in_series,
-                self.title,
-                format.to_string().to_lowercase()
-            )
-        } else {
-            format!("{}.{}", self.title, format.to_string().to_lowercase())
-        }
+    pub fn get_series_link(&self, series_id: &String) -> Option<&SeriesLink> {
+        self.series.get(series_id)
     }
 
-    pub fn parse_work(id: &str, user: Option<&User>, config: &Config) -> Result<Work> {
+    pub fn parse_work(
+        id: &str,
+        user: Option<&User>,
+        config: &Config,
+        resolver: &mut TagResolver,
+    ) -> Result<Work> {
         println!("loading work {}", id);
         let document = get_page(id, None, user).expect("Failed to get the requested page");
 
@@ -88,6 +101,12 @@ impl Work {
             Selector::parse("dd.freeform.tags>ul>li>a").expect("Error parsing additional tags");
         let part_in_series_selector = Selector::parse("dd.series>span.series>span.position")
             .expect("Error parsing part in series");
+        let chapters_selector =
+            Selector::parse("dl.stats>dd.chapters").expect("Error parsing chapters");
+        let rating_selector =
+            Selector::parse("dd.rating.tags>ul>li>a").expect("Error parsing rating");
+        let warnings_selector =
+            Selector::parse("dd.warning.tags>ul>li>a").expect("Error parsing archive warnings");
 
         let title: String = document
             .select(&title_selector)
@@ -130,6 +149,18 @@ impl Work {
             .select(&additional_tags_selector)
             .map(|x| x.text().collect())
             .collect();
+        let is_completed = document
+            .select(&chapters_selector)
+            .next()
+            .map(|chapters| is_completed(&chapters.text().collect::<String>()));
+        let rating = document
+            .select(&rating_selector)
+            .next()
+            .and_then(|rating| Rating::from_str(rating.text().collect::<String>().trim()).ok());
+        let warnings: Vec<String> = document
+            .select(&warnings_selector)
+            .map(|x| x.text().collect())
+            .collect();
         let series_element = document.select(&part_in_series_selector);
         let series_links: HashMap<String, SeriesLink> = series_element
             .map(|series| {
@@ -169,24 +200,32 @@ impl Work {
 
         println!("Work loaded");
 
+        let fandom_trace = filter_fandoms(&fandoms, config, resolver);
+
         Ok(Work {
             id: id.to_owned(),
             title: title.trim().to_owned(),
             author,
             download_links,
-            fandoms: fandoms.clone(),
-            filtered_fandom: filter_fandoms(&fandoms, config),
+            filtered_fandom: fandom_trace.fandom,
+            crossover_fandoms: fandom_trace.crossover_fandoms,
+            mapped_fandoms: fandom_trace.mapped_fandoms,
+            fandoms,
             relationships,
             characters,
             additional_tags,
             series: series_links,
+            is_completed,
+            rating,
+            warnings,
         })
     }
 
     pub fn parse_work_from_blurb(
         blurb: ElementRef,
-        series_name: &String,
+        series_name: &str,
         config: &Config,
+        resolver: &mut TagResolver,
     ) -> Result<Work> {
         let heading_selector = Selector::parse("h4.heading>a").expect("Error parsing heading");
         let fandoms_selector =
@@ -198,6 +237,12 @@ impl Work {
         let additional_tags_selector =
             Selector::parse("li.freeforms>a.tag").expect("Error parsing additional tags");
         let series_selector = Selector::parse("ul.series>li").expect("Error parsing series");
+        let chapters_selector =
+            Selector::parse("dl.stats>dd.chapters").expect("Error parsing chapters");
+        let rating_selector =
+            Selector::parse("ul.required-tags span.rating").expect("Error parsing rating");
+        let warnings_selector =
+            Selector::parse("li.warnings a.tag").expect("Error parsing archive warnings");
 
         let mut heading = blurb.select(&heading_selector);
         let title_element = heading.next().unwrap();
@@ -242,6 +287,19 @@ impl Work {
             .select(&additional_tags_selector)
             .map(|tag| tag.text().collect())
             .collect();
+        let is_completed = blurb
+            .select(&chapters_selector)
+            .next()
+            .map(|chapters| is_completed(&chapters.text().collect::<String>()));
+        let rating = blurb
+            .select(&rating_selector)
+            .next()
+            .and_then(|rating| rating.attr("title"))
+            .and_then(|rating| Rating::from_str(rating).ok());
+        let warnings: Vec<String> = blurb
+            .select(&warnings_selector)
+            .map(|warning| warning.text().collect())
+            .collect();
         let series_element = blurb.select(&series_selector);
         let series_links: HashMap<String, SeriesLink> = series_element
             .map(|series| {
@@ -267,7 +325,7 @@ impl Work {
                 (
                     series_id.clone(),
                     SeriesLink {
-                        series_name: series_name.clone(),
+                        series_name: series_name.to_owned(),
                         series_id,
                         part_in_series,
                     },
@@ -277,17 +335,24 @@ impl Work {
 
         println!("  Work parsed\n");
 
+        let fandom_trace = filter_fandoms(&fandoms, config, resolver);
+
         Ok(Work {
             id: id.to_owned(),
             title: title.trim().to_owned(),
             author,
             download_links,
-            fandoms: fandoms.clone(),
-            filtered_fandom: filter_fandoms(&fandoms, config),
+            filtered_fandom: fandom_trace.fandom,
+            crossover_fandoms: fandom_trace.crossover_fandoms,
+            mapped_fandoms: fandom_trace.mapped_fandoms,
+            fandoms,
             relationships,
             characters,
             additional_tags,
             series: series_links,
+            is_completed,
+            rating,
+            warnings,
         })
     }
 
@@ -296,20 +361,68 @@ impl Work {
         download_folder: &Path,
         format: DownloadFormat,
         series_id: Option<&String>,
-    ) -> std::io::Result<()> {
-        let download_link = self.download_links[&format].clone();
+        layout: &Layout,
+    ) -> Result<PathBuf> {
+        let download_link = self
+            .download_links
+            .get(&format)
+            .ok_or_else(|| Error::msg(format!("{} has no {} download", self.title, format)))?;
         println!("Download link: {}", download_link);
 
-        let work = reqwest::blocking::get(download_link)
-            .unwrap()
-            .bytes()
-            .unwrap();
-        let download_path = download_folder.join(self.get_filename(format, series_id));
+        let work = reqwest::blocking::get(download_link)?
+            .error_for_status()?
+            .bytes()?;
+        let download_path = download_folder.join(layout.get_filename(self, format, series_id));
 
         println!("Downloading to: {}", download_folder.to_str().unwrap());
 
-        let mut work_file = File::create(download_path)?;
+        let mut work_file = File::create(&download_path)?;
         work_file.write_all(&work)?;
-        Ok(())
+        Ok(download_path)
+    }
+
+    /// Downloads the first format each device can use, falling back down its list when AO3 fails to serve one.
+    /// Each format is only downloaded once, returns the ones that succeeded with where they were saved
+    pub fn download_for_devices(
+        &self,
+        download_folder: &Path,
+        device_formats: &[Vec<DownloadFormat>],
+        series_id: Option<&String>,
+        layout: &Layout,
+    ) -> Vec<(DownloadFormat, PathBuf)> {
+        let mut attempted: Vec<(DownloadFormat, Option<PathBuf>)> = Vec::new();
+
+        for formats in device_formats {
+            for format in formats {
+                let downloaded = match attempted.iter().find(|(attempt, _)| attempt == format) {
+                    Some((_, download_path)) => download_path.is_some(),
+                    None => {
+                        let result = self.download(download_folder, *format, series_id, layout);
+                        if let Err(error) = &result {
+                            eprintln!("Failed to download {} as {}: {}", self.title, format, error);
+                        }
+                        let downloaded = result.is_ok();
+                        attempted.push((*format, result.ok()));
+                        downloaded
+                    }
+                };
+                if downloaded {
+                    break;
+                }
+            }
+        }
+
+        attempted
+            .into_iter()
+            .filter_map(|(format, download_path)| Some((format, download_path?)))
+            .collect()
+    }
+}
+
+/// AO3 shows chapters as `3/10`, or `3/?` when the total isn't known yet
+fn is_completed(chapters: &str) -> bool {
+    match chapters.trim().split_once('/') {
+        Some((posted, total)) => posted.trim() == total.trim(),
+        None => false,
     }
 }
@@ -0,0 +1,317 @@
+use crate::config::{CollectionOptions, Config, Device};
+use crate::device::DeviceError;
+use crate::filters::check_device_filters;
+use crate::koreader::{parse_lua_table, write_lua_table, LuaValue};
+use crate::layout::Layout;
+use crate::library::{Library, LibraryEntry};
+use crate::transport::Transport;
+
+use std::collections::{BTreeMap, HashSet};
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+
+const COLLECTION_FILENAME: &str = "collection.lua";
+const UNREAD_WIPS_COLLECTION: &str = "Unread WIPs";
+/// Stored in the settings of every collection we generate, so they can be replaced without touching the user's own
+const GENERATED_BY: &str = "a2o4";
+
+/// Collection names mapped to the full remote paths of the books in them, in reading order
+pub fn generate_collections(
+    library: &Library,
+    config: &Config,
+    device: &Device,
+    options: &CollectionOptions,
+) -> BTreeMap<String, Vec<PathBuf>> {
+    let remote_download_folder = Path::new(&device.download_folder);
+    let layout = Layout::new(config, Some(device));
+    let mut collections: BTreeMap<String, Vec<(u8, PathBuf)>> = BTreeMap::new();
+    let mut add = |name: &str, order: u8, path: PathBuf| {
+        collections
+            .entry(name.to_owned())
+            .or_default()
+            .push((order, path))
+    };
+
+    for entry in &library.entries {
+        let Some(download_format) = entry.get_device_format(device) else {
+            continue;
+        };
+        if entry.removed_from_devices.contains(&device.name)
+            || check_device_filters(&entry.work, device).is_err()
+        {
+            continue;
+        }
+        let remote_path =
+            remote_download_folder.join(entry.get_remote_path(&layout, download_format));
+
+        if options.by_fandom.unwrap_or(true) {
+            add(&entry.work.filtered_fandom, 0, remote_path.clone());
+        }
+        if options.by_series.unwrap_or(true) {
+            if let Some(series_link) = entry
+                .series_id
+                .as_ref()
+                .and_then(|series_id| entry.work.get_series_link(series_id))
+            {
+                add(
+                    &series_link.series_name,
+                    series_link.part_in_series,
+                    remote_path.clone(),
+                );
+            }
+        }
+        if options.by_relationship.unwrap_or(false) {
+            for relationship in &entry.work.relationships {
+                add(relationship, 0, remote_path.clone());
+            }
+        }
+        if options.unread_wips.unwrap_or(true) && is_unread_wip(entry, device) {
+            add(UNREAD_WIPS_COLLECTION, 0, remote_path.clone());
+        }
+    }
+
+    collections
+        .into_iter()
+        .map(|(name, mut books)| {
+            books.sort();
+            books.dedup();
+            (name, books.into_iter().map(|(_, path)| path).collect())
+        })
+        .collect()
+}
+
+fn is_unread_wip(entry: &LibraryEntry, device: &Device) -> bool {
+    entry.work.is_completed == Some(false)
+        && entry
+            .reading_progress
+            .get(&device.name)
+            .is_none_or(|progress| progress.percent_finished == 0.0)
+}
+
+/// Replaces the collections we generated last time in an existing `collection.lua`, keeping any the user made themselves.
+/// A generated collection named like one of the user's is added with our name after it instead
+pub fn merge_collections(
+    existing: Option<LuaValue>,
+    collections: &BTreeMap<String, Vec<PathBuf>>,
+) -> LuaValue {
+    let mut entries = match existing {
+        Some(LuaValue::Table(entries)) => entries,
+        _ => Vec::new(),
+    };
+    entries.retain(|(_, collection)| {
+        collection
+            .get("settings")
+            .and_then(|settings| settings.get("generated_by"))
+            .and_then(LuaValue::as_str)
+            != Some(GENERATED_BY)
+    });
+    let user_collections: HashSet<String> = entries
+        .iter()
+        .filter_map(|(key, _)| key.as_str().map(str::to_owned))
+        .collect();
+
+    for (name, books) in collections {
+        let name = if user_collections.contains(name) {
+            let renamed = format!("{} ({})", name, GENERATED_BY);
+            if user_collections.contains(&renamed) {
+                eprintln!(
+                    "Not generating the {} collection, there are already collections called {} and {}",
+                    name, name, renamed
+                );
+                continue;
+            }
+            eprintln!(
+                "There is already a collection called {}, generating it as {} instead",
+                name, renamed
+            );
+            renamed
+        } else {
+            name.clone()
+        };
+        let mut collection: Vec<(LuaValue, LuaValue)> = books
+            .iter()
+            .enumerate()
+            .map(|(index, path)| {
+                let order = LuaValue::Number((index + 1) as f64);
+                let book = LuaValue::Table(vec![
+                    (
+                        LuaValue::String("file".to_owned()),
+                        LuaValue::String(path.to_string_lossy().to_string()),
+                    ),
+                    (LuaValue::String("order".to_owned()), order.clone()),
+                ]);
+                (order, book)
+            })
+            .collect();
+        collection.push((
+            LuaValue::String("settings".to_owned()),
+            LuaValue::Table(vec![(
+                LuaValue::String("generated_by".to_owned()),
+                LuaValue::String(GENERATED_BY.to_owned()),
+            )]),
+        ));
+        entries.push((LuaValue::String(name), LuaValue::Table(collection)));
+    }
+
+    LuaValue::Table(entries)
+}
+
+/// Writes the library's collections into KOReader's `collection.lua`, returns how many were generated
+pub fn upload_collections(
+    library: &Library,
+    config: &Config,
+    device: &Device,
+    connection: &dyn Transport,
+    options: &CollectionOptions,
+) -> Result<usize, DeviceError> {
+    let collection_path = Path::new(&options.koreader_settings_folder).join(COLLECTION_FILENAME);
+    let collections = generate_collections(library, config, device, options);
+
+    let existing = match connection.read_to_string(&collection_path)? {
+        Some(source) => match parse_lua_table(&source) {
+            Ok(existing) => Some(existing),
+            Err(error) => {
+                eprintln!("Failed to parse {}: {}", collection_path.display(), error);
+                None
+            }
+        },
+        None => None,
+    };
+
+    let source = write_lua_table(&merge_collections(existing, &collections));
+    let length = source.len() as u64;
+    connection.write_file(
+        &collection_path,
+        Box::new(Cursor::new(source.into_bytes())),
+        length,
+        0,
+    )?;
+
+    Ok(collections.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ao3::common::DownloadFormat;
+    use crate::ao3::work::Work;
+    use crate::test_utils::{in_series, test_device, test_work};
+
+    fn series_part(id: &str, part_in_series: u8, is_completed: bool) -> Work {
+        Work {
+            relationships: vec!["A/B".to_owned()],
+            is_completed: Some(is_completed),
+            ..in_series(test_work(id, &format!("Work {}", id)), "10", part_in_series)
+        }
+    }
+
+    #[test]
+    fn generates_collections() {
+        let device = test_device(
+            r#"
+            uses_koreader = true
+
+            [collections]
+            koreader_settings_folder = "/koreader/settings"
+            by_relationship = true
+
+            [filters]
+            blocked_fandoms = ["Fandom 2"]
+            "#,
+        );
+        let mut library = Library::default();
+        library.add_work(
+            &series_part("1", 2, true),
+            Some(&"10".to_owned()),
+            DownloadFormat::EPUB,
+            Path::new("Some Work.epub"),
+        );
+        library.add_work(
+            &series_part("2", 1, false),
+            Some(&"10".to_owned()),
+            DownloadFormat::EPUB,
+            Path::new("Some Work.epub"),
+        );
+        library.add_work(
+            &series_part("3", 1, false),
+            None,
+            DownloadFormat::EPUB,
+            Path::new("Some Work.epub"),
+        );
+        // Never put on the device, so it's in no collection
+        library.add_work(
+            &Work {
+                filtered_fandom: "Fandom 2".to_owned(),
+                ..series_part("4", 1, false)
+            },
+            None,
+            DownloadFormat::EPUB,
+            Path::new("Some Work.epub"),
+        );
+
+        let collections = generate_collections(
+            &library,
+            &Config::default(),
+            &device,
+            device.collections.as_ref().unwrap(),
+        );
+
+        let paths = |paths: &[&str]| paths.iter().map(PathBuf::from).collect::<Vec<_>>();
+        assert_eq!(
+            collections.keys().collect::<Vec<_>>(),
+            vec!["A/B", "Fandom 1", "Some Series", "Unread WIPs"]
+        );
+        assert_eq!(
+            collections["Some Series"],
+            paths(&[
+                "/fanfics/Fandom 1/Some Series/1 - Work 2.epub",
+                "/fanfics/Fandom 1/Some Series/2 - Work 1.epub"
+            ])
+        );
+        assert_eq!(
+            collections["Unread WIPs"],
+            paths(&[
+                "/fanfics/Fandom 1/Some Series/1 - Work 2.epub",
+                "/fanfics/Fandom 1/Work 3.epub"
+            ])
+        );
+    }
+
+    #[test]
+    fn keeps_the_users_own_collections() {
+        let existing = parse_lua_table(
+            r#"return {
+                ["favorites"] = { [1] = { ["file"] = "/book.epub", ["order"] = 1 } },
+                ["Old Fandom"] = { ["settings"] = { ["generated_by"] = "a2o4" } },
+                ["Fandom 2"] = { [1] = { ["file"] = "/mine.epub", ["order"] = 1 } },
+            }"#,
+        )
+        .unwrap();
+        let collections = BTreeMap::from([
+            (
+                "Fandom 1".to_owned(),
+                vec![PathBuf::from("/fanfics/1.epub")],
+            ),
+            (
+                "Fandom 2".to_owned(),
+                vec![PathBuf::from("/fanfics/2.epub")],
+            ),
+        ]);
+
+        let merged = merge_collections(Some(existing), &collections);
+        let generated_by = |name: &str| {
+            merged
+                .get(name)
+                .and_then(|collection| collection.get("settings"))
+                .and_then(|settings| settings.get("generated_by"))
+                .and_then(LuaValue::as_str)
+        };
+
+        assert!(merged.get("favorites").is_some());
+        assert!(merged.get("Old Fandom").is_none());
+        assert_eq!(generated_by("Fandom 1"), Some("a2o4"));
+        assert!(merged.get("Fandom 2").is_some());
+        assert_eq!(generated_by("Fandom 2"), None);
+        assert_eq!(generated_by("Fandom 2 (a2o4)"), Some("a2o4"));
+    }
+}
@@ -1,32 +1,222 @@
+use crate::ao3::common::{DownloadFormat, FandomMap, Rating};
+
 use std::collections::HashMap;
 use std::fs::File;
 use std::io::Read;
 
 use serde::Deserialize;
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Default, Deserialize)]
 pub struct Config {
     pub download_path: String,
     pub ao3_username: Option<String>,
     pub ao3_password: Option<String>,
     pub devices: Vec<Device>,
-    pub fandom_map: HashMap<String, String>,
+    pub fandom_map: FandomMap,
     pub fandom_filter: HashMap<String, Vec<String>>,
+    pub smtp: Option<SmtpOptions>,
+    /// Works to skip before downloading anything
+    pub filters: Option<DownloadFilters>,
+    /// Which fandom folder works with more than one fandom go in
+    pub crossovers: Option<CrossoverOptions>,
+    /// Look up fandoms `fandom_map` doesn't cover on AO3, omit to only use the config
+    pub tag_wrangling: Option<TagWranglingOptions>,
+    /// Filename and folder templates for every device, see `Layout`
+    pub layout: Option<LayoutOptions>,
+}
+
+/// The mail server works are sent through for `kind = "email"` devices
+#[derive(Debug, Deserialize, Clone)]
+pub struct SmtpOptions {
+    pub server: String,
+    pub port: Option<u16>,
+    pub security: Option<SmtpSecurity>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub from: String,
+    /// Addresses devices are allowed to send to, so a typo in `kindle_email` can't send works to a stranger
+    pub allowed_recipients: Vec<String>,
+    pub max_attachment_size_mb: Option<u64>,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SmtpSecurity {
+    #[default]
+    StartTls,
+    /// TLS from the start, usually on port 465
+    Tls,
+    None,
 }
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct Device {
     pub name: String,
+    pub kind: Option<DeviceKind>,
+    // Only needed to connect over SFTP, WebDAV uses the username and password as well
+    #[serde(default)]
     pub ip: String,
-    pub port: u16,
+    pub port: Option<u16>,
+    #[serde(default)]
     pub username: String,
+    #[serde(default)]
     pub password: String,
+    pub url: Option<String>,
+    pub kindle_email: Option<String>,
+    /// Formats to send to the device, most preferred first
+    pub formats: Option<Vec<DownloadFormat>>,
+    /// Where works go on the device, or the folder the device is mounted at for `kind = "local"`
+    #[serde(default)]
     pub download_folder: String,
+    /// For `kind = "local"`, a file that only exists while the device is mounted, e.g. `/media/KOBOeReader/.kobo`
+    pub mount_marker: Option<String>,
     pub uses_koreader: Option<bool>,
+    pub host_key_fingerprint: Option<String>,
+    pub trust_on_first_use: Option<bool>,
+    pub connect_timeout_secs: Option<u64>,
+    pub io_timeout_secs: Option<u64>,
+    pub keepalive_secs: Option<u32>,
+    pub upload_retries: Option<u32>,
+    pub upload_buffer_size: Option<usize>,
+    pub verify_checksum: Option<bool>,
+    pub retention: Option<RetentionPolicy>,
+    pub collections: Option<CollectionOptions>,
+    pub filters: Option<ContentFilters>,
+    /// Overrides the global `layout`
+    pub layout: Option<LayoutOptions>,
+    pub post_upload_command: Option<String>,
+    pub post_delete_command: Option<String>,
+}
+
+/// How works get onto the device
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum DeviceKind {
+    #[default]
+    Sftp,
+    /// A folder on this machine, such as a device mounted over USB
+    Local,
+    /// A WebDAV share at `url`, logged into with `username` and `password`
+    WebDav,
+    /// Works are emailed to `kindle_email`, through the `[smtp]` server
+    Email,
+}
+
+/// When to take finished works off a device, they are always kept in the local library
+#[derive(Debug, Deserialize, Clone)]
+pub struct RetentionPolicy {
+    pub remove_finished_after_days: u64,
+    pub keep_bookmarked: Option<bool>,
+    pub keep_series_until_finished: Option<bool>,
+}
+
+impl Device {
+    pub fn get_formats(&self) -> Vec<DownloadFormat> {
+        self.formats
+            .clone()
+            .filter(|formats| !formats.is_empty())
+            .unwrap_or_else(|| vec![DownloadFormat::EPUB])
+    }
+}
+
+/// Which KOReader collections to generate from the library, the device needs `uses_koreader`
+#[derive(Debug, Deserialize, Clone)]
+pub struct CollectionOptions {
+    pub koreader_settings_folder: String,
+    pub by_fandom: Option<bool>,
+    pub by_series: Option<bool>,
+    pub by_relationship: Option<bool>,
+    pub unread_wips: Option<bool>,
 }
 
+/// Which works are allowed onto a device, anything not listed is allowed. Names are matched ignoring case
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct ContentFilters {
+    /// Fandoms (after `fandom_map` and `fandom_filter`) the device gets, empty for all of them.
+    /// A crossover needs every one of its fandoms allowed, and none of them blocked
+    #[serde(default)]
+    pub allowed_fandoms: Vec<String>,
+    #[serde(default)]
+    pub blocked_fandoms: Vec<String>,
+    pub max_rating: Option<Rating>,
+    #[serde(default)]
+    pub blocked_warnings: Vec<String>,
+    /// Works need at least one of these relationship, character or additional tags, empty for any
+    #[serde(default)]
+    pub include_tags: Vec<String>,
+    #[serde(default)]
+    pub exclude_tags: Vec<String>,
+}
+
+/// The same rules as a device's `filters`, checked against every work before it is downloaded
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct DownloadFilters {
+    #[serde(flatten)]
+    pub rules: ContentFilters,
+    pub series: Option<SeriesFilterMode>,
+}
+
+/// What to do with a series when one of its parts is filtered out
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SeriesFilterMode {
+    #[default]
+    SkipPart,
+    SkipSeries,
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct CrossoverOptions {
+    pub policy: Option<CrossoverPolicy>,
+    /// Fandoms to prefer with `policy = "priority"`, most preferred first
+    #[serde(default)]
+    pub fandom_priority: Vec<String>,
+    /// Folder name for `policy = "folder"`, defaults to "Multiple"
+    pub folder: Option<String>,
+    /// Goes between fandoms with `policy = "joined"`, defaults to " x "
+    pub separator: Option<String>,
+}
+
+/// Where a work with more than one fandom left after `fandom_map` and `fandom_filter` goes
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum CrossoverPolicy {
+    /// A single folder for every crossover
+    #[default]
+    Folder,
+    /// The first fandom in `fandom_priority` the work has, or the first one AO3 lists
+    Priority,
+    /// The first fandom AO3 lists
+    First,
+    /// Every fandom in the order AO3 lists them, e.g. "A x B"
+    Joined,
+    /// A copy in each fandom's folder
+    Every,
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct TagWranglingOptions {
+    /// Go up to the most general metatag rather than stopping at the canonical tag, defaults to true
+    pub follow_metatags: Option<bool>,
+    /// How long to trust a looked up tag before asking AO3 again, defaults to 30
+    pub cache_days: Option<u64>,
+    /// How long to wait between requests to AO3, defaults to 2
+    pub request_delay_seconds: Option<u64>,
+}
+
+/// Templates for where works go, see `Layout` for the fields
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct LayoutOptions {
+    /// Without the extension, defaults to "[{part} - ]{title}"
+    pub filename: Option<String>,
+    /// Folders under a device's `download_folder` separated by "/", defaults to "{fandom}/{series}"
+    pub folders: Option<String>,
+}
+
+pub const CONFIG_PATH: &str = "config.toml";
+
 pub fn read_config() -> Config {
-    let mut file = File::open("config.toml").unwrap();
+    let mut file = File::open(CONFIG_PATH).unwrap();
     let mut file_contents = String::new();
     let _ = file.read_to_string(&mut file_contents); // TODO handle error
     let config: Config = toml::from_str(&file_contents).unwrap();
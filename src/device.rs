@@ -0,0 +1,1213 @@
+use crate::ao3::common::DownloadFormat;
+use crate::ao3::work::Work;
+use crate::config::{Config, Device, DeviceKind};
+use crate::email::send_work;
+use crate::filters::{check_device_filters, FilterReason};
+use crate::layout::Layout;
+use crate::library::{Library, LibraryEntry};
+use crate::transport::{connect, RemoteStat, Transport};
+
+use anyhow::Error;
+use indicatif::{ProgressBar, ProgressStyle};
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+use std::{
+    cmp::min,
+    fs::{File, Metadata},
+    io::{self, BufReader, Read, Seek, SeekFrom},
+    thread::sleep,
+    time::{Duration, UNIX_EPOCH},
+};
+
+pub const DEFAULT_CONNECT_TIMEOUT_SECS: u64 = 10;
+pub const DEFAULT_IO_TIMEOUT_SECS: u64 = 30;
+const DEFAULT_UPLOAD_RETRIES: u32 = 3;
+const DEFAULT_UPLOAD_BUFFER_SIZE: usize = 32 * 1024;
+const RETRY_DELAY_SECS: u64 = 5;
+/// How much of the end of a partial upload is compared with the local file before resuming it
+const RESUME_OVERLAP_BYTES: u64 = 64 * 1024;
+
+#[derive(Debug)]
+pub enum DeviceError {
+    Unreachable(io::Error),
+    HostKeyRejected(Error),
+    AuthFailed(ssh2::Error),
+    PermissionDenied(PathBuf),
+    DiskFull(PathBuf),
+    MissingLocalFile(PathBuf),
+    Ssh(ssh2::Error),
+    Http(reqwest::Error),
+    /// A WebDAV request the server answered with an unexpected status
+    HttpStatus(u16, PathBuf),
+    /// Something the device's kind can't do, like running commands on a WebDAV share
+    Unsupported(String),
+    RecipientNotAllowed(String),
+    /// The file and the most the device accepts, in bytes
+    TooLarge(PathBuf, u64),
+    /// The mail server's reply to a command it refused
+    Smtp(String),
+    Io(io::Error),
+}
+
+impl std::fmt::Display for DeviceError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            DeviceError::Unreachable(error) => write!(f, "device is unreachable: {}", error),
+            DeviceError::HostKeyRejected(error) => write!(f, "{}", error),
+            DeviceError::AuthFailed(error) => write!(f, "authentication failed: {}", error),
+            DeviceError::PermissionDenied(path) => {
+                write!(f, "permission denied for {}", path.display())
+            }
+            DeviceError::DiskFull(path) => {
+                write!(f, "device ran out of space writing {}", path.display())
+            }
+            DeviceError::MissingLocalFile(path) => {
+                write!(f, "local file {} does not exist", path.display())
+            }
+            DeviceError::Ssh(error) => write!(f, "ssh error: {}", error),
+            DeviceError::Http(error) => write!(f, "http error: {}", error),
+            DeviceError::HttpStatus(status, path) => {
+                write!(f, "server answered {} for {}", status, path.display())
+            }
+            DeviceError::Unsupported(operation) => {
+                write!(f, "{} isn't supported by this device", operation)
+            }
+            DeviceError::RecipientNotAllowed(recipient) => {
+                write!(f, "{} is not in smtp.allowed_recipients", recipient)
+            }
+            DeviceError::TooLarge(path, limit) => {
+                write!(f, "{} is larger than {} bytes", path.display(), limit)
+            }
+            DeviceError::Smtp(reply) => write!(f, "mail server refused: {}", reply),
+            DeviceError::Io(error) => write!(f, "io error: {}", error),
+        }
+    }
+}
+
+impl std::error::Error for DeviceError {}
+
+impl DeviceError {
+    /// Errors that could be caused by the device dropping off the network, rather than something a retry won't fix
+    fn is_retryable(&self) -> bool {
+        matches!(
+            self,
+            DeviceError::Unreachable(_)
+                | DeviceError::Ssh(_)
+                | DeviceError::Http(_)
+                | DeviceError::Io(_)
+        )
+    }
+
+    /// Errors from writing to a device, `SftpWriter` passes the `ssh2::Error` along inside the `io::Error`
+    pub fn from_io(error: io::Error, remote_path: &Path) -> DeviceError {
+        match error.kind() {
+            io::ErrorKind::PermissionDenied => {
+                return DeviceError::PermissionDenied(remote_path.to_owned())
+            }
+            io::ErrorKind::StorageFull | io::ErrorKind::QuotaExceeded => {
+                return DeviceError::DiskFull(remote_path.to_owned())
+            }
+            _ => {}
+        }
+
+        match error.downcast::<ssh2::Error>() {
+            Ok(ssh_error) => DeviceError::from_ssh(ssh_error, remote_path),
+            Err(error) => DeviceError::Io(error),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum UploadOutcome {
+    Uploaded,
+    /// The device already had an identical copy of the file
+    Skipped,
+}
+
+pub struct UploadReport {
+    pub device: String,
+    pub uploaded: Vec<String>,
+    pub skipped: Vec<String>,
+    pub deleted: Vec<String>,
+    /// Works the device's filters kept off it
+    pub filtered: Vec<(String, FilterReason)>,
+    pub failed: Vec<(String, DeviceError)>,
+    pub connection_error: Option<DeviceError>,
+}
+
+impl UploadReport {
+    pub fn new(device: &Device) -> UploadReport {
+        UploadReport {
+            device: device.name.clone(),
+            uploaded: Vec::new(),
+            skipped: Vec::new(),
+            deleted: Vec::new(),
+            filtered: Vec::new(),
+            failed: Vec::new(),
+            connection_error: None,
+        }
+    }
+}
+
+impl std::fmt::Display for UploadReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if let Some(error) = &self.connection_error {
+            return write!(f, "{}: nothing was uploaded, {}", self.device, error);
+        }
+        write!(
+            f,
+            "{}: {} uploaded, {} already up to date, {} deleted, {} filtered out, {} failed",
+            self.device,
+            self.uploaded.len(),
+            self.skipped.len(),
+            self.deleted.len(),
+            self.filtered.len(),
+            self.failed.len()
+        )?;
+        for (work, reason) in &self.filtered {
+            write!(f, "\n  {} skipped: {}", work, reason)?;
+        }
+        for (work, error) in &self.failed {
+            write!(f, "\n  {}: {}", work, error)?;
+        }
+        Ok(())
+    }
+}
+
+pub fn upload_work(
+    entry: &mut LibraryEntry,
+    device: &Device,
+    config: &Config,
+    existing_connection: Option<&mut Box<dyn Transport>>,
+) -> Result<UploadOutcome, DeviceError> {
+    if device.kind == Some(DeviceKind::Email) {
+        return send_work(entry, device, config);
+    }
+    let download_format = get_downloaded_format(entry, device, config)?;
+    let mut new_connection = None;
+    let connection = match existing_connection {
+        Some(connection) => connection,
+        None => new_connection.insert(connect(device)?),
+    };
+
+    let filename = entry.get_filename(&Layout::new(config, Some(device)), download_format);
+    let file_path = entry.get_local_path(config, download_format);
+    let mut file = open_local_file(&file_path)?;
+
+    // Crossovers can have a copy in more than one fandom folder
+    let mut outcome = UploadOutcome::Skipped;
+    let remote_file_paths = get_remote_file_paths(
+        &entry.work,
+        device,
+        config,
+        download_format,
+        entry.series_id.as_ref(),
+    );
+    move_renamed_copies(connection.as_ref(), device, entry, &remote_file_paths)?;
+    for remote_file_path in &remote_file_paths {
+        if upload_file(connection, device, &mut file, remote_file_path, &filename)?
+            == UploadOutcome::Uploaded
+        {
+            outcome = UploadOutcome::Uploaded;
+        }
+    }
+    entry
+        .remote_paths
+        .insert(device.name.clone(), remote_file_paths);
+    Ok(outcome)
+}
+
+/// Moves copies the device got at paths the work no longer goes at, e.g. from before it was completed,
+/// to the new paths, so KOReader's metadata goes with them instead of being lost to a delete and upload
+fn move_renamed_copies(
+    connection: &dyn Transport,
+    device: &Device,
+    entry: &LibraryEntry,
+    remote_file_paths: &[PathBuf],
+) -> Result<(), DeviceError> {
+    let mut new_paths = Vec::new();
+    for path in remote_file_paths {
+        if !connection.exists(path)? {
+            new_paths.push(path);
+        }
+    }
+    let mut new_paths = new_paths.into_iter();
+    for old_path in entry.get_renamed_paths(device, remote_file_paths) {
+        if !connection.exists(&old_path)? {
+            continue;
+        }
+        let Some(new_path) = new_paths.next() else {
+            break;
+        };
+        println!(
+            "Moving {} to {} on {}",
+            old_path.display(),
+            new_path.display(),
+            device.name
+        );
+        move_remote_book(connection, device, &old_path, new_path)?;
+    }
+    Ok(())
+}
+
+fn upload_file(
+    connection: &mut Box<dyn Transport>,
+    device: &Device,
+    file: &mut File,
+    remote_file_path: &Path,
+    filename: &str,
+) -> Result<UploadOutcome, DeviceError> {
+    let file_metadata = file.metadata().map_err(DeviceError::Io)?;
+    let file_length = file_metadata.len();
+    let file_modified = get_modified_secs(&file_metadata);
+
+    create_missing_folders_on_remote(
+        connection.as_ref(),
+        remote_file_path.parent().unwrap(),
+        Path::new(&device.download_folder),
+    )?;
+
+    if remote_file_is_identical(
+        connection.as_ref(),
+        device,
+        file,
+        remote_file_path,
+        file_length,
+        file_modified,
+    )? {
+        println!("Skipping {}, {} already has it", filename, device.name);
+        return Ok(UploadOutcome::Skipped);
+    }
+
+    println!("Starting to upload file: {}", filename);
+    println!("file is {} bytes", file_length);
+
+    let buffer_size = device
+        .upload_buffer_size
+        .unwrap_or(DEFAULT_UPLOAD_BUFFER_SIZE);
+    let retries = device.upload_retries.unwrap_or(DEFAULT_UPLOAD_RETRIES);
+
+    let pb = ProgressBar::new(file_length);
+    pb.set_style(
+        ProgressStyle::with_template(
+            "{msg} {spinner:.green} [{elapsed_precise}] [{bar:.cyan/blue}] {bytes}/{total_bytes} ({eta})"
+        )
+            .unwrap()
+            .progress_chars("##-")
+    );
+
+    let mut resume_from = 0;
+    let mut last_error = None;
+
+    for attempt in 0..=retries {
+        if attempt > 0 {
+            eprintln!(
+                "Upload of {} to {} was interrupted ({}), retrying {}/{}",
+                filename,
+                device.name,
+                last_error.as_ref().unwrap(),
+                attempt,
+                retries
+            );
+            sleep(Duration::from_secs(RETRY_DELAY_SECS * u64::from(attempt)));
+
+            match connect(device) {
+                Ok(reconnected) => *connection = reconnected,
+                Err(error) if error.is_retryable() => {
+                    last_error = Some(error);
+                    continue;
+                }
+                Err(error) => return Err(error),
+            }
+
+            // Pick up from whatever made it onto the device before the connection dropped
+            if connection.supports_resume() {
+                resume_from = match get_resume_offset(
+                    connection.as_ref(),
+                    device,
+                    file,
+                    remote_file_path,
+                    file_length,
+                ) {
+                    Ok(resume_from) => resume_from,
+                    Err(error) if error.is_retryable() => {
+                        last_error = Some(error);
+                        continue;
+                    }
+                    Err(error) => return Err(error),
+                };
+            }
+        }
+
+        match write_remote_file(
+            connection.as_ref(),
+            remote_file_path,
+            file,
+            resume_from,
+            buffer_size,
+            &pb,
+        ) {
+            Ok(()) => {
+                pb.finish_with_message("Finished writing file\n");
+                // Match the local modification time so the next upload can tell the file is unchanged
+                if connection.preserves_mtime() {
+                    if let Some(file_modified) = file_modified {
+                        let _ = connection.set_modified(remote_file_path, file_modified);
+                    }
+                }
+                return Ok(UploadOutcome::Uploaded);
+            }
+            Err(error) if error.is_retryable() => last_error = Some(error),
+            Err(error) => return Err(error),
+        }
+    }
+
+    pb.abandon_with_message("Failed to write file\n");
+    Err(last_error.unwrap())
+}
+
+/// Downloads are named with the global `layout` and kept in a folder per series
+pub fn get_local_file_path(
+    work: &Work,
+    config: &Config,
+    download_format: DownloadFormat,
+    series_id: Option<&String>,
+) -> PathBuf {
+    let filename = Layout::new(config, None).get_filename(work, download_format, series_id);
+    // A work can drop out of a series after it was downloaded as part of it, then it's kept with the works on their own
+    match series_id.and_then(|series_id| work.get_series_link(series_id)) {
+        Some(series_link) => Path::new(&config.download_path)
+            .join(&series_link.series_name)
+            .join(filename),
+        None => Path::new(&config.download_path).join(filename),
+    }
+}
+
+/// The device's most preferred format the library has a download of, see `LibraryEntry::get_device_format`
+pub fn get_downloaded_format(
+    entry: &LibraryEntry,
+    device: &Device,
+    config: &Config,
+) -> Result<DownloadFormat, DeviceError> {
+    entry.get_device_format(device).ok_or_else(|| {
+        DeviceError::MissingLocalFile(entry.get_local_path(config, device.get_formats()[0]))
+    })
+}
+
+pub fn open_local_file(file_path: &Path) -> Result<File, DeviceError> {
+    File::open(file_path).map_err(|error| match error.kind() {
+        io::ErrorKind::NotFound => DeviceError::MissingLocalFile(file_path.to_owned()),
+        _ => DeviceError::Io(error),
+    })
+}
+
+/// Where the work goes on the device, one path for each fandom folder it goes in unless the device's `layout`
+/// leaves the fandom out
+pub fn get_remote_file_paths(
+    work: &Work,
+    device: &Device,
+    config: &Config,
+    download_format: DownloadFormat,
+    series_id: Option<&String>,
+) -> Vec<PathBuf> {
+    let layout = Layout::new(config, Some(device));
+    let filename = layout.get_filename(work, download_format, series_id);
+    let mut remote_file_paths: Vec<PathBuf> = Vec::new();
+    for fandom in work.get_fandom_folders() {
+        let remote_file_path = Path::new(&device.download_folder)
+            .join(layout.get_folder(work, fandom, series_id))
+            .join(&filename);
+        if !remote_file_paths.contains(&remote_file_path) {
+            remote_file_paths.push(remote_file_path);
+        }
+    }
+    remote_file_paths
+}
+
+/// Modification time in whole seconds, the same precision SFTP reports for remote files
+pub fn get_modified_secs(metadata: &Metadata) -> Option<u64> {
+    metadata
+        .modified()
+        .ok()
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .map(|modified| modified.as_secs())
+}
+
+fn remote_file_is_identical(
+    connection: &dyn Transport,
+    device: &Device,
+    local_file: &mut File,
+    remote_file_path: &Path,
+    file_length: u64,
+    file_modified: Option<u64>,
+) -> Result<bool, DeviceError> {
+    let Some(remote_stat) = connection.stat(remote_file_path)? else {
+        return Ok(false);
+    };
+    if remote_stat.size != Some(file_length) {
+        return Ok(false);
+    }
+
+    if device.verify_checksum.unwrap_or(false) {
+        let local_hash = hash_local_file(local_file, file_length)?;
+        return Ok(connection
+            .hash_file(remote_file_path)
+            .is_some_and(|remote_hash| remote_hash == local_hash));
+    }
+
+    Ok(!connection.preserves_mtime()
+        || (file_modified.is_some() && remote_stat.mtime == file_modified))
+}
+
+/// How much of an interrupted upload can be kept, 0 to start over when what's on the device isn't the start of
+/// the local file. The bytes just before the end of the partial file are compared, or the whole of it is hashed
+/// with `verify_checksum`
+fn get_resume_offset(
+    connection: &dyn Transport,
+    device: &Device,
+    local_file: &mut File,
+    remote_file_path: &Path,
+    file_length: u64,
+) -> Result<u64, DeviceError> {
+    let remote_size = match connection.stat(remote_file_path)? {
+        Some(RemoteStat {
+            size: Some(size), ..
+        }) if size > 0 && size <= file_length => size,
+        _ => return Ok(0),
+    };
+
+    let is_intact = if device.verify_checksum.unwrap_or(false) {
+        let local_hash = hash_local_file(local_file, remote_size)?;
+        connection
+            .hash_file(remote_file_path)
+            .is_some_and(|remote_hash| remote_hash == local_hash)
+    } else {
+        let overlap = min(remote_size, RESUME_OVERLAP_BYTES);
+        let remote_end = connection.read_range(remote_file_path, remote_size - overlap, overlap)?;
+        let mut local_end = vec![0; overlap as usize];
+        local_file
+            .seek(SeekFrom::Start(remote_size - overlap))
+            .and_then(|_| local_file.read_exact(&mut local_end))
+            .map_err(DeviceError::Io)?;
+        remote_end == local_end
+    };
+
+    if is_intact {
+        Ok(remote_size)
+    } else {
+        eprintln!(
+            "{} on {} doesn't match the local file, uploading it from the start",
+            remote_file_path.display(),
+            device.name
+        );
+        Ok(0)
+    }
+}
+
+/// sha256 of the first `length` bytes of the file
+fn hash_local_file(local_file: &mut File, length: u64) -> Result<String, DeviceError> {
+    let mut hasher = Sha256::new();
+    local_file
+        .seek(SeekFrom::Start(0))
+        .map_err(DeviceError::Io)?;
+    io::copy(&mut (&*local_file).take(length), &mut hasher).map_err(DeviceError::Io)?;
+    local_file
+        .seek(SeekFrom::Start(0))
+        .map_err(DeviceError::Io)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Runs the device's `post_upload_command` and `post_delete_command` if the report shows anything was uploaded or deleted
+pub fn run_device_hooks(connection: &dyn Transport, device: &Device, report: &UploadReport) {
+    let hooks = [
+        (&device.post_upload_command, !report.uploaded.is_empty()),
+        (&device.post_delete_command, !report.deleted.is_empty()),
+    ];
+
+    for command in hooks
+        .into_iter()
+        .filter_map(|(command, should_run)| command.as_ref().filter(|_| should_run))
+    {
+        match connection.run_command(command) {
+            Ok((exit_status, output)) => {
+                println!(
+                    "{}: `{}` exited with status {}",
+                    device.name, command, exit_status
+                );
+                for line in output.lines() {
+                    println!("  {}", line);
+                }
+            }
+            Err(error) => eprintln!("{}: failed to run `{}`: {}", device.name, command, error),
+        }
+    }
+}
+
+pub fn shell_quote(argument: &str) -> String {
+    format!("'{}'", argument.replace('\'', "'\\''"))
+}
+
+fn write_remote_file(
+    connection: &dyn Transport,
+    remote_file_path: &Path,
+    local_file: &mut File,
+    resume_from: u64,
+    buffer_size: usize,
+    pb: &ProgressBar,
+) -> Result<(), DeviceError> {
+    let file_length = local_file.metadata().map_err(DeviceError::Io)?.len();
+    local_file
+        .seek(SeekFrom::Start(resume_from))
+        .map_err(DeviceError::Io)?;
+    pb.set_position(resume_from);
+
+    let contents = ProgressReader {
+        file: local_file.try_clone().map_err(DeviceError::Io)?,
+        pb: pb.clone(),
+    };
+    connection.write_file(
+        remote_file_path,
+        Box::new(BufReader::with_capacity(buffer_size, contents)),
+        file_length - resume_from,
+        resume_from,
+    )
+}
+
+/// Moves the progress bar along as the upload reads the local file
+struct ProgressReader {
+    file: File,
+    pb: ProgressBar,
+}
+
+impl Read for ProgressReader {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        let bytes_read = self.file.read(buffer)?;
+        self.pb.inc(bytes_read as u64);
+        Ok(bytes_read)
+    }
+}
+
+/// Uploads a batch of library entries (by index), e.g. every part of a series, carrying on past works that fail
+/// so one missing file doesn't stop the rest
+pub fn upload_works(
+    library: &mut Library,
+    indices: &[usize],
+    device: &Device,
+    config: &Config,
+) -> UploadReport {
+    let mut report = UploadReport::new(device);
+
+    // Works are emailed one at a time, there is nothing to connect to first
+    let mut connection = match device.kind {
+        Some(DeviceKind::Email) => None,
+        _ => match connect(device) {
+            Ok(connection) => Some(connection),
+            Err(error) => {
+                report.connection_error = Some(error);
+                return report;
+            }
+        },
+    };
+
+    let layout = Layout::new(config, Some(device));
+    for index in indices {
+        let entry = &mut library.entries[*index];
+        let download_format = entry
+            .get_device_format(device)
+            .unwrap_or(device.get_formats()[0]);
+        let work_name = entry.get_filename(&layout, download_format);
+        if let Err(reason) = check_device_filters(&entry.work, device) {
+            report.filtered.push((work_name, reason));
+            continue;
+        }
+        match upload_work(entry, device, config, connection.as_mut()) {
+            Ok(UploadOutcome::Uploaded) => report.uploaded.push(work_name),
+            Ok(UploadOutcome::Skipped) => report.skipped.push(work_name),
+            Err(error) => {
+                eprintln!(
+                    "Failed to upload {} to {}: {}",
+                    work_name, device.name, error
+                );
+                report.failed.push((work_name, error));
+            }
+        }
+    }
+
+    if let Some(connection) = &connection {
+        run_device_hooks(connection.as_ref(), device, &report);
+    }
+    report
+}
+
+/// Deletes every copy of the work, including any still at a path it was put at before
+pub fn delete_work(
+    entry: &LibraryEntry,
+    device: &Device,
+    config: &Config,
+    download_format: DownloadFormat,
+    existing_connection: Option<&dyn Transport>,
+) -> Result<(), DeviceError> {
+    let mut new_connection = None;
+    let connection = match existing_connection {
+        Some(connection) => connection,
+        None => &**new_connection.insert(connect(device)?),
+    };
+
+    let filename = entry.get_filename(&Layout::new(config, Some(device)), download_format);
+    println!("Deleting {} from {}", &filename, device.name);
+    let remote_file_paths = get_remote_file_paths(
+        &entry.work,
+        device,
+        config,
+        download_format,
+        entry.series_id.as_ref(),
+    );
+    let renamed_paths = entry.get_renamed_paths(device, &remote_file_paths);
+    for remote_file_path in remote_file_paths.iter().chain(&renamed_paths) {
+        delete_remote_book(connection, device, remote_file_path)?;
+    }
+    Ok(())
+}
+
+/// Deletes a batch of library entries (by index), e.g. every part of a series, carrying on past works that fail
+/// like `upload_works`. Only the works that were deleted are marked as removed from the device
+pub fn delete_works(
+    library: &mut Library,
+    indices: &[usize],
+    device: &Device,
+    config: &Config,
+) -> UploadReport {
+    let mut report = UploadReport::new(device);
+
+    let connection = match connect(device) {
+        Ok(connection) => connection,
+        Err(error) => {
+            report.connection_error = Some(error);
+            return report;
+        }
+    };
+
+    let layout = Layout::new(config, Some(device));
+    for index in indices {
+        let entry = &mut library.entries[*index];
+        // Nothing was downloaded in a format this device takes, so nothing was uploaded to it
+        let Some(download_format) = entry.get_device_format(device) else {
+            continue;
+        };
+        let work_name = entry.get_filename(&layout, download_format);
+        match delete_work(
+            entry,
+            device,
+            config,
+            download_format,
+            Some(connection.as_ref()),
+        ) {
+            Ok(()) => {
+                entry.remote_paths.remove(&device.name);
+                if !entry.removed_from_devices.contains(&device.name) {
+                    entry.removed_from_devices.push(device.name.clone());
+                }
+                report.deleted.push(work_name);
+            }
+            Err(error) => {
+                eprintln!(
+                    "Failed to delete {} from {}: {}",
+                    work_name, device.name, error
+                );
+                report.failed.push((work_name, error));
+            }
+        }
+    }
+
+    run_device_hooks(connection.as_ref(), device, &report);
+    report
+}
+
+/// Removes a book along with its KOReader metadata, then any fandom or series folders it leaves empty
+pub fn delete_remote_book(
+    connection: &dyn Transport,
+    device: &Device,
+    remote_file_path: &Path,
+) -> Result<(), DeviceError> {
+    // Already gone is fine, there may still be metadata and folders to clean up
+    connection.delete_file(remote_file_path)?;
+
+    if device.uses_koreader.unwrap_or(false) {
+        let metadata_folder = get_koreader_metadata_folder(remote_file_path);
+        if connection.exists(&metadata_folder)? {
+            remove_remote_folder(connection, &metadata_folder)?;
+        }
+    }
+
+    if let Some(parent_folder) = remote_file_path.parent() {
+        remove_empty_folders_on_remote(
+            connection,
+            parent_folder,
+            Path::new(&device.download_folder),
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Moves a book along with its KOReader metadata so reading progress is kept, then removes the folders it leaves empty
+pub fn move_remote_book(
+    connection: &dyn Transport,
+    device: &Device,
+    from: &Path,
+    to: &Path,
+) -> Result<(), DeviceError> {
+    let remote_download_folder = Path::new(&device.download_folder);
+    if let Some(parent_folder) = to.parent() {
+        create_missing_folders_on_remote(connection, parent_folder, remote_download_folder)?;
+    }
+
+    // The metadata goes first, so a failure never leaves the book without its reading progress
+    let old_metadata_folder = get_koreader_metadata_folder(from);
+    let new_metadata_folder = get_koreader_metadata_folder(to);
+    let moves_metadata =
+        device.uses_koreader.unwrap_or(false) && connection.exists(&old_metadata_folder)?;
+    if moves_metadata {
+        connection.rename(&old_metadata_folder, &new_metadata_folder)?;
+    }
+    if let Err(error) = connection.rename(from, to) {
+        if moves_metadata {
+            if let Err(rollback_error) =
+                connection.rename(&new_metadata_folder, &old_metadata_folder)
+            {
+                eprintln!(
+                    "Failed to move {} back: {}",
+                    new_metadata_folder.display(),
+                    rollback_error
+                );
+            }
+        }
+        return Err(error);
+    }
+
+    if let Some(parent_folder) = from.parent() {
+        remove_empty_folders_on_remote(connection, parent_folder, remote_download_folder)?;
+    }
+    Ok(())
+}
+
+/// KOReader keeps its metadata for `book.epub` in a `book.sdr` folder next to it
+pub fn get_koreader_metadata_folder(remote_file_path: &Path) -> PathBuf {
+    remote_file_path.with_extension("sdr")
+}
+
+fn remove_remote_folder(connection: &dyn Transport, folder: &Path) -> Result<(), DeviceError> {
+    for (path, stat) in connection.read_folder(folder)? {
+        if stat.is_dir {
+            remove_remote_folder(connection, &path)?;
+        } else {
+            connection.delete_file(&path)?;
+        }
+    }
+    connection.delete_folder(folder)
+}
+
+/// Walks up from `folder`, removing each folder that is empty until reaching `remote_download_folder`
+pub fn remove_empty_folders_on_remote(
+    connection: &dyn Transport,
+    folder: &Path,
+    remote_download_folder: &Path,
+) -> Result<(), DeviceError> {
+    for path in folder.ancestors() {
+        if path == remote_download_folder || !path.starts_with(remote_download_folder) {
+            break;
+        }
+        match connection.read_folder(path) {
+            Ok(entries) if entries.is_empty() => connection.delete_folder(path)?,
+            _ => break,
+        }
+    }
+    Ok(())
+}
+
+/// Recursively lists every file under `folder`, skipping KOReader's `.sdr` metadata folders
+pub fn list_remote_files(
+    connection: &dyn Transport,
+    folder: &Path,
+) -> Result<Vec<(PathBuf, RemoteStat)>, DeviceError> {
+    let mut files = Vec::new();
+
+    for (path, stat) in connection.read_folder(folder)? {
+        if stat.is_dir {
+            if path.extension().is_some_and(|extension| extension == "sdr") {
+                continue;
+            }
+            files.extend(list_remote_files(connection, &path)?);
+        } else {
+            files.push((path, stat));
+        }
+    }
+
+    Ok(files)
+}
+
+pub fn create_missing_folders_on_remote(
+    connection: &dyn Transport,
+    path_to_create: &Path,
+    remote_download_folder: &Path,
+) -> Result<(), DeviceError> {
+    let remote_download_folder_num_ancestors = remote_download_folder.ancestors().count();
+    let remote_file_ancestors = path_to_create.ancestors().collect::<Vec<&Path>>();
+    let remote_file_iterator = remote_file_ancestors
+        .iter()
+        .rev()
+        .skip(remote_download_folder_num_ancestors);
+
+    for path in remote_file_iterator {
+        if !connection.exists(path)? {
+            connection.create_folder(path)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::LayoutOptions;
+    use crate::test_utils::{in_series, test_config, test_local_device, test_work};
+    use std::fs;
+
+    /// Leaves `partial` where the file was going and then loses the connection, like a device going out of range
+    /// mid upload
+    struct DroppedTransport {
+        inner: Box<dyn Transport>,
+        partial: Vec<u8>,
+    }
+
+    impl Transport for DroppedTransport {
+        fn stat(&self, path: &Path) -> Result<Option<RemoteStat>, DeviceError> {
+            self.inner.stat(path)
+        }
+
+        fn create_folder(&self, path: &Path) -> Result<(), DeviceError> {
+            self.inner.create_folder(path)
+        }
+
+        fn write_file(
+            &self,
+            path: &Path,
+            _contents: Box<dyn io::BufRead + Send>,
+            _length: u64,
+            _resume_from: u64,
+        ) -> Result<(), DeviceError> {
+            fs::write(path, &self.partial).unwrap();
+            Err(DeviceError::Io(io::ErrorKind::ConnectionReset.into()))
+        }
+
+        fn set_modified(&self, path: &Path, modified: u64) -> Result<(), DeviceError> {
+            self.inner.set_modified(path, modified)
+        }
+
+        fn read_to_string(&self, path: &Path) -> Result<Option<String>, DeviceError> {
+            self.inner.read_to_string(path)
+        }
+
+        fn read_range(
+            &self,
+            path: &Path,
+            offset: u64,
+            length: u64,
+        ) -> Result<Vec<u8>, DeviceError> {
+            self.inner.read_range(path, offset, length)
+        }
+
+        fn read_folder(&self, path: &Path) -> Result<Vec<(PathBuf, RemoteStat)>, DeviceError> {
+            self.inner.read_folder(path)
+        }
+
+        fn delete_file(&self, path: &Path) -> Result<(), DeviceError> {
+            self.inner.delete_file(path)
+        }
+
+        fn delete_folder(&self, path: &Path) -> Result<(), DeviceError> {
+            self.inner.delete_folder(path)
+        }
+
+        fn rename(&self, from: &Path, to: &Path) -> Result<(), DeviceError> {
+            self.inner.rename(from, to)
+        }
+
+        fn hash_file(&self, path: &Path) -> Option<String> {
+            self.inner.hash_file(path)
+        }
+
+        fn run_command(&self, command: &str) -> Result<(i32, String), DeviceError> {
+            self.inner.run_command(command)
+        }
+    }
+
+    /// A local file of `length` bytes that aren't all the same, and a device to upload it to
+    fn upload_fixture(
+        test_folder: &Path,
+        length: usize,
+        extra_toml: &str,
+    ) -> (Vec<u8>, File, Device, PathBuf) {
+        let contents: Vec<u8> = (0..length).map(|byte| (byte % 251) as u8).collect();
+        let local_path = test_folder.join("Some Work.epub");
+        fs::write(&local_path, &contents).unwrap();
+        let device_folder = test_folder.join("device");
+        fs::create_dir_all(&device_folder).unwrap();
+        let device = test_local_device(&device_folder, extra_toml);
+        let remote_path = device_folder.join("Some Work.epub");
+        (
+            contents,
+            File::open(local_path).unwrap(),
+            device,
+            remote_path,
+        )
+    }
+
+    #[test]
+    fn local_path_skips_series_the_work_left() {
+        let config = test_config(Path::new("downloads"));
+        let work = in_series(test_work("12", "Some Work"), "345", 2);
+
+        let local_path = |series_id: &str| {
+            get_local_file_path(
+                &work,
+                &config,
+                DownloadFormat::EPUB,
+                Some(&series_id.to_owned()),
+            )
+        };
+
+        assert_eq!(
+            local_path("345"),
+            Path::new("downloads/Some Series/2 - Some Work.epub")
+        );
+        assert_eq!(local_path("678"), Path::new("downloads/Some Work.epub"));
+    }
+
+    #[test]
+    fn uploads_to_and_deletes_from_a_local_device() {
+        let test_folder = tempfile::tempdir().unwrap();
+        let download_path = test_folder.path().join("downloads");
+        let device_folder = test_folder.path().join("device");
+        fs::create_dir_all(&download_path).unwrap();
+        fs::create_dir_all(&device_folder).unwrap();
+        fs::write(download_path.join("Some Work.epub"), "not really an epub").unwrap();
+
+        let config = test_config(&download_path);
+        let device = test_local_device(&device_folder, "uses_koreader = true");
+        let mut library = Library::default();
+        library.add_work(
+            &test_work("12", "Some Work"),
+            None,
+            DownloadFormat::EPUB,
+            &download_path.join("Some Work.epub"),
+        );
+        let mut upload = || upload_work(&mut library.entries[0], &device, &config, None);
+
+        assert_eq!(upload().unwrap(), UploadOutcome::Uploaded);
+        assert_eq!(upload().unwrap(), UploadOutcome::Skipped);
+
+        let remote_path = device_folder.join("Fandom 1").join("Some Work.epub");
+        fs::create_dir_all(remote_path.with_extension("sdr")).unwrap();
+        let connection = connect(&device).unwrap();
+        let remote_files = list_remote_files(connection.as_ref(), &device_folder).unwrap();
+        assert_eq!(
+            remote_files
+                .iter()
+                .map(|(path, _)| path)
+                .collect::<Vec<_>>(),
+            vec![&remote_path]
+        );
+
+        delete_remote_book(connection.as_ref(), &device, &remote_path).unwrap();
+        assert!(fs::read_dir(&device_folder).unwrap().next().is_none());
+    }
+
+    #[test]
+    fn only_marks_deleted_works_as_removed() {
+        let test_folder = tempfile::tempdir().unwrap();
+        let device_folder = test_folder.path().join("device");
+        let fandom_folder = device_folder.join("Fandom 1");
+        fs::create_dir_all(&fandom_folder).unwrap();
+        fs::write(fandom_folder.join("Deleted.epub"), "not really an epub").unwrap();
+        // Can't be removed as a file
+        fs::create_dir(fandom_folder.join("Stuck.epub")).unwrap();
+
+        let config = test_config(test_folder.path());
+        let device = test_local_device(&device_folder, "");
+        let mut library = Library::default();
+        for (id, title) in [("1", "Deleted"), ("2", "Stuck")] {
+            library.add_work(
+                &test_work(id, title),
+                None,
+                DownloadFormat::EPUB,
+                &test_folder.path().join(format!("{}.epub", title)),
+            );
+        }
+
+        let report = delete_works(&mut library, &[0, 1], &device, &config);
+
+        assert_eq!(report.deleted.len(), 1);
+        assert_eq!(report.failed.len(), 1);
+        assert_eq!(library.entries[0].removed_from_devices, vec!["Kobo"]);
+        assert!(library.entries[1].removed_from_devices.is_empty());
+    }
+
+    #[test]
+    fn moves_renamed_copies_with_their_metadata() {
+        let test_folder = tempfile::tempdir().unwrap();
+        let download_path = test_folder.path().join("downloads");
+        let device_folder = test_folder.path().join("device");
+        fs::create_dir_all(&download_path).unwrap();
+        fs::create_dir_all(&device_folder).unwrap();
+        let local_path = download_path.join("Some Work.epub");
+        fs::write(&local_path, "not really an epub").unwrap();
+
+        let device = test_local_device(&device_folder, "uses_koreader = true");
+        let mut library = Library::default();
+        let work = Work {
+            is_completed: Some(false),
+            ..test_work("12", "Some Work")
+        };
+        library.add_work(&work, None, DownloadFormat::EPUB, &local_path);
+        let config = Config {
+            layout: Some(LayoutOptions {
+                folders: Some("{fandom}/{status}".to_owned()),
+                ..Default::default()
+            }),
+            ..test_config(&download_path)
+        };
+        upload_work(&mut library.entries[0], &device, &config, None).unwrap();
+        let old_path = device_folder
+            .join("Fandom 1")
+            .join("In Progress")
+            .join("Some Work.epub");
+        fs::create_dir_all(old_path.with_extension("sdr")).unwrap();
+
+        library.entries[0].work.is_completed = Some(true);
+        let outcome = upload_work(&mut library.entries[0], &device, &config, None);
+
+        let new_path = device_folder
+            .join("Fandom 1")
+            .join("Complete")
+            .join("Some Work.epub");
+        assert_eq!(outcome.unwrap(), UploadOutcome::Skipped);
+        assert!(new_path.is_file());
+        assert!(new_path.with_extension("sdr").is_dir());
+        assert!(!device_folder.join("Fandom 1").join("In Progress").exists());
+        assert_eq!(library.entries[0].remote_paths["Kobo"], vec![new_path]);
+    }
+
+    #[test]
+    fn streams_the_file_with_progress() {
+        let test_folder = tempfile::tempdir().unwrap();
+        let (contents, mut file, device, remote_path) =
+            upload_fixture(test_folder.path(), 1000, "");
+        let connection = connect(&device).unwrap();
+
+        let pb = ProgressBar::hidden();
+        write_remote_file(connection.as_ref(), &remote_path, &mut file, 0, 7, &pb).unwrap();
+        assert_eq!(fs::read(&remote_path).unwrap(), contents);
+        assert_eq!(pb.position(), 1000);
+
+        // Carries on from the end of what's there
+        fs::write(&remote_path, &contents[..400]).unwrap();
+        let pb = ProgressBar::hidden();
+        write_remote_file(connection.as_ref(), &remote_path, &mut file, 400, 7, &pb).unwrap();
+        assert_eq!(fs::read(&remote_path).unwrap(), contents);
+        assert_eq!(pb.position(), 1000);
+    }
+
+    #[test]
+    fn only_resumes_intact_partial_uploads() {
+        let test_folder = tempfile::tempdir().unwrap();
+        let (contents, mut file, device, remote_path) =
+            upload_fixture(test_folder.path(), 1000, "");
+        let connection = connect(&device).unwrap();
+        let mut resume_offset = |device: &Device, partial: &[u8]| {
+            fs::write(&remote_path, partial).unwrap();
+            get_resume_offset(connection.as_ref(), device, &mut file, &remote_path, 1000).unwrap()
+        };
+
+        assert_eq!(resume_offset(&device, &contents[..400]), 400);
+        let mut corrupted = contents[..400].to_vec();
+        corrupted[399] ^= 1;
+        assert_eq!(resume_offset(&device, &corrupted), 0);
+        assert_eq!(
+            resume_offset(&device, &[contents.as_slice(), b"more"].concat()),
+            0
+        );
+
+        let verifying_device = Device {
+            verify_checksum: Some(true),
+            ..device.clone()
+        };
+        assert_eq!(resume_offset(&verifying_device, &contents[..400]), 400);
+        let mut corrupted = contents[..400].to_vec();
+        corrupted[0] ^= 1;
+        assert_eq!(resume_offset(&verifying_device, &corrupted), 0);
+    }
+
+    #[test]
+    fn starts_over_when_a_dropped_upload_left_the_wrong_bytes() {
+        let test_folder = tempfile::tempdir().unwrap();
+        let (contents, mut file, device, remote_path) =
+            upload_fixture(test_folder.path(), 1000, "upload_retries = 1");
+        let mut connection: Box<dyn Transport> = Box::new(DroppedTransport {
+            inner: connect(&device).unwrap(),
+            partial: vec![b'x'; 400],
+        });
+
+        let outcome = upload_file(
+            &mut connection,
+            &device,
+            &mut file,
+            &remote_path,
+            "Some Work.epub",
+        );
+
+        assert_eq!(outcome.unwrap(), UploadOutcome::Uploaded);
+        assert_eq!(fs::read(&remote_path).unwrap(), contents);
+    }
+
+    #[test]
+    fn skips_files_already_on_the_device() {
+        let test_folder = tempfile::tempdir().unwrap();
+        let (contents, mut file, device, remote_path) =
+            upload_fixture(test_folder.path(), 1000, "");
+        let connection = connect(&device).unwrap();
+        let modified = get_modified_secs(&file.metadata().unwrap());
+        let mut is_identical = |device: &Device| {
+            remote_file_is_identical(
+                connection.as_ref(),
+                device,
+                &mut file,
+                &remote_path,
+                1000,
+                modified,
+            )
+            .unwrap()
+        };
+        let verifying_device = Device {
+            verify_checksum: Some(true),
+            ..device.clone()
+        };
+
+        assert!(!is_identical(&device));
+        fs::write(&remote_path, &contents).unwrap();
+        connection
+            .set_modified(&remote_path, modified.unwrap() - 60)
+            .unwrap();
+        assert!(!is_identical(&device));
+        assert!(is_identical(&verifying_device));
+
+        connection
+            .set_modified(&remote_path, modified.unwrap())
+            .unwrap();
+        assert!(is_identical(&device));
+        let mut changed = contents.clone();
+        changed[500] ^= 1;
+        fs::write(&remote_path, &changed).unwrap();
+        assert!(!is_identical(&verifying_device));
+    }
+}
@@ -0,0 +1,503 @@
+use crate::ao3::common::DownloadFormat;
+use crate::ao3::work::Work;
+use crate::config::{Config, Device, SmtpOptions, SmtpSecurity};
+use crate::device::{
+    get_downloaded_format, get_modified_secs, open_local_file, DeviceError, UploadOutcome,
+    DEFAULT_CONNECT_TIMEOUT_SECS, DEFAULT_IO_TIMEOUT_SECS,
+};
+use crate::layout::Layout;
+use crate::library::LibraryEntry;
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use native_tls::{TlsConnector, TlsStream};
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::Path;
+use std::time::Duration;
+
+const DEFAULT_SMTP_PORT: u16 = 587;
+/// Send to Kindle rejects emails over 50MB
+const DEFAULT_MAX_ATTACHMENT_SIZE_MB: u64 = 50;
+const BOUNDARY: &str = "a2o4-attachment-boundary";
+/// Bytes of the attachment that encode to one 76 character line
+const ATTACHMENT_LINE_BYTES: usize = 57;
+
+enum SmtpStream {
+    Plain(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+impl Read for SmtpStream {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        match self {
+            SmtpStream::Plain(stream) => stream.read(buffer),
+            SmtpStream::Tls(stream) => stream.read(buffer),
+        }
+    }
+}
+
+impl Write for SmtpStream {
+    fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+        match self {
+            SmtpStream::Plain(stream) => stream.write(buffer),
+            SmtpStream::Tls(stream) => stream.write(buffer),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            SmtpStream::Plain(stream) => stream.flush(),
+            SmtpStream::Tls(stream) => stream.flush(),
+        }
+    }
+}
+
+/// Emails a downloaded work to the device's `kindle_email`, unless this download of it was already sent there.
+/// `upload_work` sends works here for `kind = "email"` devices
+pub fn send_work(
+    entry: &mut LibraryEntry,
+    device: &Device,
+    config: &Config,
+) -> Result<UploadOutcome, DeviceError> {
+    let smtp = config
+        .smtp
+        .as_ref()
+        .ok_or_else(|| DeviceError::Unsupported("emailing without [smtp]".to_owned()))?;
+    let recipient = get_recipient(device, smtp)?;
+
+    let download_format = get_downloaded_format(entry, device, config)?;
+    let filename = entry.get_filename(&Layout::new(config, Some(device)), download_format);
+    let file_path = entry.get_local_path(config, download_format);
+    let mut file = open_local_file(&file_path)?;
+    let file_metadata = file.metadata().map_err(DeviceError::Io)?;
+    let file_modified = get_modified_secs(&file_metadata);
+    if file_modified.is_some() && entry.emailed.get(&device.name) == file_modified.as_ref() {
+        println!(
+            "Skipping {}, it was already emailed to {}",
+            filename, recipient
+        );
+        return Ok(UploadOutcome::Skipped);
+    }
+    check_attachment_size(&file_path, file_metadata.len(), smtp)?;
+
+    println!("Emailing {} to {}", &filename, recipient);
+    let headers = build_headers(
+        &smtp.from,
+        recipient,
+        &entry.work,
+        &filename,
+        download_format,
+    );
+    send_message(smtp, device, recipient, &headers, &mut file)?;
+    if let Some(file_modified) = file_modified {
+        entry.emailed.insert(device.name.clone(), file_modified);
+    }
+    Ok(UploadOutcome::Uploaded)
+}
+
+fn get_recipient<'a>(device: &'a Device, smtp: &SmtpOptions) -> Result<&'a str, DeviceError> {
+    let recipient = device
+        .kindle_email
+        .as_deref()
+        .ok_or_else(|| DeviceError::Unsupported("emailing without kindle_email".to_owned()))?;
+    if smtp
+        .allowed_recipients
+        .iter()
+        .any(|allowed| allowed.trim().eq_ignore_ascii_case(recipient.trim()))
+    {
+        Ok(recipient)
+    } else {
+        Err(DeviceError::RecipientNotAllowed(recipient.to_owned()))
+    }
+}
+
+fn check_attachment_size(
+    file_path: &Path,
+    file_length: u64,
+    smtp: &SmtpOptions,
+) -> Result<(), DeviceError> {
+    let limit = smtp
+        .max_attachment_size_mb
+        .unwrap_or(DEFAULT_MAX_ATTACHMENT_SIZE_MB)
+        * 1024
+        * 1024;
+    // Base64 makes the attachment a third bigger on the way
+    if file_length.div_ceil(3) * 4 > limit {
+        return Err(DeviceError::TooLarge(file_path.to_owned(), limit));
+    }
+    Ok(())
+}
+
+fn get_content_type(download_format: DownloadFormat) -> &'static str {
+    match download_format {
+        DownloadFormat::AZW3 => "application/vnd.amazon.ebook",
+        DownloadFormat::EPUB => "application/epub+zip",
+        DownloadFormat::MOBI => "application/x-mobipocket-ebook",
+        DownloadFormat::PDF => "application/pdf",
+        DownloadFormat::HTML => "text/html",
+    }
+}
+
+/// Header values have to be ASCII, anything else goes in an RFC 2047 encoded word. So do control characters,
+/// a line break in a work's title would otherwise start a header of its own
+fn encode_header(value: &str) -> String {
+    if value.is_ascii() && !value.chars().any(|character| character.is_ascii_control()) {
+        value.to_owned()
+    } else {
+        format!("=?UTF-8?B?{}?=", STANDARD.encode(value))
+    }
+}
+
+/// Everything in the message before the attachment's contents, which `write_attachment` streams after it
+fn build_headers(
+    from: &str,
+    to: &str,
+    work: &Work,
+    filename: &str,
+    download_format: DownloadFormat,
+) -> String {
+    let filename = encode_header(&filename.replace(['"', '\\'], ""));
+    // Addresses can't be encoded, and only come from the config, but still can't be allowed to end the header
+    let from = from.replace(['\r', '\n'], "");
+    let to = to.replace(['\r', '\n'], "");
+
+    [
+        format!("From: {}", from),
+        format!("To: {}", to),
+        format!("Subject: {}", encode_header(&work.title)),
+        "MIME-Version: 1.0".to_owned(),
+        format!("Content-Type: multipart/mixed; boundary=\"{}\"", BOUNDARY),
+        String::new(),
+        format!("--{}", BOUNDARY),
+        "Content-Type: text/plain; charset=utf-8".to_owned(),
+        "Content-Transfer-Encoding: base64".to_owned(),
+        String::new(),
+        STANDARD.encode(format!(
+            "{} by {}, https://archiveofourown.org/works/{}",
+            work.title, work.author, work.id
+        )),
+        format!("--{}", BOUNDARY),
+        format!(
+            "Content-Type: {}; name=\"{}\"",
+            get_content_type(download_format),
+            filename
+        ),
+        format!("Content-Disposition: attachment; filename=\"{}\"", filename),
+        "Content-Transfer-Encoding: base64".to_owned(),
+        String::new(),
+        String::new(),
+    ]
+    .join("\r\n")
+}
+
+/// Base64 encodes the file into `destination` a chunk at a time, in lines of 76 characters as MIME requires
+fn write_attachment(destination: &mut impl Write, file: &mut impl Read) -> io::Result<()> {
+    // Whole lines of input, so only the last chunk can need padding
+    let mut buffer = vec![0; ATTACHMENT_LINE_BYTES * 64];
+    loop {
+        let mut filled = 0;
+        while filled < buffer.len() {
+            match file.read(&mut buffer[filled..])? {
+                0 => break,
+                bytes_read => filled += bytes_read,
+            }
+        }
+        if filled == 0 {
+            return Ok(());
+        }
+        for line in buffer[..filled].chunks(ATTACHMENT_LINE_BYTES) {
+            destination.write_all(STANDARD.encode(line).as_bytes())?;
+            destination.write_all(b"\r\n")?;
+        }
+        if filled < buffer.len() {
+            return Ok(());
+        }
+    }
+}
+
+fn send_message(
+    smtp: &SmtpOptions,
+    device: &Device,
+    recipient: &str,
+    headers: &str,
+    attachment: &mut File,
+) -> Result<(), DeviceError> {
+    let security = smtp.security.unwrap_or_default();
+    let address = (smtp.server.as_str(), smtp.port.unwrap_or(DEFAULT_SMTP_PORT))
+        .to_socket_addrs()
+        .map_err(DeviceError::Unreachable)?
+        .next()
+        .ok_or_else(|| {
+            DeviceError::Unreachable(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("could not resolve {}", smtp.server),
+            ))
+        })?;
+    let tcp = TcpStream::connect_timeout(
+        &address,
+        Duration::from_secs(
+            device
+                .connect_timeout_secs
+                .unwrap_or(DEFAULT_CONNECT_TIMEOUT_SECS),
+        ),
+    )
+    .map_err(DeviceError::Unreachable)?;
+    let io_timeout = Some(Duration::from_secs(
+        device.io_timeout_secs.unwrap_or(DEFAULT_IO_TIMEOUT_SECS),
+    ));
+    tcp.set_read_timeout(io_timeout).map_err(DeviceError::Io)?;
+    tcp.set_write_timeout(io_timeout).map_err(DeviceError::Io)?;
+
+    let mut stream = match security {
+        SmtpSecurity::Tls => SmtpStream::Tls(Box::new(start_tls(smtp, tcp)?)),
+        _ => SmtpStream::Plain(tcp),
+    };
+    expect_reply(&mut stream, 220)?;
+    command(&mut stream, "EHLO a2o4", 250)?;
+
+    if security == SmtpSecurity::StartTls {
+        command(&mut stream, "STARTTLS", 220)?;
+        let SmtpStream::Plain(tcp) = stream else {
+            unreachable!()
+        };
+        stream = SmtpStream::Tls(Box::new(start_tls(smtp, tcp)?));
+        command(&mut stream, "EHLO a2o4", 250)?;
+    }
+
+    if let (Some(username), Some(password)) = (&smtp.username, &smtp.password) {
+        let credentials = STANDARD.encode(format!("\0{}\0{}", username, password));
+        command(&mut stream, &format!("AUTH PLAIN {}", credentials), 235)?;
+    }
+
+    command(&mut stream, &format!("MAIL FROM:<{}>", smtp.from), 250)?;
+    command(&mut stream, &format!("RCPT TO:<{}>", recipient), 250)?;
+    command(&mut stream, "DATA", 354)?;
+    // Lines starting with a dot would otherwise end the message early, base64 never starts one
+    let mut data = String::with_capacity(headers.len());
+    for line in headers.split_inclusive("\r\n") {
+        if line.starts_with('.') {
+            data.push('.');
+        }
+        data.push_str(line);
+    }
+    let mut writer = BufWriter::new(&mut stream);
+    writer
+        .write_all(data.as_bytes())
+        .and_then(|_| write_attachment(&mut writer, attachment))
+        .and_then(|_| write!(writer, "--{}--\r\n.\r\n", BOUNDARY))
+        .and_then(|_| writer.flush())
+        .map_err(DeviceError::Io)?;
+    drop(writer);
+    expect_reply(&mut stream, 250)?;
+    let _ = command(&mut stream, "QUIT", 221);
+    Ok(())
+}
+
+fn start_tls(smtp: &SmtpOptions, tcp: TcpStream) -> Result<TlsStream<TcpStream>, DeviceError> {
+    TlsConnector::new()
+        .map_err(|error| DeviceError::Io(io::Error::other(error)))?
+        .connect(&smtp.server, tcp)
+        .map_err(|error| DeviceError::Io(io::Error::other(error.to_string())))
+}
+
+fn command(stream: &mut SmtpStream, line: &str, expected_code: u16) -> Result<(), DeviceError> {
+    stream
+        .write_all(format!("{}\r\n", line).as_bytes())
+        .map_err(DeviceError::Io)?;
+    expect_reply(stream, expected_code)
+}
+
+/// Reads a possibly multi-line reply, e.g. `250-first\r\n250 last\r\n`, and checks its status code
+fn expect_reply(stream: &mut SmtpStream, expected_code: u16) -> Result<(), DeviceError> {
+    let mut reply = String::new();
+    loop {
+        let mut line = Vec::new();
+        let mut byte = [0];
+        while !line.ends_with(b"\r\n") {
+            if stream.read(&mut byte).map_err(DeviceError::Io)? == 0 {
+                return Err(DeviceError::Io(io::ErrorKind::UnexpectedEof.into()));
+            }
+            line.push(byte[0]);
+        }
+        let line = String::from_utf8_lossy(&line).trim_end().to_owned();
+        reply.push_str(&line);
+        // The last line of a reply has a space after the code instead of a dash
+        if line.as_bytes().get(3) != Some(&b'-') {
+            break;
+        }
+        reply.push('\n');
+    }
+
+    match reply.get(..3).and_then(|code| code.parse::<u16>().ok()) {
+        Some(code) if code == expected_code => Ok(()),
+        _ => Err(DeviceError::Smtp(reply)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::upload_work;
+    use crate::library::Library;
+    use crate::test_utils::{test_config, test_work};
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpListener;
+    use std::thread;
+
+    fn test_smtp(port: u16) -> SmtpOptions {
+        SmtpOptions {
+            server: "127.0.0.1".to_owned(),
+            port: Some(port),
+            security: Some(SmtpSecurity::None),
+            username: Some("me".to_owned()),
+            password: Some("password".to_owned()),
+            from: "me@example.com".to_owned(),
+            allowed_recipients: vec!["Someone@Kindle.com".to_owned()],
+            max_attachment_size_mb: Some(1),
+        }
+    }
+
+    fn test_device(kindle_email: &str) -> Device {
+        crate::test_utils::test_device(&format!(
+            "kind = \"email\"\nkindle_email = \"{}\"",
+            kindle_email
+        ))
+    }
+
+    /// Accepts one message and returns everything sent after `DATA`
+    fn smtp_sink(listener: TcpListener) -> thread::JoinHandle<String> {
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut writer = stream.try_clone().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut data = String::new();
+            let mut in_data = false;
+            writer.write_all(b"220 sink\r\n").unwrap();
+
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap() > 0 {
+                let reply: &[u8] = if in_data {
+                    if line == ".\r\n" {
+                        in_data = false;
+                        b"250 queued\r\n"
+                    } else {
+                        data.push_str(&line);
+                        b""
+                    }
+                } else if line.starts_with("EHLO") {
+                    b"250-sink\r\n250 AUTH PLAIN\r\n"
+                } else if line.starts_with("AUTH") {
+                    b"235 ok\r\n"
+                } else if line.starts_with("DATA") {
+                    in_data = true;
+                    b"354 go ahead\r\n"
+                } else if line.starts_with("QUIT") {
+                    writer.write_all(b"221 bye\r\n").unwrap();
+                    break;
+                } else {
+                    b"250 ok\r\n"
+                };
+                writer.write_all(reply).unwrap();
+                line.clear();
+            }
+            data
+        })
+    }
+
+    #[test]
+    fn emails_works_as_attachments() {
+        let download_path = tempfile::tempdir().unwrap();
+        std::fs::write(
+            download_path.path().join("Some Work.epub"),
+            "not really an epub",
+        )
+        .unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let sink = smtp_sink(listener);
+
+        let config = Config {
+            smtp: Some(test_smtp(port)),
+            ..test_config(download_path.path())
+        };
+        let work = test_work("12", "Some Work");
+
+        let mut library = Library::default();
+        library.add_work(
+            &work,
+            None,
+            DownloadFormat::EPUB,
+            &download_path.path().join("Some Work.epub"),
+        );
+
+        let device = test_device("someone@kindle.com");
+        let mut send = || upload_work(&mut library.entries[0], &device, &config, None);
+
+        assert_eq!(send().unwrap(), UploadOutcome::Uploaded);
+        let data = sink.join().unwrap();
+        assert!(data.contains("To: someone@kindle.com\r\n"));
+        assert!(data.contains("filename=\"Some Work.epub\""));
+        assert!(data.contains(&STANDARD.encode("not really an epub")));
+        assert!(data.ends_with(&format!("--{}--\r\n", BOUNDARY)));
+        // The sink is gone, so this would fail if it tried to send the work again
+        assert_eq!(send().unwrap(), UploadOutcome::Skipped);
+    }
+
+    #[test]
+    fn keeps_line_breaks_out_of_headers() {
+        let work = test_work("12", "Some Work\r\nBcc: someone@example.com");
+
+        let headers = build_headers(
+            "a2o4@example.com\r\nBcc: someone@example.com",
+            "someone@kindle.com",
+            &work,
+            "Some Work\nBcc: someone@example.com.epub",
+            DownloadFormat::EPUB,
+        );
+        assert!(!headers.contains("\r\nBcc:"));
+        assert!(!headers.contains("\nBcc:"));
+        assert!(headers.contains(&format!(
+            "Subject: =?UTF-8?B?{}?=\r\n",
+            STANDARD.encode("Some Work\r\nBcc: someone@example.com")
+        )));
+    }
+
+    #[test]
+    fn encodes_attachments_in_lines() {
+        let contents: Vec<u8> = (0..10_000).map(|byte| (byte % 251) as u8).collect();
+        let mut encoded = Vec::new();
+        write_attachment(&mut encoded, &mut contents.as_slice()).unwrap();
+
+        let expected: Vec<String> = STANDARD
+            .encode(&contents)
+            .as_bytes()
+            .chunks(76)
+            .map(|line| format!("{}\r\n", std::str::from_utf8(line).unwrap()))
+            .collect();
+        assert_eq!(String::from_utf8(encoded).unwrap(), expected.concat());
+    }
+
+    #[test]
+    fn only_sends_to_allowed_recipients() {
+        let smtp = test_smtp(25);
+
+        assert!(get_recipient(&test_device("someone@kindle.com"), &smtp).is_ok());
+        assert!(matches!(
+            get_recipient(&test_device("someone@kindle.co"), &smtp),
+            Err(DeviceError::RecipientNotAllowed(_))
+        ));
+    }
+
+    #[test]
+    fn checks_attachment_size() {
+        let smtp = test_smtp(25);
+        let path = Path::new("Some Work.epub");
+
+        assert!(check_attachment_size(path, 700 * 1024, &smtp).is_ok());
+        assert!(matches!(
+            check_attachment_size(path, 800 * 1024, &smtp),
+            Err(DeviceError::TooLarge(_, 1048576))
+        ));
+    }
+}
@@ -0,0 +1,184 @@
+use crate::ao3::common::{filter_fandoms, FandomTrace};
+use crate::ao3::tags::TagResolver;
+use crate::config::{Config, DeviceKind};
+use crate::device::get_remote_file_paths;
+use crate::filters::{check_device_filters, FilterReason};
+use crate::library::LibraryEntry;
+
+use std::path::PathBuf;
+
+/// Where a work ends up on a device
+#[derive(Debug, PartialEq)]
+pub enum Placement {
+    /// The paths `upload_work` writes to, more than one for crossovers with a copy in every fandom
+    Uploaded(Vec<PathBuf>),
+    Emailed,
+    Filtered(FilterReason),
+}
+
+/// Why a work goes where it does, see `explain_work`
+#[derive(Debug)]
+pub struct WorkExplanation {
+    pub title: String,
+    pub trace: FandomTrace,
+    /// The folder stored with the work when it was chosen under a different config than the current one
+    pub stored_fandom: Option<String>,
+    pub devices: Vec<(String, Placement)>,
+}
+
+impl std::fmt::Display for WorkExplanation {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(f, "{}", self.title)?;
+        write!(f, "{}", self.trace)?;
+        if let Some(stored_fandom) = &self.stored_fandom {
+            write!(
+                f,
+                "\n  but it was downloaded into {}, download it again to use the current config",
+                stored_fandom
+            )?;
+        }
+        for (device, placement) in &self.devices {
+            match placement {
+                Placement::Uploaded(paths) => {
+                    for path in paths {
+                        write!(f, "\n{}: {}", device, path.display())?;
+                    }
+                }
+                Placement::Emailed => write!(f, "\n{}: emailed, there are no folders", device)?,
+                Placement::Filtered(reason) => write!(f, "\n{}: not sent, {}", device, reason)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Runs the work's fandoms through `filter_fandoms` with the current config, and works out where
+/// `upload_work` would put the work on each device as it is stored now
+pub fn explain_work(
+    entry: &LibraryEntry,
+    config: &Config,
+    resolver: &mut TagResolver,
+) -> WorkExplanation {
+    let work = &entry.work;
+    let trace = filter_fandoms(&work.fandoms, config, resolver);
+    let stored_fandom = (trace.fandom != work.filtered_fandom
+        || trace.crossover_fandoms != work.crossover_fandoms)
+        .then(|| work.filtered_fandom.clone());
+
+    let devices = config
+        .devices
+        .iter()
+        .map(|device| {
+            let placement = if let Err(reason) = check_device_filters(work, device) {
+                Placement::Filtered(reason)
+            } else if device.kind == Some(DeviceKind::Email) {
+                Placement::Emailed
+            } else {
+                let download_format = entry
+                    .get_device_format(device)
+                    .unwrap_or(device.get_formats()[0]);
+                Placement::Uploaded(get_remote_file_paths(
+                    work,
+                    device,
+                    config,
+                    download_format,
+                    entry.series_id.as_ref(),
+                ))
+            };
+            (device.name.clone(), placement)
+        })
+        .collect();
+
+    WorkExplanation {
+        title: work.title.clone(),
+        trace,
+        stored_fandom,
+        devices,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ao3::common::FandomStep;
+    use crate::ao3::work::Work;
+    use crate::config::CrossoverPolicy;
+    use std::collections::HashMap;
+
+    #[test]
+    fn explains_each_step() {
+        let config: Config = toml::from_str(
+            r#"
+            download_path = "downloads"
+
+            [[devices]]
+            name = "Kindle"
+            download_folder = "/fanfics"
+
+            [[devices]]
+            name = "Phone"
+            download_folder = "/fanfics"
+            filters = { blocked_fandoms = ["Multiple"] }
+
+            [fandom_map]
+            "glob:Persona *" = "Persona"
+
+            [fandom_filter]
+            "Persona" = ["Shin Megami Tensei"]
+            "#,
+        )
+        .unwrap();
+        let fandoms = vec![
+            "Persona 5".to_owned(),
+            "Shin Megami Tensei".to_owned(),
+            "Fallout".to_owned(),
+        ];
+        let work = Work {
+            id: "12".to_owned(),
+            title: "Some Work".to_owned(),
+            filtered_fandom: filter_fandoms(&fandoms, &config, &mut TagResolver::default()).fandom,
+            fandoms,
+            series: HashMap::new(),
+            ..Default::default()
+        };
+
+        let explanation = explain_work(
+            &LibraryEntry::new(&work, None),
+            &config,
+            &mut TagResolver::default(),
+        );
+
+        assert_eq!(
+            explanation.trace.steps,
+            vec![
+                FandomStep::Mapped {
+                    fandom: "Persona 5".to_owned(),
+                    rule: "glob:Persona *".to_owned(),
+                    mapped_fandom: "Persona".to_owned(),
+                },
+                FandomStep::Filtered {
+                    fandom: "Shin Megami Tensei".to_owned(),
+                    because_of: "Persona".to_owned(),
+                },
+                FandomStep::Crossover {
+                    fandoms: vec!["Persona".to_owned(), "Fallout".to_owned()],
+                    policy: CrossoverPolicy::Folder,
+                },
+            ]
+        );
+        assert_eq!(explanation.stored_fandom, None);
+        assert_eq!(
+            explanation.devices,
+            vec![
+                (
+                    "Kindle".to_owned(),
+                    Placement::Uploaded(vec![PathBuf::from("/fanfics/Multiple/Some Work.epub")])
+                ),
+                (
+                    "Phone".to_owned(),
+                    Placement::Filtered(FilterReason::FandomBlocked("Multiple".to_owned()))
+                ),
+            ]
+        );
+    }
+}
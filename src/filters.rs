@@ -0,0 +1,329 @@
+use crate::ao3::common::Rating;
+use crate::ao3::series::Series;
+use crate::ao3::work::Work;
+use crate::config::{Config, ContentFilters, Device, DownloadFilters, SeriesFilterMode};
+
+/// Why a filter ruled a work out
+#[derive(Debug, PartialEq)]
+pub enum FilterReason {
+    FandomNotAllowed(String),
+    FandomBlocked(String),
+    /// `None` when the work was stored before ratings were tracked
+    RatingTooHigh(Option<Rating>),
+    WarningBlocked(String),
+    MissingIncludedTag,
+    TagExcluded(String),
+    /// Another part of the series was filtered out with `series = "skip_series"`
+    SeriesSkipped(String),
+}
+
+impl std::fmt::Display for FilterReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            FilterReason::FandomNotAllowed(fandom) => {
+                write!(f, "fandom {} is not in allowed_fandoms", fandom)
+            }
+            FilterReason::FandomBlocked(fandom) => write!(f, "fandom {} is blocked", fandom),
+            FilterReason::RatingTooHigh(Some(rating)) => {
+                write!(f, "rated {}, above max_rating", rating)
+            }
+            FilterReason::RatingTooHigh(None) => {
+                write!(
+                    f,
+                    "rating unknown, download it again to check it against max_rating"
+                )
+            }
+            FilterReason::WarningBlocked(warning) => write!(f, "warning {} is blocked", warning),
+            FilterReason::MissingIncludedTag => write!(f, "has none of include_tags"),
+            FilterReason::TagExcluded(tag) => write!(f, "tag {} is excluded", tag),
+            FilterReason::SeriesSkipped(reason) => {
+                write!(f, "another part of the series was skipped, {}", reason)
+            }
+        }
+    }
+}
+
+/// Checks the work against the device's filters, `Ok` when it has none
+pub fn check_device_filters(work: &Work, device: &Device) -> Result<(), FilterReason> {
+    match &device.filters {
+        Some(filters) => check_filters(work, filters),
+        None => Ok(()),
+    }
+}
+
+/// Checks the work against the config's `[filters]`, `Ok` when there are none
+pub fn check_download_filters(work: &Work, config: &Config) -> Result<(), FilterReason> {
+    match &config.filters {
+        Some(filters) => check_filters(work, &filters.rules),
+        None => Ok(()),
+    }
+}
+
+/// Takes the parts the config's `[filters]` rule out of the series before it is downloaded,
+/// or every part with `series = "skip_series"`. Returns the titles of the parts that were skipped
+pub fn filter_series(series: &mut Series, config: &Config) -> Vec<(String, FilterReason)> {
+    match &config.filters {
+        Some(filters) => filter_parts(&mut series.works, filters),
+        None => Vec::new(),
+    }
+}
+
+fn filter_parts(works: &mut Vec<Work>, filters: &DownloadFilters) -> Vec<(String, FilterReason)> {
+    let (allowed, skipped): (Vec<_>, Vec<_>) = works
+        .drain(..)
+        .map(|work| {
+            let result = check_filters(&work, &filters.rules);
+            (work, result)
+        })
+        .partition(|(_, result)| result.is_ok());
+    let mut skipped: Vec<_> = skipped
+        .into_iter()
+        .filter_map(|(work, result)| result.err().map(|reason| (work.title, reason)))
+        .collect();
+
+    if skipped.is_empty() || filters.series.unwrap_or_default() == SeriesFilterMode::SkipPart {
+        *works = allowed.into_iter().map(|(work, _)| work).collect();
+    } else {
+        // The reason the whole series went is whatever ruled out its first filtered part
+        let first_reason = skipped[0].1.to_string();
+        skipped.extend(allowed.into_iter().map(|(work, _)| {
+            (
+                work.title,
+                FilterReason::SeriesSkipped(first_reason.clone()),
+            )
+        }));
+    }
+    skipped
+}
+
+/// The first filter that rules out the work, checked in the order they are listed in the config
+pub fn check_filters(work: &Work, filters: &ContentFilters) -> Result<(), FilterReason> {
+    // A crossover has to be allowed in each of its fandoms and is blocked by any one of them.
+    // The mapped fandoms cover every folder a copy goes in and the fandoms behind a crossover folder
+    let fandoms: Vec<&String> = if work.mapped_fandoms.is_empty() {
+        work.get_fandom_folders().collect()
+    } else {
+        work.mapped_fandoms.iter().collect()
+    };
+    if !filters.allowed_fandoms.is_empty() {
+        if let Some(fandom) = fandoms
+            .iter()
+            .find(|fandom| !contains(&filters.allowed_fandoms, fandom))
+        {
+            return Err(FilterReason::FandomNotAllowed((*fandom).clone()));
+        }
+    }
+    if let Some(fandom) = fandoms
+        .iter()
+        .find(|fandom| contains(&filters.blocked_fandoms, fandom))
+    {
+        return Err(FilterReason::FandomBlocked((*fandom).clone()));
+    }
+
+    if let Some(max_rating) = filters.max_rating {
+        if !work
+            .rating
+            .is_some_and(|rating| rating.is_within(max_rating))
+        {
+            return Err(FilterReason::RatingTooHigh(work.rating));
+        }
+    }
+
+    if let Some(warning) = work
+        .warnings
+        .iter()
+        .find(|warning| contains(&filters.blocked_warnings, warning))
+    {
+        return Err(FilterReason::WarningBlocked(warning.clone()));
+    }
+
+    let mut tags = work
+        .relationships
+        .iter()
+        .chain(&work.characters)
+        .chain(&work.additional_tags);
+    if !filters.include_tags.is_empty()
+        && !tags.clone().any(|tag| contains(&filters.include_tags, tag))
+    {
+        return Err(FilterReason::MissingIncludedTag);
+    }
+    if let Some(tag) = tags.find(|tag| contains(&filters.exclude_tags, tag)) {
+        return Err(FilterReason::TagExcluded(tag.clone()));
+    }
+
+    Ok(())
+}
+
+fn contains(names: &[String], name: &str) -> bool {
+    names
+        .iter()
+        .any(|listed| listed.to_lowercase() == name.to_lowercase())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::test_work;
+
+    fn tagged_work() -> Work {
+        Work {
+            rating: Some(Rating::Mature),
+            warnings: vec!["No Archive Warnings Apply".to_owned()],
+            relationships: vec!["A/B".to_owned()],
+            additional_tags: vec!["Fluff".to_owned()],
+            ..test_work("12", "Some Work")
+        }
+    }
+
+    fn test_filters(toml: &str) -> ContentFilters {
+        toml::from_str(toml).unwrap()
+    }
+
+    #[test]
+    fn checks_each_filter() {
+        let work = tagged_work();
+
+        assert_eq!(check_filters(&work, &test_filters("")), Ok(()));
+        assert_eq!(
+            check_filters(&work, &test_filters("allowed_fandoms = [\"Fandom 2\"]")),
+            Err(FilterReason::FandomNotAllowed("Fandom 1".to_owned()))
+        );
+        assert_eq!(
+            check_filters(&work, &test_filters("blocked_fandoms = [\"fandom 1\"]")),
+            Err(FilterReason::FandomBlocked("Fandom 1".to_owned()))
+        );
+        assert_eq!(
+            check_filters(
+                &work,
+                &test_filters("max_rating = \"Teen And Up Audiences\"")
+            ),
+            Err(FilterReason::RatingTooHigh(Some(Rating::Mature)))
+        );
+        assert_eq!(
+            check_filters(&work, &test_filters("max_rating = \"Explicit\"")),
+            Ok(())
+        );
+        assert_eq!(
+            check_filters(
+                &work,
+                &test_filters("blocked_warnings = [\"No Archive Warnings Apply\"]")
+            ),
+            Err(FilterReason::WarningBlocked(
+                "No Archive Warnings Apply".to_owned()
+            ))
+        );
+        assert_eq!(
+            check_filters(&work, &test_filters("include_tags = [\"Angst\"]")),
+            Err(FilterReason::MissingIncludedTag)
+        );
+        assert_eq!(
+            check_filters(&work, &test_filters("include_tags = [\"a/b\"]")),
+            Ok(())
+        );
+        assert_eq!(
+            check_filters(&work, &test_filters("exclude_tags = [\"Fluff\"]")),
+            Err(FilterReason::TagExcluded("Fluff".to_owned()))
+        );
+        // Warnings are only matched by blocked_warnings
+        assert_eq!(
+            check_filters(
+                &work,
+                &test_filters("exclude_tags = [\"No Archive Warnings Apply\"]")
+            ),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn checks_every_fandom_of_a_crossover() {
+        let crossover = Work {
+            fandoms: vec!["Fandom 1".to_owned(), "Fandom 2".to_owned()],
+            filtered_fandom: "Crossover".to_owned(),
+            mapped_fandoms: vec!["Fandom 1".to_owned(), "Fandom 2".to_owned()],
+            ..tagged_work()
+        };
+
+        assert_eq!(
+            check_filters(
+                &crossover,
+                &test_filters("allowed_fandoms = [\"Fandom 1\"]")
+            ),
+            Err(FilterReason::FandomNotAllowed("Fandom 2".to_owned()))
+        );
+        assert_eq!(
+            check_filters(
+                &crossover,
+                &test_filters("allowed_fandoms = [\"Fandom 1\", \"Fandom 2\"]")
+            ),
+            Ok(())
+        );
+        assert_eq!(
+            check_filters(
+                &crossover,
+                &test_filters("blocked_fandoms = [\"fandom 2\"]")
+            ),
+            Err(FilterReason::FandomBlocked("Fandom 2".to_owned()))
+        );
+
+        // Works stored before their mapped fandoms were kept are checked by their folders
+        let every = Work {
+            crossover_fandoms: vec!["Fandom 2".to_owned()],
+            ..tagged_work()
+        };
+        assert_eq!(
+            check_filters(&every, &test_filters("blocked_fandoms = [\"Fandom 2\"]")),
+            Err(FilterReason::FandomBlocked("Fandom 2".to_owned()))
+        );
+    }
+
+    #[test]
+    fn unknown_and_unrated_works_fail_max_rating() {
+        let filters = test_filters("max_rating = \"Mature\"");
+        let mut work = tagged_work();
+
+        work.rating = None;
+        assert_eq!(
+            check_filters(&work, &filters),
+            Err(FilterReason::RatingTooHigh(None))
+        );
+        work.rating = Some(Rating::NotRated);
+        assert_eq!(
+            check_filters(&work, &filters),
+            Err(FilterReason::RatingTooHigh(Some(Rating::NotRated)))
+        );
+    }
+
+    #[test]
+    fn filters_series_parts() {
+        let mut blocked = tagged_work();
+        blocked.title = "Blocked Part".to_owned();
+        blocked.additional_tags.push("Angst".to_owned());
+        let works = vec![tagged_work(), blocked];
+
+        let mut skip_part = works.clone();
+        let skipped = filter_parts(
+            &mut skip_part,
+            &toml::from_str("exclude_tags = [\"Angst\"]").unwrap(),
+        );
+        assert_eq!(
+            skipped,
+            vec![(
+                "Blocked Part".to_owned(),
+                FilterReason::TagExcluded("Angst".to_owned())
+            )]
+        );
+        assert_eq!(skip_part.len(), 1);
+
+        let mut skip_series = works;
+        let skipped = filter_parts(
+            &mut skip_series,
+            &toml::from_str("exclude_tags = [\"Angst\"]\nseries = \"skip_series\"").unwrap(),
+        );
+        assert_eq!(skipped.len(), 2);
+        assert_eq!(
+            skipped[1].1,
+            FilterReason::SeriesSkipped("tag Angst is excluded".to_owned())
+        );
+        assert!(skip_series.is_empty());
+    }
+}
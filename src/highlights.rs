@@ -0,0 +1,237 @@
+use crate::config::{Config, Device};
+use crate::device::DeviceError;
+use crate::koreader::{parse_highlights, read_latest_koreader_metadata, Highlight};
+use crate::layout::Layout;
+use crate::library::{Library, LibraryEntry};
+use crate::transport::Transport;
+
+use anyhow::Result;
+use serde::Serialize;
+use std::fs::{create_dir_all, write};
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ExportFormat {
+    Markdown,
+    Json,
+}
+
+#[derive(Debug, Serialize)]
+pub struct WorkHighlights {
+    pub work_id: String,
+    pub title: String,
+    pub author: String,
+    pub device: String,
+    pub chapters: Vec<ChapterHighlights>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ChapterHighlights {
+    pub chapter: Option<String>,
+    pub highlights: Vec<Highlight>,
+}
+
+impl WorkHighlights {
+    pub fn new(
+        entry: &LibraryEntry,
+        device: &Device,
+        highlights: Vec<Highlight>,
+    ) -> WorkHighlights {
+        let mut chapters: Vec<ChapterHighlights> = Vec::new();
+        for highlight in highlights {
+            match chapters
+                .iter_mut()
+                .find(|chapter| chapter.chapter == highlight.chapter)
+            {
+                Some(chapter) => chapter.highlights.push(highlight),
+                None => chapters.push(ChapterHighlights {
+                    chapter: highlight.chapter.clone(),
+                    highlights: vec![highlight],
+                }),
+            }
+        }
+
+        WorkHighlights {
+            work_id: entry.work.id.clone(),
+            title: entry.work.title.clone(),
+            author: entry.work.author.clone(),
+            device: device.name.clone(),
+            chapters,
+        }
+    }
+
+    pub fn to_markdown(&self) -> String {
+        let mut markdown = format!(
+            "# {}\n\nby {}, https://archiveofourown.org/works/{}\n\nHighlighted on {}\n",
+            self.title, self.author, self.work_id, self.device
+        );
+
+        for chapter in &self.chapters {
+            markdown.push_str(&format!(
+                "\n## {}\n",
+                chapter.chapter.as_deref().unwrap_or("Unknown chapter")
+            ));
+            for highlight in &chapter.highlights {
+                markdown.push('\n');
+                for line in highlight.text.lines() {
+                    markdown.push_str(&format!("> {}\n", line));
+                }
+                if let Some(note) = &highlight.note {
+                    markdown.push_str(&format!("\n{}\n", note));
+                }
+                if let Some(datetime) = &highlight.datetime {
+                    markdown.push_str(&format!("\n_{}_\n", datetime));
+                }
+            }
+        }
+
+        markdown
+    }
+
+    /// HTML ready to paste into an AO3 comment, quoting each highlight with its note underneath
+    pub fn to_comment_draft(&self) -> String {
+        let mut comment = String::new();
+
+        for chapter in &self.chapters {
+            if let Some(chapter_name) = &chapter.chapter {
+                comment.push_str(&format!(
+                    "<p><strong>{}</strong></p>\n",
+                    escape_html(chapter_name)
+                ));
+            }
+            for highlight in &chapter.highlights {
+                comment.push_str(&format!(
+                    "<blockquote>{}</blockquote>\n",
+                    escape_html(&highlight.text).replace('\n', "<br />")
+                ));
+                if let Some(note) = &highlight.note {
+                    comment.push_str(&format!(
+                        "<p>{}</p>\n",
+                        escape_html(note).replace('\n', "<br />")
+                    ));
+                }
+            }
+        }
+
+        comment
+    }
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+/// Writes the highlights of every work opened on the device to `download_path/highlights/<device>/<work id>.<md|json>`
+pub fn export_highlights(
+    library: &Library,
+    config: &Config,
+    device: &Device,
+    connection: &dyn Transport,
+    export_format: ExportFormat,
+    comment_drafts: bool,
+) -> Result<Vec<PathBuf>, DeviceError> {
+    let layout = Layout::new(config, Some(device));
+    let export_folder = Path::new(&config.download_path)
+        .join("highlights")
+        .join(&device.name);
+    let mut exported = Vec::new();
+
+    for entry in &library.entries {
+        let Some(download_format) = entry.get_device_format(device) else {
+            continue;
+        };
+        let remote_file_paths = entry.get_device_paths(device, &layout, download_format);
+        let Some((metadata, _)) =
+            read_latest_koreader_metadata(connection, device, &remote_file_paths)
+        else {
+            continue;
+        };
+        let highlights = parse_highlights(&metadata);
+        if highlights.is_empty() {
+            continue;
+        }
+
+        let work_highlights = WorkHighlights::new(entry, device, highlights);
+        create_dir_all(&export_folder).map_err(DeviceError::Io)?;
+
+        let (extension, contents) = match export_format {
+            ExportFormat::Markdown => ("md", work_highlights.to_markdown()),
+            ExportFormat::Json => (
+                "json",
+                serde_json::to_string_pretty(&work_highlights)
+                    .map_err(|error| DeviceError::Io(error.into()))?,
+            ),
+        };
+        let export_path = export_folder.join(format!("{}.{}", entry.work.id, extension));
+        write(&export_path, contents).map_err(DeviceError::Io)?;
+        exported.push(export_path);
+
+        if comment_drafts {
+            let comment_path = export_folder.join(format!("{}.comment.html", entry.work.id));
+            write(&comment_path, work_highlights.to_comment_draft()).map_err(DeviceError::Io)?;
+            exported.push(comment_path);
+        }
+    }
+
+    Ok(exported)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{test_device, test_work};
+
+    fn test_highlights() -> WorkHighlights {
+        let entry = LibraryEntry::new(&test_work("12", "Some Work"), None);
+        let device = test_device("");
+        let highlight = |chapter: &str, text: &str, note: Option<&str>| Highlight {
+            chapter: Some(chapter.to_owned()),
+            text: text.to_owned(),
+            note: note.map(str::to_owned),
+            datetime: None,
+        };
+
+        WorkHighlights::new(
+            &entry,
+            &device,
+            vec![
+                highlight("Chapter 1", "First", None),
+                highlight("Chapter 2", "Second <3", Some("cute & sad")),
+                highlight("Chapter 1", "Third", None),
+            ],
+        )
+    }
+
+    #[test]
+    fn groups_highlights_by_chapter() {
+        let work_highlights = test_highlights();
+
+        assert_eq!(work_highlights.chapters.len(), 2);
+        assert_eq!(
+            work_highlights.chapters[0].chapter,
+            Some("Chapter 1".to_owned())
+        );
+        assert_eq!(work_highlights.chapters[0].highlights.len(), 2);
+    }
+
+    #[test]
+    fn writes_markdown() {
+        assert_eq!(
+            test_highlights().to_markdown(),
+            "# Some Work\n\nby Some Author, https://archiveofourown.org/works/12\n\nHighlighted on Kindle\n\
+             \n## Chapter 1\n\n> First\n\n> Third\n\
+             \n## Chapter 2\n\n> Second <3\n\ncute & sad\n"
+        );
+    }
+
+    #[test]
+    fn escapes_comment_drafts() {
+        assert_eq!(
+            test_highlights().to_comment_draft(),
+            "<p><strong>Chapter 1</strong></p>\n<blockquote>First</blockquote>\n<blockquote>Third</blockquote>\n\
+             <p><strong>Chapter 2</strong></p>\n<blockquote>Second &lt;3</blockquote>\n<p>cute &amp; sad</p>\n"
+        );
+    }
+}
@@ -0,0 +1,699 @@
+use crate::config::{Config, Device};
+use crate::device::{get_koreader_metadata_folder, DeviceError};
+use crate::layout::Layout;
+use crate::library::Library;
+use crate::transport::Transport;
+
+use anyhow::{Error, Result};
+use serde::{Deserialize, Serialize};
+use std::iter::Peekable;
+use std::path::{Path, PathBuf};
+use std::str::Chars;
+
+/// A value from one of the Lua tables KOReader saves its settings and metadata as
+#[derive(Debug, PartialEq, Clone)]
+pub enum LuaValue {
+    Nil,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Table(Vec<(LuaValue, LuaValue)>),
+}
+
+impl LuaValue {
+    /// Looks up a string key, e.g. `["summary"]`, in a table
+    pub fn get(&self, key: &str) -> Option<&LuaValue> {
+        match self {
+            LuaValue::Table(entries) => entries
+                .iter()
+                .find(|(entry_key, _)| matches!(entry_key, LuaValue::String(k) if k == key))
+                .map(|(_, value)| value),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            LuaValue::String(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            LuaValue::Number(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    /// Values of the table in order, for the array-like tables KOReader writes as `[1] = ..., [2] = ...`
+    pub fn values(&self) -> Vec<&LuaValue> {
+        match self {
+            LuaValue::Table(entries) => entries.iter().map(|(_, value)| value).collect(),
+            _ => Vec::new(),
+        }
+    }
+}
+
+/// Writes a value back out the way KOReader does, so files we change still look like its own
+pub fn write_lua_table(value: &LuaValue) -> String {
+    let mut source = String::from("return ");
+    write_lua_value(value, 0, &mut source);
+    source.push('\n');
+    source
+}
+
+fn write_lua_value(value: &LuaValue, depth: usize, source: &mut String) {
+    match value {
+        LuaValue::Nil => source.push_str("nil"),
+        LuaValue::Bool(value) => source.push_str(&value.to_string()),
+        LuaValue::Number(value) => source.push_str(&value.to_string()),
+        LuaValue::String(value) => write_lua_string(value, source),
+        LuaValue::Table(entries) => {
+            source.push_str("{\n");
+            for (key, value) in entries {
+                source.push_str(&"    ".repeat(depth + 1));
+                source.push('[');
+                write_lua_value(key, depth + 1, source);
+                source.push_str("] = ");
+                write_lua_value(value, depth + 1, source);
+                source.push_str(",\n");
+            }
+            source.push_str(&"    ".repeat(depth));
+            source.push('}');
+        }
+    }
+}
+
+/// The inverse of [`parse_lua_string`], escaping like Lua's `%q`
+fn write_lua_string(value: &str, source: &mut String) {
+    source.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => source.push_str("\\\""),
+            '\\' => source.push_str("\\\\"),
+            '\n' => source.push_str("\\n"),
+            '\r' => source.push_str("\\r"),
+            '\0' => source.push_str("\\0"),
+            c => source.push(c),
+        }
+    }
+    source.push('"');
+}
+
+/// Parses the `return { ... }` files KOReader writes, such as `metadata.epub.lua` in a book's `.sdr` folder
+pub fn parse_lua_table(source: &str) -> Result<LuaValue> {
+    let mut chars = source.chars().peekable();
+    skip_whitespace_and_comments(&mut chars);
+
+    let mut keyword = String::new();
+    while let Some(c) = chars.peek().filter(|c| c.is_alphabetic()) {
+        keyword.push(*c);
+        chars.next();
+    }
+    if keyword != "return" {
+        return Err(Error::msg("Expected the file to start with return"));
+    }
+
+    parse_lua_value(&mut chars)
+}
+
+fn skip_whitespace_and_comments(chars: &mut Peekable<Chars>) {
+    loop {
+        while chars.peek().is_some_and(|c| c.is_whitespace()) {
+            chars.next();
+        }
+        let mut lookahead = chars.clone();
+        if lookahead.next() == Some('-') && lookahead.next() == Some('-') {
+            for c in chars.by_ref() {
+                if c == '\n' {
+                    break;
+                }
+            }
+        } else {
+            return;
+        }
+    }
+}
+
+fn parse_lua_value(chars: &mut Peekable<Chars>) -> Result<LuaValue> {
+    skip_whitespace_and_comments(chars);
+    match chars.peek() {
+        Some('{') => parse_lua_table_body(chars),
+        Some('"') | Some('\'') => Ok(LuaValue::String(parse_lua_string(chars)?)),
+        Some(c) if c.is_ascii_digit() || *c == '-' || *c == '.' => {
+            let mut number = String::new();
+            while let Some(c) = chars
+                .peek()
+                .filter(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '+'))
+            {
+                number.push(*c);
+                chars.next();
+            }
+            number
+                .parse()
+                .map(LuaValue::Number)
+                .map_err(|_| Error::msg(format!("Invalid number {}", number)))
+        }
+        Some(c) if c.is_alphabetic() => {
+            let mut word = String::new();
+            while let Some(c) = chars.peek().filter(|c| c.is_alphanumeric() || **c == '_') {
+                word.push(*c);
+                chars.next();
+            }
+            match word.as_str() {
+                "true" => Ok(LuaValue::Bool(true)),
+                "false" => Ok(LuaValue::Bool(false)),
+                "nil" => Ok(LuaValue::Nil),
+                _ => Err(Error::msg(format!("Unexpected {}", word))),
+            }
+        }
+        other => Err(Error::msg(format!("Unexpected {:?}", other))),
+    }
+}
+
+fn parse_lua_table_body(chars: &mut Peekable<Chars>) -> Result<LuaValue> {
+    chars.next(); // {
+    let mut entries = Vec::new();
+    let mut next_index = 1.0;
+
+    loop {
+        skip_whitespace_and_comments(chars);
+        match chars.peek() {
+            Some('}') => {
+                chars.next();
+                return Ok(LuaValue::Table(entries));
+            }
+            Some(',') | Some(';') => {
+                chars.next();
+            }
+            Some('[') => {
+                chars.next();
+                let key = parse_lua_value(chars)?;
+                skip_whitespace_and_comments(chars);
+                expect_char(chars, ']')?;
+                skip_whitespace_and_comments(chars);
+                expect_char(chars, '=')?;
+                entries.push((key, parse_lua_value(chars)?));
+            }
+            Some(c) if c.is_alphabetic() || *c == '_' => {
+                let mut lookahead = chars.clone();
+                let mut name = String::new();
+                while let Some(c) = lookahead
+                    .peek()
+                    .filter(|c| c.is_alphanumeric() || **c == '_')
+                {
+                    name.push(*c);
+                    lookahead.next();
+                }
+                skip_whitespace_and_comments(&mut lookahead);
+                if lookahead.peek() == Some(&'=') {
+                    lookahead.next();
+                    *chars = lookahead;
+                    entries.push((LuaValue::String(name), parse_lua_value(chars)?));
+                } else {
+                    entries.push((LuaValue::Number(next_index), parse_lua_value(chars)?));
+                    next_index += 1.0;
+                }
+            }
+            Some(_) => {
+                entries.push((LuaValue::Number(next_index), parse_lua_value(chars)?));
+                next_index += 1.0;
+            }
+            None => return Err(Error::msg("Unterminated table")),
+        }
+    }
+}
+
+fn expect_char(chars: &mut Peekable<Chars>, expected: char) -> Result<()> {
+    match chars.next() {
+        Some(c) if c == expected => Ok(()),
+        other => Err(Error::msg(format!(
+            "Expected {} but found {:?}",
+            expected, other
+        ))),
+    }
+}
+
+/// Handles the escapes Lua's `%q` produces, which is how KOReader writes strings
+fn parse_lua_string(chars: &mut Peekable<Chars>) -> Result<String> {
+    let quote = chars.next().unwrap();
+    // Decimal escapes are single bytes, which can be parts of a multi-byte character
+    let mut bytes = Vec::new();
+    let mut buffer = [0; 4];
+
+    loop {
+        match chars.next() {
+            Some(c) if c == quote => return Ok(String::from_utf8_lossy(&bytes).to_string()),
+            Some('\\') => match chars.next() {
+                Some('n') | Some('\n') => bytes.push(b'\n'),
+                Some('r') => bytes.push(b'\r'),
+                Some('t') => bytes.push(b'\t'),
+                Some(c) if c.is_ascii_digit() => {
+                    let mut code = c.to_string();
+                    while code.len() < 3 && chars.peek().is_some_and(|c| c.is_ascii_digit()) {
+                        code.push(chars.next().unwrap());
+                    }
+                    bytes.push(code.parse::<u8>()?);
+                }
+                Some(c) => bytes.extend_from_slice(c.encode_utf8(&mut buffer).as_bytes()),
+                None => return Err(Error::msg("Unterminated string")),
+            },
+            Some(c) => bytes.extend_from_slice(c.encode_utf8(&mut buffer).as_bytes()),
+            None => return Err(Error::msg("Unterminated string")),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize)]
+pub enum ReadingStatus {
+    Reading,
+    Finished,
+    Abandoned,
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct ReadingProgress {
+    pub percent_finished: f64,
+    pub status: ReadingStatus,
+    /// Unix timestamp of when KOReader last saved the book's metadata
+    pub last_read: Option<u64>,
+    /// Unix timestamp of the day the book was marked as finished
+    #[serde(default)]
+    pub finished_on: Option<u64>,
+}
+
+impl std::fmt::Display for ReadingProgress {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{:.0}% ({:?})",
+            self.percent_finished * 100.0,
+            self.status
+        )
+    }
+}
+
+pub fn parse_reading_progress(metadata: &LuaValue, last_read: Option<u64>) -> ReadingProgress {
+    let status = match metadata
+        .get("summary")
+        .and_then(|summary| summary.get("status"))
+        .and_then(LuaValue::as_str)
+    {
+        Some("complete") => ReadingStatus::Finished,
+        Some("abandoned") => ReadingStatus::Abandoned,
+        _ => ReadingStatus::Reading,
+    };
+
+    // summary.modified is the day the status last changed
+    let finished_on = if status == ReadingStatus::Finished {
+        metadata
+            .get("summary")
+            .and_then(|summary| summary.get("modified"))
+            .and_then(LuaValue::as_str)
+            .and_then(parse_date)
+            .or(last_read)
+    } else {
+        None
+    };
+
+    ReadingProgress {
+        percent_finished: metadata
+            .get("percent_finished")
+            .and_then(LuaValue::as_f64)
+            .unwrap_or(0.0),
+        status,
+        last_read,
+        finished_on,
+    }
+}
+
+/// Converts a `YYYY-MM-DD` date into a unix timestamp at midnight UTC
+pub fn parse_date(date: &str) -> Option<u64> {
+    let mut parts = date.trim().splitn(3, '-');
+    let year: i64 = parts.next()?.parse().ok()?;
+    let month: i64 = parts.next()?.parse().ok()?;
+    let day: i64 = parts.next()?.parse().ok()?;
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+
+    // Days since the epoch from http://howardhinnant.github.io/date_algorithms.html#days_from_civil
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146097 + day_of_era - 719468;
+
+    u64::try_from(days * 86400).ok()
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize)]
+pub struct Highlight {
+    pub chapter: Option<String>,
+    pub text: String,
+    pub note: Option<String>,
+    pub datetime: Option<String>,
+}
+
+/// Reads highlights from `annotations`, or the `highlight` table older KOReader versions used instead
+pub fn parse_highlights(metadata: &LuaValue) -> Vec<Highlight> {
+    let get_string = |value: &LuaValue, key: &str| {
+        value
+            .get(key)
+            .and_then(LuaValue::as_str)
+            .map(str::to_owned)
+            .filter(|string| !string.is_empty())
+    };
+
+    let annotations = match metadata.get("annotations") {
+        Some(annotations) => annotations.values(),
+        // Old highlights are grouped by page, and only have notes in the separate bookmarks table
+        None => metadata
+            .get("highlight")
+            .map(|pages| {
+                pages
+                    .values()
+                    .into_iter()
+                    .flat_map(LuaValue::values)
+                    .collect()
+            })
+            .unwrap_or_default(),
+    };
+
+    annotations
+        .into_iter()
+        .filter_map(|annotation| {
+            Some(Highlight {
+                chapter: get_string(annotation, "chapter"),
+                text: get_string(annotation, "text")?,
+                note: get_string(annotation, "note"),
+                datetime: get_string(annotation, "datetime"),
+            })
+        })
+        .collect()
+}
+
+/// Where KOReader keeps the metadata for a book, e.g. `book.sdr/metadata.epub.lua`
+pub fn get_koreader_metadata_path(remote_file_path: &Path) -> PathBuf {
+    let extension = remote_file_path
+        .extension()
+        .map(|extension| extension.to_string_lossy().to_string())
+        .unwrap_or_default();
+    get_koreader_metadata_folder(remote_file_path).join(format!("metadata.{}.lua", extension))
+}
+
+/// Reads and parses a book's KOReader metadata, `None` if the book hasn't been opened on the device
+pub fn read_koreader_metadata(
+    connection: &dyn Transport,
+    remote_file_path: &Path,
+) -> Result<Option<(LuaValue, Option<u64>)>, DeviceError> {
+    let metadata_path = get_koreader_metadata_path(remote_file_path);
+    let Some(source) = connection.read_to_string(&metadata_path)? else {
+        return Ok(None);
+    };
+    let last_read = connection.stat(&metadata_path)?.and_then(|stat| stat.mtime);
+
+    match parse_lua_table(&source) {
+        Ok(metadata) => Ok(Some((metadata, last_read))),
+        Err(error) => {
+            eprintln!("Failed to parse {}: {}", metadata_path.display(), error);
+            Ok(None)
+        }
+    }
+}
+
+/// The metadata of whichever copy of the work was read last, crossovers can have one in each fandom folder.
+/// A copy that can't be read is reported and the others are still tried
+pub fn read_latest_koreader_metadata(
+    connection: &dyn Transport,
+    device: &Device,
+    remote_file_paths: &[PathBuf],
+) -> Option<(LuaValue, Option<u64>)> {
+    remote_file_paths
+        .iter()
+        .filter_map(
+            |remote_file_path| match read_koreader_metadata(connection, remote_file_path) {
+                Ok(metadata) => metadata,
+                Err(error) => {
+                    eprintln!(
+                        "Failed to read KOReader metadata for {} on {}: {}",
+                        remote_file_path.display(),
+                        device.name,
+                        error
+                    );
+                    None
+                }
+            },
+        )
+        .max_by_key(|(_, last_read)| *last_read)
+}
+
+/// Records the reading progress of every work in the library that has been opened on the device,
+/// returning how many there were
+pub fn fetch_reading_progress(
+    library: &mut Library,
+    config: &Config,
+    device: &Device,
+    connection: &dyn Transport,
+) -> usize {
+    let layout = Layout::new(config, Some(device));
+    let mut num_updated = 0;
+
+    for entry in library.entries.iter_mut() {
+        let Some(download_format) = entry.get_device_format(device) else {
+            continue;
+        };
+        let remote_file_paths = entry.get_device_paths(device, &layout, download_format);
+        if let Some((metadata, last_read)) =
+            read_latest_koreader_metadata(connection, device, &remote_file_paths)
+        {
+            entry.reading_progress.insert(
+                device.name.clone(),
+                parse_reading_progress(&metadata, last_read),
+            );
+            num_updated += 1;
+        }
+    }
+
+    num_updated
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ao3::common::DownloadFormat;
+    use crate::ao3::work::Work;
+    use crate::test_utils::{test_config, test_local_device, test_work};
+    use crate::transport::connect;
+    use std::fs::{create_dir_all, write};
+
+    const METADATA: &str = r#"-- we can read Lua syntax here!
+return {
+    ["bookmarks"] = {},
+    ["doc_path"] = "/mnt/us/fanfics/sorted/Fandom 1/Some \"Quoted\" Work.epub",
+    ["percent_finished"] = 0.5234,
+    ["summary"] = {
+        ["modified"] = "2024-05-02",
+        ["status"] = "complete",
+    },
+    ["stats"] = {
+        ["pages"] = 312,
+        ["highlights"] = 2,
+        ["notes"] = -1,
+    },
+    ["annotations"] = {
+        [1] = {
+            ["chapter"] = "Chapter 1",
+            ["text"] = "First line\
+second line",
+        },
+        [2] = {
+            ["chapter"] = "Chapter 3",
+            ["datetime"] = "2024-05-01 21:14:03",
+            ["note"] = "so good",
+            ["text"] = "caf\195\169",
+        },
+    },
+    ["readermenu"] = true,
+}
+"#;
+
+    #[test]
+    fn parses_koreader_metadata() {
+        let metadata = parse_lua_table(METADATA).unwrap();
+
+        assert_eq!(
+            metadata.get("doc_path").and_then(LuaValue::as_str),
+            Some("/mnt/us/fanfics/sorted/Fandom 1/Some \"Quoted\" Work.epub")
+        );
+        assert_eq!(
+            metadata
+                .get("stats")
+                .and_then(|stats| stats.get("notes"))
+                .and_then(LuaValue::as_f64),
+            Some(-1.0)
+        );
+        assert_eq!(metadata.get("readermenu"), Some(&LuaValue::Bool(true)));
+
+        let annotations = metadata.get("annotations").unwrap().values();
+        assert_eq!(annotations.len(), 2);
+        assert_eq!(
+            annotations[0].get("text").and_then(LuaValue::as_str),
+            Some("First line\nsecond line")
+        );
+        assert_eq!(
+            annotations[1].get("text").and_then(LuaValue::as_str),
+            Some("café")
+        );
+    }
+
+    #[test]
+    fn parses_highlights() {
+        let metadata = parse_lua_table(METADATA).unwrap();
+
+        assert_eq!(
+            parse_highlights(&metadata),
+            vec![
+                Highlight {
+                    chapter: Some("Chapter 1".to_owned()),
+                    text: "First line\nsecond line".to_owned(),
+                    note: None,
+                    datetime: None,
+                },
+                Highlight {
+                    chapter: Some("Chapter 3".to_owned()),
+                    text: "café".to_owned(),
+                    note: Some("so good".to_owned()),
+                    datetime: Some("2024-05-01 21:14:03".to_owned()),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_old_style_highlights() {
+        let metadata = parse_lua_table(
+            r#"return {
+                ["highlight"] = {
+                    [12] = {
+                        [1] = {
+                            ["chapter"] = "Chapter 2",
+                            ["datetime"] = "2023-01-01 10:00:00",
+                            ["text"] = "An old highlight",
+                        },
+                    },
+                },
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            parse_highlights(&metadata),
+            vec![Highlight {
+                chapter: Some("Chapter 2".to_owned()),
+                text: "An old highlight".to_owned(),
+                note: None,
+                datetime: Some("2023-01-01 10:00:00".to_owned()),
+            }]
+        );
+    }
+
+    #[test]
+    fn parses_reading_progress() {
+        let metadata = parse_lua_table(METADATA).unwrap();
+
+        assert_eq!(
+            parse_reading_progress(&metadata, Some(1714608000)),
+            ReadingProgress {
+                percent_finished: 0.5234,
+                status: ReadingStatus::Finished,
+                last_read: Some(1714608000),
+                finished_on: Some(1714608000),
+            }
+        );
+    }
+
+    #[test]
+    fn written_tables_parse_back() {
+        let metadata = parse_lua_table(METADATA).unwrap();
+
+        assert_eq!(
+            parse_lua_table(&write_lua_table(&metadata)).unwrap(),
+            metadata
+        );
+        assert_eq!(
+            write_lua_table(&LuaValue::Table(vec![(
+                LuaValue::Number(1.0),
+                LuaValue::String("say \"hi\"\n".to_owned())
+            )])),
+            "return {\n    [1] = \"say \\\"hi\\\"\\n\",\n}\n"
+        );
+    }
+
+    #[test]
+    fn parses_dates() {
+        assert_eq!(parse_date("1970-01-01"), Some(0));
+        assert_eq!(parse_date("2024-05-02"), Some(1714608000));
+        assert_eq!(parse_date("2000-03-01"), Some(951868800));
+        assert_eq!(parse_date("2024-13-02"), None);
+        assert_eq!(parse_date("yesterday"), None);
+    }
+
+    #[test]
+    fn metadata_path_uses_the_book_extension() {
+        assert_eq!(
+            get_koreader_metadata_path(Path::new("/fanfics/Fandom 1/2 - Mr. Work.epub")),
+            Path::new("/fanfics/Fandom 1/2 - Mr. Work.sdr/metadata.epub.lua")
+        );
+    }
+
+    #[test]
+    fn fetches_progress_from_every_copy_on_the_device() {
+        let test_folder = tempfile::tempdir().unwrap();
+        let device_folder = test_folder.path().join("device");
+        let device = test_local_device(&device_folder, "uses_koreader = true");
+        let connection = connect(&device).unwrap();
+        let config = test_config(&test_folder.path().join("downloads"));
+
+        let mut library = Library::default();
+        let crossover = Work {
+            crossover_fandoms: vec!["Fandom 2".to_owned()],
+            ..test_work("2", "Crossover")
+        };
+        let works = [
+            test_work("1", "Unreadable"),
+            crossover,
+            test_work("3", "Moved"),
+        ];
+        for work in works {
+            let local_path = PathBuf::from(format!("{}.epub", work.title));
+            library.add_work(&work, None, DownloadFormat::EPUB, &local_path);
+        }
+        // Put somewhere the layout no longer gives
+        let moved_path = device_folder.join("Fandom 1/In Progress/Moved.epub");
+        library.entries[2]
+            .remote_paths
+            .insert("Kobo".to_owned(), vec![moved_path.clone()]);
+
+        for remote_path in [device_folder.join("Fandom 2/Crossover.epub"), moved_path] {
+            let metadata_path = get_koreader_metadata_path(&remote_path);
+            create_dir_all(metadata_path.parent().unwrap()).unwrap();
+            write(metadata_path, METADATA).unwrap();
+        }
+        // A folder can't be read as the metadata, which is reported without stopping the other works
+        create_dir_all(get_koreader_metadata_path(
+            &device_folder.join("Fandom 1/Unreadable.epub"),
+        ))
+        .unwrap();
+
+        assert_eq!(
+            fetch_reading_progress(&mut library, &config, &device, connection.as_ref()),
+            2
+        );
+        assert!(library.entries[0].reading_progress.is_empty());
+        assert!(library.entries[1].reading_progress.contains_key("Kobo"));
+        assert!(library.entries[2].reading_progress.contains_key("Kobo"));
+    }
+}
@@ -0,0 +1,239 @@
+use crate::ao3::common::DownloadFormat;
+use crate::ao3::work::{SeriesLink, Work};
+use crate::config::{Config, Device};
+
+use std::path::PathBuf;
+
+/// `download_folder/filtered_fandom/series/part - title.ext`, as works were always laid out
+pub const DEFAULT_FILENAME: &str = "[{part} - ]{title}";
+pub const DEFAULT_FOLDERS: &str = "{fandom}/{series}";
+
+/// The filename and folder templates in effect for a device, or for local downloads.
+///
+/// Fields go in braces: `{id}`, `{title}`, `{author}`, `{fandom}`, `{series}`, `{series_id}`, `{part}`,
+/// `{rating}` and `{status}`. `{part:3}` pads the part with zeros to 3 digits.
+/// Anything in square brackets is left out when a field in it is empty, e.g. `[{part} - ]` for works not in a series,
+/// and folders that come out empty are skipped. Unknown fields are kept as they are
+pub struct Layout<'a> {
+    filename: &'a str,
+    folders: &'a str,
+}
+
+impl<'a> Layout<'a> {
+    /// The device's own templates, then the global ones, then the defaults.
+    /// Local downloads (`device` of `None`) only use the global filename, they are always kept in series folders
+    pub fn new(config: &'a Config, device: Option<&'a Device>) -> Layout<'a> {
+        let device_layout = device.and_then(|device| device.layout.as_ref());
+        let global_layout = config.layout.as_ref();
+        Layout {
+            filename: device_layout
+                .and_then(|layout| layout.filename.as_deref())
+                .or(global_layout.and_then(|layout| layout.filename.as_deref()))
+                .unwrap_or(DEFAULT_FILENAME),
+            folders: device_layout
+                .and_then(|layout| layout.folders.as_deref())
+                .or(global_layout.and_then(|layout| layout.folders.as_deref()))
+                .unwrap_or(DEFAULT_FOLDERS),
+        }
+    }
+
+    pub fn get_filename(
+        &self,
+        work: &Work,
+        format: DownloadFormat,
+        series_id: Option<&String>,
+    ) -> String {
+        let series_link = series_id.and_then(|id| work.get_series_link(id));
+        format!(
+            "{}.{}",
+            render(self.filename, |field| get_field(
+                work,
+                &work.filtered_fandom,
+                series_link,
+                field
+            )),
+            format.to_string().to_lowercase()
+        )
+    }
+
+    /// Folder relative to a device's `download_folder` for the copy of the work in `fandom`
+    pub fn get_folder(&self, work: &Work, fandom: &str, series_id: Option<&String>) -> PathBuf {
+        let series_link = series_id.and_then(|id| work.get_series_link(id));
+        self.folders
+            .split('/')
+            .map(|folder| render(folder, |field| get_field(work, fandom, series_link, field)))
+            .filter(|folder| !folder.is_empty())
+            .collect()
+    }
+}
+
+/// `None` for fields that don't exist, empty for ones the work doesn't have
+fn get_field(
+    work: &Work,
+    fandom: &str,
+    series_link: Option<&SeriesLink>,
+    field: &str,
+) -> Option<String> {
+    let (name, width) = match field.split_once(':') {
+        Some((name, width)) => (name, Some(width.parse::<usize>().ok()?)),
+        None => (field, None),
+    };
+    let value = match name {
+        "id" => work.id.clone(),
+        "title" => work.title.clone(),
+        "author" => work.author.clone(),
+        "fandom" => fandom.to_owned(),
+        "series" => series_link.map_or_else(String::new, |link| link.series_name.clone()),
+        "series_id" => series_link.map_or_else(String::new, |link| link.series_id.clone()),
+        "part" => series_link.map_or_else(String::new, |link| {
+            format!(
+                "{:0width$}",
+                link.part_in_series,
+                width = width.unwrap_or(0)
+            )
+        }),
+        "rating" => work
+            .rating
+            .map_or_else(String::new, |rating| rating.to_string()),
+        "status" => match work.is_completed {
+            Some(true) => "Complete".to_owned(),
+            Some(false) => "In Progress".to_owned(),
+            None => String::new(),
+        },
+        _ => return None,
+    };
+    Some(value)
+}
+
+fn render(template: &str, get_field: impl Fn(&str) -> Option<String>) -> String {
+    let mut output = String::new();
+    // The text of the square brackets we're in, and whether every field in it had a value so far
+    let mut optional: Option<(String, bool)> = None;
+    let mut chars = template.chars();
+
+    while let Some(character) = chars.next() {
+        match character {
+            '[' if optional.is_none() => optional = Some((String::new(), true)),
+            ']' if optional.is_some() => {
+                let (text, is_complete) = optional.take().unwrap();
+                if is_complete {
+                    output.push_str(&text);
+                }
+            }
+            '{' => {
+                let field: String = chars.by_ref().take_while(|c| *c != '}').collect();
+                let value = get_field(&field);
+                let text = match &mut optional {
+                    Some((text, is_complete)) => {
+                        if value.as_ref().is_some_and(String::is_empty) {
+                            *is_complete = false;
+                        }
+                        text
+                    }
+                    None => &mut output,
+                };
+                match value {
+                    Some(value) => text.push_str(&value),
+                    None => text.push_str(&format!("{{{}}}", field)),
+                }
+            }
+            character => match &mut optional {
+                Some((text, _)) => text.push(character),
+                None => output.push(character),
+            },
+        }
+    }
+
+    // A bracket that was never closed is just text
+    if let Some((text, _)) = optional {
+        output.push('[');
+        output.push_str(&text);
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ao3::common::Rating;
+    use crate::test_utils::{in_series, test_work};
+
+    fn some_work() -> Work {
+        Work {
+            is_completed: Some(false),
+            rating: Some(Rating::Teen),
+            ..in_series(test_work("12", "Some Work"), "345", 2)
+        }
+    }
+
+    #[test]
+    fn defaults_to_the_original_layout() {
+        let config = Config::default();
+        let layout = Layout::new(&config, None);
+        let work = some_work();
+        let series_id = "345".to_owned();
+
+        assert_eq!(
+            layout.get_filename(&work, DownloadFormat::EPUB, Some(&series_id)),
+            "2 - Some Work.epub"
+        );
+        assert_eq!(
+            layout.get_filename(&work, DownloadFormat::AZW3, None),
+            "Some Work.azw3"
+        );
+        assert_eq!(
+            layout.get_folder(&work, "Fandom 1", Some(&series_id)),
+            PathBuf::from("Fandom 1/Some Series")
+        );
+        assert_eq!(
+            layout.get_folder(&work, "Fandom 1", None),
+            PathBuf::from("Fandom 1")
+        );
+    }
+
+    #[test]
+    fn device_templates_override_global_ones() {
+        let config: Config = toml::from_str(
+            r#"
+            download_path = "downloads"
+
+            [[devices]]
+            name = "Kindle"
+            download_folder = "/fanfics"
+            layout = { filename = "[{part:03} ]{title} ({status}, {rating})[ {unknown}]" }
+
+            [fandom_map]
+
+            [fandom_filter]
+
+            [layout]
+            filename = "{title} - {author}"
+            folders = "{author}/[{series} ({series_id})]"
+            "#,
+        )
+        .unwrap();
+        let device = &config.devices[0];
+        let work = some_work();
+        let series_id = "345".to_owned();
+
+        let local_layout = Layout::new(&config, None);
+        assert_eq!(
+            local_layout.get_filename(&work, DownloadFormat::EPUB, None),
+            "Some Work - Some Author.epub"
+        );
+
+        let device_layout = Layout::new(&config, Some(device));
+        assert_eq!(
+            device_layout.get_filename(&work, DownloadFormat::EPUB, Some(&series_id)),
+            "002 Some Work (In Progress, Teen And Up Audiences) {unknown}.epub"
+        );
+        assert_eq!(
+            device_layout.get_folder(&work, "Fandom 1", Some(&series_id)),
+            PathBuf::from("Some Author/Some Series (345)")
+        );
+        assert_eq!(
+            device_layout.get_folder(&work, "Fandom 1", None),
+            PathBuf::from("Some Author")
+        );
+    }
+}
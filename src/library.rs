@@ -0,0 +1,323 @@
+use crate::ao3::common::DownloadFormat;
+use crate::ao3::series::Series;
+use crate::ao3::work::Work;
+use crate::config::{Config, Device};
+use crate::device::get_local_file_path;
+use crate::koreader::ReadingProgress;
+use crate::layout::Layout;
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const LIBRARY_FILENAME: &str = "library.toml";
+
+/// Everything that has been downloaded into `download_path`, so it can be compared against what's on each device
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Library {
+    #[serde(default)]
+    pub entries: Vec<LibraryEntry>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LibraryEntry {
+    pub work: Work,
+    pub series_id: Option<String>,
+    pub formats: Vec<DownloadFormat>,
+    /// Where each format was downloaded to, named with the layout in effect at the time
+    #[serde(default)]
+    pub local_paths: HashMap<DownloadFormat, PathBuf>,
+    /// Where the work was last put on each device, keyed by device name.
+    /// Lets a copy be moved rather than uploaded again when its path changes, e.g. once the work is complete
+    #[serde(default)]
+    pub remote_paths: HashMap<String, Vec<PathBuf>>,
+    /// Devices the work was deliberately deleted from, which sync won't put it back on
+    #[serde(default)]
+    pub removed_from_devices: Vec<String>,
+    /// Progress KOReader has recorded on each device, keyed by device name
+    #[serde(default)]
+    pub reading_progress: HashMap<String, ReadingProgress>,
+    /// When the file last emailed to each `kind = "email"` device was modified, keyed by device name.
+    /// Emails can't be listed like a device's files, so this is how a work is only sent again once it's updated
+    #[serde(default)]
+    pub emailed: HashMap<String, u64>,
+}
+
+impl LibraryEntry {
+    /// A work that hasn't been downloaded in any format yet
+    pub fn new(work: &Work, series_id: Option<&String>) -> LibraryEntry {
+        LibraryEntry {
+            work: work.clone(),
+            series_id: series_id.cloned(),
+            formats: Vec::new(),
+            local_paths: HashMap::new(),
+            remote_paths: HashMap::new(),
+            removed_from_devices: Vec::new(),
+            reading_progress: HashMap::new(),
+            emailed: HashMap::new(),
+        }
+    }
+
+    pub fn get_filename(&self, layout: &Layout, format: DownloadFormat) -> String {
+        layout.get_filename(&self.work, format, self.series_id.as_ref())
+    }
+
+    /// Where the format was downloaded to, works downloaded before that was recorded are looked for where
+    /// the current layout would put them
+    pub fn get_local_path(&self, config: &Config, format: DownloadFormat) -> PathBuf {
+        match self.local_paths.get(&format) {
+            Some(local_path) => local_path.clone(),
+            None => get_local_file_path(&self.work, config, format, self.series_id.as_ref()),
+        }
+    }
+
+    /// The device's most preferred format this work was downloaded in
+    pub fn get_device_format(&self, device: &Device) -> Option<DownloadFormat> {
+        device
+            .get_formats()
+            .into_iter()
+            .find(|format| self.formats.contains(format))
+    }
+
+    /// Path of the file relative to a device's `download_folder`, with the device's `layout`
+    pub fn get_remote_path(&self, layout: &Layout, format: DownloadFormat) -> PathBuf {
+        self.get_remote_path_in(layout, &self.work.filtered_fandom, format)
+    }
+
+    /// `get_remote_path` followed by the copies in any other fandom folders the work goes in
+    pub fn get_remote_paths(&self, layout: &Layout, format: DownloadFormat) -> Vec<PathBuf> {
+        let mut remote_paths: Vec<PathBuf> = Vec::new();
+        for fandom in self.work.get_fandom_folders() {
+            let remote_path = self.get_remote_path_in(layout, fandom, format);
+            if !remote_paths.contains(&remote_path) {
+                remote_paths.push(remote_path);
+            }
+        }
+        remote_paths
+    }
+
+    /// Full paths of every copy on the device, as they were last put there. Works uploaded before that was
+    /// recorded are looked for where the device's layout puts them
+    pub fn get_device_paths(
+        &self,
+        device: &Device,
+        layout: &Layout,
+        format: DownloadFormat,
+    ) -> Vec<PathBuf> {
+        match self.remote_paths.get(&device.name) {
+            Some(remote_paths) if !remote_paths.is_empty() => remote_paths.clone(),
+            _ => self
+                .get_remote_paths(layout, format)
+                .into_iter()
+                .map(|remote_path| Path::new(&device.download_folder).join(remote_path))
+                .collect(),
+        }
+    }
+
+    /// Paths the work was put at on the device that aren't among `remote_paths`, where it goes now
+    pub fn get_renamed_paths(&self, device: &Device, remote_paths: &[PathBuf]) -> Vec<PathBuf> {
+        self.remote_paths
+            .get(&device.name)
+            .into_iter()
+            .flatten()
+            .filter(|path| !remote_paths.contains(path))
+            .cloned()
+            .collect()
+    }
+
+    fn get_remote_path_in(&self, layout: &Layout, fandom: &str, format: DownloadFormat) -> PathBuf {
+        layout
+            .get_folder(&self.work, fandom, self.series_id.as_ref())
+            .join(self.get_filename(layout, format))
+    }
+}
+
+impl Library {
+    pub fn load(config: &Config) -> Result<Library> {
+        let library_path = Path::new(&config.download_path).join(LIBRARY_FILENAME);
+        if !library_path.exists() {
+            return Ok(Library::default());
+        }
+        Ok(toml::from_str(&fs::read_to_string(library_path)?)?)
+    }
+
+    pub fn save(&self, config: &Config) -> Result<()> {
+        let library_path = Path::new(&config.download_path).join(LIBRARY_FILENAME);
+        fs::write(library_path, toml::to_string(self)?)?;
+        Ok(())
+    }
+
+    pub fn add_work(
+        &mut self,
+        work: &Work,
+        series_id: Option<&String>,
+        format: DownloadFormat,
+        local_path: &Path,
+    ) {
+        let existing_entry = self
+            .entries
+            .iter_mut()
+            .find(|entry| entry.work.id == work.id && entry.series_id.as_ref() == series_id);
+
+        match existing_entry {
+            Some(entry) => {
+                entry.work = work.clone();
+                if !entry.formats.contains(&format) {
+                    entry.formats.push(format);
+                }
+                entry.local_paths.insert(format, local_path.to_owned());
+            }
+            None => self.entries.push(LibraryEntry {
+                formats: vec![format],
+                local_paths: HashMap::from([(format, local_path.to_owned())]),
+                ..LibraryEntry::new(work, series_id)
+            }),
+        }
+    }
+
+    /// Records each work in the series with the downloads `Series::download_for_devices` made of it
+    pub fn add_series(&mut self, series: &Series, downloads: &[Vec<(DownloadFormat, PathBuf)>]) {
+        for (work, work_downloads) in series.works.iter().zip(downloads) {
+            for (format, local_path) in work_downloads {
+                self.add_work(work, Some(&series.id), *format, local_path);
+            }
+        }
+    }
+
+    /// Indices of the entries for the works in the series, in the order `add_series` added them
+    pub fn get_series_entries(&self, series_id: &str) -> Vec<usize> {
+        (0..self.entries.len())
+            .filter(|index| self.entries[*index].series_id.as_deref() == Some(series_id))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{in_series, test_work};
+
+    fn some_work() -> Work {
+        in_series(test_work("12", "Some Work"), "345", 2)
+    }
+
+    #[test]
+    fn round_trips_through_toml() {
+        let mut library = Library::default();
+        library.add_work(
+            &some_work(),
+            Some(&"345".to_owned()),
+            DownloadFormat::EPUB,
+            Path::new("Some Work.epub"),
+        );
+        library.add_work(
+            &some_work(),
+            None,
+            DownloadFormat::EPUB,
+            Path::new("Some Work.epub"),
+        );
+        library.entries[1].remote_paths.insert(
+            "Kindle".to_owned(),
+            vec![PathBuf::from("/fanfics/Fandom 1/Some Work.epub")],
+        );
+
+        let loaded: Library = toml::from_str(&toml::to_string(&library).unwrap()).unwrap();
+
+        assert_eq!(loaded.entries.len(), 2);
+        assert_eq!(loaded.entries[0].work.id, "12");
+        assert_eq!(loaded.entries[0].series_id, Some("345".to_owned()));
+        assert_eq!(loaded.entries[1].formats, vec![DownloadFormat::EPUB]);
+        assert_eq!(
+            loaded.entries[1].local_paths[&DownloadFormat::EPUB],
+            Path::new("Some Work.epub")
+        );
+        assert_eq!(
+            loaded.entries[1].remote_paths["Kindle"],
+            vec![PathBuf::from("/fanfics/Fandom 1/Some Work.epub")]
+        );
+    }
+
+    #[test]
+    fn adding_a_format_reuses_the_entry() {
+        let mut library = Library::default();
+        library.add_work(
+            &some_work(),
+            None,
+            DownloadFormat::EPUB,
+            Path::new("Some Work.epub"),
+        );
+        library.add_work(
+            &some_work(),
+            None,
+            DownloadFormat::AZW3,
+            Path::new("Some Work.azw3"),
+        );
+
+        assert_eq!(library.entries.len(), 1);
+        assert_eq!(
+            library.entries[0].formats,
+            vec![DownloadFormat::EPUB, DownloadFormat::AZW3]
+        );
+    }
+
+    #[test]
+    fn remote_path_uses_fandom_and_series_folders() {
+        let mut library = Library::default();
+        library.add_work(
+            &some_work(),
+            Some(&"345".to_owned()),
+            DownloadFormat::EPUB,
+            Path::new("Some Work.epub"),
+        );
+        library.add_work(
+            &some_work(),
+            None,
+            DownloadFormat::EPUB,
+            Path::new("Some Work.epub"),
+        );
+        let config = Config::default();
+        let layout = Layout::new(&config, None);
+
+        assert_eq!(
+            library.entries[0].get_remote_path(&layout, DownloadFormat::EPUB),
+            Path::new("Fandom 1/Some Series/2 - Some Work.epub")
+        );
+        assert_eq!(
+            library.entries[1].get_remote_path(&layout, DownloadFormat::EPUB),
+            Path::new("Fandom 1/Some Work.epub")
+        );
+    }
+
+    #[test]
+    fn device_format_follows_device_preference() {
+        let mut library = Library::default();
+        library.add_work(
+            &some_work(),
+            None,
+            DownloadFormat::EPUB,
+            Path::new("Some Work.epub"),
+        );
+        library.add_work(
+            &some_work(),
+            None,
+            DownloadFormat::AZW3,
+            Path::new("Some Work.azw3"),
+        );
+        let device = |formats: &str| -> Device {
+            toml::from_str(&format!("name = \"Kindle\"\nformats = {}", formats)).unwrap()
+        };
+
+        let entry = &library.entries[0];
+        assert_eq!(
+            entry.get_device_format(&device(r#"["PDF", "AZW3", "EPUB"]"#)),
+            Some(DownloadFormat::AZW3)
+        );
+        assert_eq!(
+            entry.get_device_format(&device("[]")),
+            Some(DownloadFormat::EPUB)
+        );
+        assert_eq!(entry.get_device_format(&device(r#"["PDF"]"#)), None);
+    }
+}
@@ -1,33 +1,485 @@
 mod ao3;
+mod collections;
 mod config;
+mod device;
+mod email;
+mod explain;
+mod filters;
+mod highlights;
+mod koreader;
+mod layout;
+mod library;
+mod reorganize;
+mod retention;
 mod sftp;
+mod suggest;
+mod sync;
+#[cfg(test)]
+mod test_utils;
+mod transport;
+mod webdav;
 
 use ao3::series::Series;
+use ao3::tags::TagResolver;
 use ao3::work::Work;
-use ao3::common::DownloadFormat;
 use ao3::user::User;
-use config::read_config;
+use collections::upload_collections;
+use config::{read_config, Config, Device, DeviceKind, CONFIG_PATH};
+use device::{delete_works, get_remote_file_paths, upload_works};
+use explain::explain_work;
+use filters::{check_download_filters, filter_series};
+use highlights::{export_highlights, ExportFormat};
+use koreader::fetch_reading_progress;
+use layout::Layout;
+use library::{Library, LibraryEntry};
+use reorganize::{apply_reorganize, get_refiled_works, plan_reorganize};
+use suggest::{suggest_fandom_rules, write_suggestions};
+use sync::{apply_sync, plan_email_sync, plan_sync};
+use transport::connect;
 
-use std::env::current_dir;
-use anyhow::{Ok, Result};
+use std::env;
+use std::io::{self, Write};
+use std::path::Path;
+use anyhow::{Error, Result};
 
 fn main() -> Result<()> {
     let config = read_config();
+    let args: Vec<String> = env::args().skip(1).collect();
 
+    match args.first().map(String::as_str) {
+        Some("sync") => sync_devices(&config, &args[1..]),
+        Some("delete") => delete_from_devices(&config, &args[1..]),
+        Some("explain") => explain_folders(&config, &args[1..]),
+        Some("reorganize") => reorganize_devices(&config, &args[1..]),
+        Some("suggest") => suggest_fandom_config(&config),
+        Some("progress") => fetch_progress(&config),
+        Some("highlights") => export_device_highlights(&config, &args[1..]),
+        Some("collections") => generate_device_collections(&config),
+        _ => download_and_upload(&config),
+    }
+}
+
+/// Without a command, downloads the work and series and then uploads every part of the series to each device
+fn download_and_upload(config: &Config) -> Result<()> {
     let user: Option<User> = if let (Some(username), Some(password)) = (&config.ao3_username, &config.ao3_password) {
         Some(User::new(username, password))
     } else {
         None
     };
-    
-    let download_path = current_dir().unwrap().join("downloads");
-    let work = Work::parse_work("12", user.as_ref(), &config).unwrap();
-    let series = Series::parse_series("12345", user.as_ref(), &config).unwrap();
-    let _ = work.download(download_path.clone(), DownloadFormat::EPUB, false, &String::new());
-    let _ = series.download(download_path.clone(), DownloadFormat::EPUB);
+
+    let download_path = Path::new(&config.download_path);
+    let mut library = Library::load(config)?;
+    let mut tag_resolver = TagResolver::load(config)?;
+    let work = Work::parse_work("12", user.as_ref(), config, &mut tag_resolver).unwrap();
+    let mut series =
+        Series::parse_series("12345", user.as_ref(), config, &mut tag_resolver).unwrap();
+    tag_resolver.save(config)?;
+    // Every format some device wants is downloaded once, with each device falling back down its own list
+    let device_formats: Vec<_> = config.devices.iter().map(Device::get_formats).collect();
+    let layout = Layout::new(config, None);
+    match check_download_filters(&work, config) {
+        Ok(()) => {
+            for (format, local_path) in
+                work.download_for_devices(download_path, &device_formats, None, &layout)
+            {
+                library.add_work(&work, None, format, &local_path);
+            }
+        }
+        Err(reason) => println!("Skipping {}: {}", work.title, reason),
+    }
+    for (title, reason) in filter_series(&mut series, config) {
+        println!("Skipping {} in {}: {}", title, series.title, reason);
+    }
+    if let Ok(downloads) = series.download_for_devices(download_path, &device_formats, &layout) {
+        library.add_series(&series, &downloads);
+    }
+    library.save(config)?;
 
     println!("{}", series);
     println!("{}", work);
 
+    let series_entries = library.get_series_entries(&series.id);
+    let reports: Vec<_> = config
+        .devices
+        .iter()
+        .map(|device| upload_works(&mut library, &series_entries, device, config))
+        .collect();
+
+    for report in reports {
+        println!("{}", report);
+    }
+
+    // Where each work was put on each device
+    library.save(config)
+}
+
+/// `sync [--apply] [--delete]`, only prints what would change unless `--apply` is passed
+fn sync_devices(config: &Config, args: &[String]) -> Result<()> {
+    let apply = args.iter().any(|arg| arg == "--apply");
+    let delete_removed = args.iter().any(|arg| arg == "--delete");
+    let mut library = Library::load(config)?;
+
+    let keeps_bookmarked = config.devices.iter().any(|device| {
+        device
+            .retention
+            .as_ref()
+            .is_some_and(|retention| retention.keep_bookmarked.unwrap_or(true))
+    });
+    let bookmarked_work_ids = match (&config.ao3_username, &config.ao3_password) {
+        (Some(username), Some(password)) if keeps_bookmarked => {
+            Some(User::new(username, password).get_bookmarked_work_ids()?)
+        }
+        _ => None,
+    };
+
+    for device in &config.devices {
+        if device.kind == Some(DeviceKind::Email) {
+            let plan = plan_email_sync(&library, config, device);
+            println!("{}", plan);
+            if apply {
+                let indices: Vec<usize> = plan
+                    .missing
+                    .iter()
+                    .chain(&plan.changed)
+                    .map(|(index, _)| *index)
+                    .collect();
+                println!("{}", upload_works(&mut library, &indices, device, config));
+            }
+            continue;
+        }
+
+        let mut connection = match connect(device) {
+            Ok(connection) => connection,
+            Err(error) => {
+                eprintln!("Skipping {}: {}", device.name, error);
+                continue;
+            }
+        };
+
+        let needs_bookmarks = device
+            .retention
+            .as_ref()
+            .is_some_and(|retention| retention.keep_bookmarked.unwrap_or(true));
+        if needs_bookmarks && bookmarked_work_ids.is_none() {
+            eprintln!(
+                "Not removing finished works from {}: keep_bookmarked needs ao3_username and ao3_password",
+                device.name
+            );
+        }
+
+        // Retention needs to know what has been finished since the last sync
+        if device.retention.is_some() && device.uses_koreader.unwrap_or(false) {
+            fetch_reading_progress(&mut library, config, device, connection.as_ref());
+        }
+
+        let plan = match plan_sync(
+            &library,
+            config,
+            device,
+            connection.as_ref(),
+            bookmarked_work_ids.as_ref(),
+        ) {
+            Ok(plan) => plan,
+            Err(error) => {
+                eprintln!("Failed to read the library on {}: {}", device.name, error);
+                continue;
+            }
+        };
+        println!("{}", plan);
+
+        if apply {
+            let report = apply_sync(
+                &plan,
+                &mut library,
+                config,
+                device,
+                &mut connection,
+                delete_removed,
+            );
+            println!("{}", report);
+        }
+    }
+
+    if !apply {
+        println!("Dry run, pass --apply to make these changes");
+    }
+
+    library.save(config)
+}
+
+/// `delete <work|series> <id>`, removes the works from every device but keeps them in the library
+fn delete_from_devices(config: &Config, args: &[String]) -> Result<()> {
+    let (kind, id) = match args {
+        [kind, id] if kind == "work" || kind == "series" => (kind.as_str(), id),
+        _ => return Err(Error::msg("Usage: delete <work|series> <id>")),
+    };
+    let mut library = Library::load(config)?;
+
+    let matching_entries: Vec<usize> = library
+        .entries
+        .iter()
+        .enumerate()
+        .filter(|(_, entry)| match kind {
+            "work" => &entry.work.id == id,
+            _ => entry.series_id.as_ref() == Some(id),
+        })
+        .map(|(index, _)| index)
+        .collect();
+
+    if matching_entries.is_empty() {
+        return Err(Error::msg(format!(
+            "No {} with id {} in the library",
+            kind, id
+        )));
+    }
+
+    for device in &config.devices {
+        let report = delete_works(&mut library, &matching_entries, device, config);
+        println!("{}", report);
+    }
+
+    library.save(config)
+}
+
+/// `explain <work|series> <id>`, shows how the folder each work goes in was chosen.
+/// Works that aren't in the library are fetched from AO3
+fn explain_folders(config: &Config, args: &[String]) -> Result<()> {
+    let (kind, id) = match args {
+        [kind, id] if kind == "work" || kind == "series" => (kind.as_str(), id),
+        _ => return Err(Error::msg("Usage: explain <work|series> <id>")),
+    };
+    let library = Library::load(config)?;
+    let mut tag_resolver = TagResolver::load(config)?;
+
+    let mut entries: Vec<LibraryEntry> = library
+        .entries
+        .into_iter()
+        .filter(|entry| match kind {
+            "work" => &entry.work.id == id,
+            _ => entry.series_id.as_ref() == Some(id),
+        })
+        .collect();
+
+    if entries.is_empty() {
+        let user = if let (Some(username), Some(password)) =
+            (&config.ao3_username, &config.ao3_password)
+        {
+            Some(User::new(username, password))
+        } else {
+            None
+        };
+        entries = match kind {
+            "work" => vec![LibraryEntry::new(
+                &Work::parse_work(id, user.as_ref(), config, &mut tag_resolver)?,
+                None,
+            )],
+            _ => Series::parse_series(id, user.as_ref(), config, &mut tag_resolver)?
+                .works
+                .iter()
+                .map(|work| LibraryEntry::new(work, Some(id)))
+                .collect(),
+        };
+    }
+
+    for entry in &entries {
+        println!("{}\n", explain_work(entry, config, &mut tag_resolver));
+    }
+    tag_resolver.save(config)
+}
+
+/// `suggest`, proposes `fandom_map` and `fandom_filter` entries for fandoms in the library that look like duplicates,
+/// and adds them to the config once confirmed
+fn suggest_fandom_config(config: &Config) -> Result<()> {
+    let library = Library::load(config)?;
+    let suggestions = suggest_fandom_rules(&library, config);
+    if suggestions.is_empty() {
+        println!("No duplicate fandoms found");
+        return Ok(());
+    }
+
+    println!("{}", suggestions);
+    print!("Add these to {}? [y/N] ", CONFIG_PATH);
+    io::stdout().flush()?;
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+
+    if matches!(answer.trim().to_lowercase().as_str(), "y" | "yes") {
+        write_suggestions(&suggestions, Path::new(CONFIG_PATH))?;
+        println!("Added to {}", CONFIG_PATH);
+    }
+    Ok(())
+}
+
+/// `reorganize [--apply]`, moves works on each device into the folders the current fandom rules give them.
+/// Downloads aren't sorted by fandom, so locally only the library changes. Only prints the moves unless `--apply` is passed
+fn reorganize_devices(config: &Config, args: &[String]) -> Result<()> {
+    let apply = args.iter().any(|arg| arg == "--apply");
+    let mut library = Library::load(config)?;
+    let mut tag_resolver = TagResolver::load(config)?;
+    let refiled_works = get_refiled_works(&library, config, &mut tag_resolver);
+    tag_resolver.save(config)?;
+    if refiled_works.is_empty() {
+        println!("Every work is already in the right folder");
+        return Ok(());
+    }
+
+    for device in &config.devices {
+        let connection = match connect(device) {
+            Ok(connection) => connection,
+            Err(error) => {
+                eprintln!("Skipping {}: {}", device.name, error);
+                continue;
+            }
+        };
+
+        let plan = match plan_reorganize(
+            &library,
+            config,
+            &refiled_works,
+            device,
+            connection.as_ref(),
+        ) {
+            Ok(plan) => plan,
+            Err(error) => {
+                eprintln!("Skipping {}: {}", device.name, error);
+                continue;
+            }
+        };
+        println!("{}", plan);
+
+        if apply {
+            let report = apply_reorganize(&plan, device, connection.as_ref());
+            println!("{}", report);
+            for (index, new_paths) in plan.new_paths {
+                if !report
+                    .failed
+                    .iter()
+                    .any(|(failed_index, _, _)| *failed_index == index)
+                {
+                    library.entries[index]
+                        .remote_paths
+                        .insert(device.name.clone(), new_paths);
+                }
+            }
+        }
+    }
+
+    if !apply {
+        return Ok(());
+    }
+    for (index, work) in refiled_works {
+        let entry = &mut library.entries[index];
+        // Devices that weren't reorganized keep where the work still is, so sync moves it there later
+        for device in &config.devices {
+            let Some(download_format) = entry.get_device_format(device) else {
+                continue;
+            };
+            if device.kind != Some(DeviceKind::Email)
+                && !entry.remote_paths.contains_key(&device.name)
+            {
+                let old_paths = get_remote_file_paths(
+                    &entry.work,
+                    device,
+                    config,
+                    download_format,
+                    entry.series_id.as_ref(),
+                );
+                entry.remote_paths.insert(device.name.clone(), old_paths);
+            }
+        }
+        entry.work = work;
+    }
+    library.save(config)
+}
+
+/// `progress`, records how far along each KOReader device is with every work in the library
+fn fetch_progress(config: &Config) -> Result<()> {
+    let mut library = Library::load(config)?;
+
+    for device in config
+        .devices
+        .iter()
+        .filter(|device| device.uses_koreader.unwrap_or(false))
+    {
+        match connect(device) {
+            Ok(connection) => {
+                let num_updated =
+                    fetch_reading_progress(&mut library, config, device, connection.as_ref());
+                println!("{}: read progress for {} works", device.name, num_updated);
+            }
+            Err(error) => eprintln!("Skipping {}: {}", device.name, error),
+        }
+    }
+
+    for entry in &library.entries {
+        for (device_name, progress) in &entry.reading_progress {
+            println!("{} on {}: {}", entry.work.title, device_name, progress);
+        }
+    }
+
+    library.save(config)
+}
+
+/// `highlights [--json] [--comment]`, exports KOReader highlights and notes as Markdown, or JSON with `--json`
+fn export_device_highlights(config: &Config, args: &[String]) -> Result<()> {
+    let export_format = if args.iter().any(|arg| arg == "--json") {
+        ExportFormat::Json
+    } else {
+        ExportFormat::Markdown
+    };
+    let comment_drafts = args.iter().any(|arg| arg == "--comment");
+    let library = Library::load(config)?;
+
+    for device in config
+        .devices
+        .iter()
+        .filter(|device| device.uses_koreader.unwrap_or(false))
+    {
+        let result = connect(device).and_then(|connection| {
+            export_highlights(
+                &library,
+                config,
+                device,
+                connection.as_ref(),
+                export_format,
+                comment_drafts,
+            )
+        });
+        match result {
+            Ok(exported) => {
+                for path in exported {
+                    println!("{}: wrote {}", device.name, path.display());
+                }
+            }
+            Err(error) => eprintln!("Skipping {}: {}", device.name, error),
+        }
+    }
+
+    Ok(())
+}
+
+/// `collections`, writes KOReader collections for each device that has them configured
+fn generate_device_collections(config: &Config) -> Result<()> {
+    let library = Library::load(config)?;
+
+    for device in config
+        .devices
+        .iter()
+        .filter(|device| device.uses_koreader.unwrap_or(false))
+    {
+        let Some(options) = &device.collections else {
+            continue;
+        };
+        let result = connect(device).and_then(|connection| {
+            upload_collections(&library, config, device, connection.as_ref(), options)
+        });
+        match result {
+            Ok(num_collections) => {
+                println!("{}: wrote {} collections", device.name, num_collections)
+            }
+            Err(error) => eprintln!("Skipping {}: {}", device.name, error),
+        }
+    }
+
     Ok(())
 }
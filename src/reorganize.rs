@@ -0,0 +1,515 @@
+use crate::ao3::common::filter_fandoms;
+use crate::ao3::tags::TagResolver;
+use crate::ao3::work::Work;
+use crate::config::{Config, Device};
+use crate::device::{
+    delete_remote_book, get_koreader_metadata_folder, get_remote_file_paths, move_remote_book,
+    remove_empty_folders_on_remote, DeviceError,
+};
+use crate::library::Library;
+use crate::transport::Transport;
+
+use std::path::{Path, PathBuf};
+
+/// How the books on a device need to move to match the current fandom rules
+#[derive(Debug, Default)]
+pub struct ReorganizePlan {
+    pub device: String,
+    /// Library entries (by index) with where their book is now and where it goes
+    pub moves: Vec<(usize, PathBuf, PathBuf)>,
+    /// Copies left over when a work goes in fewer folders than before, e.g. after leaving the "every" crossover policy
+    pub extra_copies: Vec<(usize, PathBuf)>,
+    /// Folders a work now also goes in with no copy to move there, `sync` uploads these
+    pub missing: Vec<(usize, PathBuf)>,
+    /// KOReader metadata left in an old folder without its book, e.g. by an interrupted move, with where it goes
+    pub sidecars: Vec<(usize, PathBuf, PathBuf)>,
+    /// Library entries on the device with every path the work goes at once it's reorganized
+    pub new_paths: Vec<(usize, Vec<PathBuf>)>,
+}
+
+impl std::fmt::Display for ReorganizePlan {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{}: {} to move, {} extra copies, {} to upload with sync, {} leftover metadata folders",
+            self.device,
+            self.moves.len(),
+            self.extra_copies.len(),
+            self.missing.len(),
+            self.sidecars.len()
+        )?;
+        for (_, from, to) in self.moves.iter().chain(&self.sidecars) {
+            write!(f, "\n  > {} -> {}", from.display(), to.display())?;
+        }
+        for (_, path) in &self.extra_copies {
+            write!(f, "\n  - {}", path.display())?;
+        }
+        for (_, path) in &self.missing {
+            write!(f, "\n  + {}", path.display())?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ReorganizeReport {
+    pub device: String,
+    pub moved: Vec<PathBuf>,
+    pub deleted: Vec<PathBuf>,
+    /// Library entries (by index) that are still in their old folder
+    pub failed: Vec<(usize, PathBuf, DeviceError)>,
+}
+
+impl std::fmt::Display for ReorganizeReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{}: moved {}, deleted {}, failed {}",
+            self.device,
+            self.moved.len(),
+            self.deleted.len(),
+            self.failed.len()
+        )?;
+        for (_, path, error) in &self.failed {
+            write!(f, "\n  {}: {}", path.display(), error)?;
+        }
+        Ok(())
+    }
+}
+
+/// Library entries (by index) whose folders differ under the current `fandom_map`, `fandom_filter` and crossover
+/// policy, or that some device has at a path the current layout no longer gives, with the work as it should be
+/// stored now
+pub fn get_refiled_works(
+    library: &Library,
+    config: &Config,
+    resolver: &mut TagResolver,
+) -> Vec<(usize, Work)> {
+    library
+        .entries
+        .iter()
+        .enumerate()
+        .filter_map(|(index, entry)| {
+            let trace = filter_fandoms(&entry.work.fandoms, config, resolver);
+            let mut work = entry.work.clone();
+            work.filtered_fandom = trace.fandom;
+            work.crossover_fandoms = trace.crossover_fandoms;
+            work.mapped_fandoms = trace.mapped_fandoms;
+
+            let is_refiled = work.filtered_fandom != entry.work.filtered_fandom
+                || work.crossover_fandoms != entry.work.crossover_fandoms;
+            let is_renamed = config.devices.iter().any(|device| {
+                let Some(download_format) = entry.get_device_format(device) else {
+                    return false;
+                };
+                entry
+                    .remote_paths
+                    .get(&device.name)
+                    .is_some_and(|uploaded_paths| {
+                        let remote_paths = get_remote_file_paths(
+                            &work,
+                            device,
+                            config,
+                            download_format,
+                            entry.series_id.as_ref(),
+                        );
+                        *uploaded_paths != remote_paths
+                    })
+            });
+            (is_refiled || is_renamed).then_some((index, work))
+        })
+        .collect()
+}
+
+/// Pairs each copy of a refiled work on the device with one of its new paths.
+/// Works that aren't on the device are left for `sync`
+pub fn plan_reorganize(
+    library: &Library,
+    config: &Config,
+    refiled_works: &[(usize, Work)],
+    device: &Device,
+    connection: &dyn Transport,
+) -> Result<ReorganizePlan, DeviceError> {
+    let mut plan = ReorganizePlan {
+        device: device.name.clone(),
+        ..Default::default()
+    };
+
+    for (index, work) in refiled_works {
+        let entry = &library.entries[*index];
+        if entry.removed_from_devices.contains(&device.name) {
+            continue;
+        }
+        let Some(download_format) = entry.get_device_format(device) else {
+            continue;
+        };
+        let series_id = entry.series_id.as_ref();
+        // Where the work was put, which may have been with an earlier layout
+        let old_paths = entry
+            .remote_paths
+            .get(&device.name)
+            .cloned()
+            .unwrap_or_else(|| {
+                get_remote_file_paths(&entry.work, device, config, download_format, series_id)
+            });
+        let all_new_paths = get_remote_file_paths(work, device, config, download_format, series_id);
+
+        let new_paths: Vec<PathBuf> = all_new_paths
+            .iter()
+            .filter(|path| !old_paths.contains(path))
+            .cloned()
+            .collect();
+        let (new_copies, new_targets) = partition_existing(connection, new_paths.iter())?;
+        let (old_copies, old_leftovers) = partition_existing(
+            connection,
+            old_paths
+                .into_iter()
+                .filter(|path| !new_paths.contains(path)),
+        )?;
+        if old_copies.is_empty() && new_copies.is_empty() {
+            continue;
+        }
+        plan.new_paths.push((*index, all_new_paths));
+
+        let mut new_targets = new_targets.into_iter();
+        for old_path in old_copies {
+            match new_targets.next() {
+                Some(new_path) => plan.moves.push((*index, old_path, new_path.clone())),
+                None => plan.extra_copies.push((*index, old_path)),
+            }
+        }
+        plan.missing
+            .extend(new_targets.map(|new_path| (*index, new_path.clone())));
+
+        if device.uses_koreader.unwrap_or(false) {
+            // Books that already made it to a new folder without their metadata
+            let mut bare_copies = Vec::new();
+            for path in new_copies {
+                if !connection.exists(&get_koreader_metadata_folder(path))? {
+                    bare_copies.push(path);
+                }
+            }
+            let mut bare_copies = bare_copies.into_iter();
+            for old_path in old_leftovers {
+                let metadata_folder = get_koreader_metadata_folder(&old_path);
+                if !connection.exists(&metadata_folder)? {
+                    continue;
+                }
+                if let Some(new_path) = bare_copies.next() {
+                    plan.sidecars.push((
+                        *index,
+                        metadata_folder,
+                        get_koreader_metadata_folder(new_path),
+                    ));
+                }
+            }
+        }
+    }
+
+    Ok(plan)
+}
+
+/// Splits the paths into the ones something exists at on the device, and the ones that are free
+fn partition_existing<P: AsRef<Path>>(
+    connection: &dyn Transport,
+    paths: impl Iterator<Item = P>,
+) -> Result<(Vec<P>, Vec<P>), DeviceError> {
+    let mut existing = Vec::new();
+    let mut free = Vec::new();
+    for path in paths {
+        if connection.exists(path.as_ref())? {
+            existing.push(path);
+        } else {
+            free.push(path);
+        }
+    }
+    Ok((existing, free))
+}
+
+/// Moves each book along with its KOReader metadata so reading progress is kept,
+/// then removes the folders left empty
+pub fn apply_reorganize(
+    plan: &ReorganizePlan,
+    device: &Device,
+    connection: &dyn Transport,
+) -> ReorganizeReport {
+    let mut report = ReorganizeReport {
+        device: device.name.clone(),
+        ..Default::default()
+    };
+
+    for (index, from, to) in &plan.moves {
+        match move_remote_book(connection, device, from, to) {
+            Ok(()) => report.moved.push(to.clone()),
+            Err(error) => {
+                eprintln!(
+                    "Failed to move {} on {}: {}",
+                    from.display(),
+                    device.name,
+                    error
+                );
+                report.failed.push((*index, from.clone(), error));
+            }
+        }
+    }
+    for (index, from, to) in &plan.sidecars {
+        let remote_download_folder = Path::new(&device.download_folder);
+        let result = connection
+            .rename(from, to)
+            .and_then(|_| match from.parent() {
+                Some(parent_folder) => remove_empty_folders_on_remote(
+                    connection,
+                    parent_folder,
+                    remote_download_folder,
+                ),
+                None => Ok(()),
+            });
+        match result {
+            Ok(()) => report.moved.push(to.clone()),
+            Err(error) => {
+                eprintln!(
+                    "Failed to move {} on {}: {}",
+                    from.display(),
+                    device.name,
+                    error
+                );
+                report.failed.push((*index, from.clone(), error));
+            }
+        }
+    }
+    for (index, path) in &plan.extra_copies {
+        match delete_remote_book(connection, device, path) {
+            Ok(()) => report.deleted.push(path.clone()),
+            Err(error) => {
+                eprintln!(
+                    "Failed to delete {} from {}: {}",
+                    path.display(),
+                    device.name,
+                    error
+                );
+                report.failed.push((*index, path.clone(), error));
+            }
+        }
+    }
+
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ao3::common::DownloadFormat;
+    use crate::test_utils::test_work;
+    use crate::transport::connect;
+    use std::fs;
+
+    fn refiling_config(device_folder: &Path) -> Config {
+        toml::from_str(&format!(
+            r#"
+            download_path = "downloads"
+
+            [[devices]]
+            name = "Kobo"
+            kind = "local"
+            download_folder = '{}'
+            uses_koreader = true
+
+            [fandom_map]
+            "Fandom 1" = "Fandom 2"
+
+            [fandom_filter]
+            "#,
+            device_folder.display()
+        ))
+        .unwrap()
+    }
+
+    /// A book in `Fandom 1` with KOReader metadata
+    fn add_book(device_folder: &Path) -> PathBuf {
+        let old_path = device_folder.join("Fandom 1").join("Some Work.epub");
+        fs::create_dir_all(old_path.with_extension("sdr")).unwrap();
+        fs::write(&old_path, "not really an epub").unwrap();
+        fs::write(
+            old_path.with_extension("sdr").join("metadata.epub.lua"),
+            "return {}",
+        )
+        .unwrap();
+        old_path
+    }
+
+    fn test_library() -> Library {
+        let mut library = Library::default();
+        library.add_work(
+            &test_work("12", "Some Work"),
+            None,
+            DownloadFormat::EPUB,
+            Path::new("Some Work.epub"),
+        );
+        library
+    }
+
+    #[test]
+    fn moves_books_and_koreader_metadata() {
+        let test_folder = tempfile::tempdir().unwrap();
+        let device_folder = test_folder.path().join("device");
+        let old_path = add_book(&device_folder);
+        let config = refiling_config(&device_folder);
+        let device = &config.devices[0];
+        let library = test_library();
+
+        let refiled_works = get_refiled_works(&library, &config, &mut TagResolver::default());
+        assert_eq!(refiled_works.len(), 1);
+        assert_eq!(refiled_works[0].1.filtered_fandom, "Fandom 2");
+
+        let connection = connect(device).unwrap();
+        let plan = plan_reorganize(
+            &library,
+            &config,
+            &refiled_works,
+            device,
+            connection.as_ref(),
+        )
+        .unwrap();
+        let new_path = device_folder.join("Fandom 2").join("Some Work.epub");
+        assert_eq!(plan.moves, vec![(0, old_path.clone(), new_path.clone())]);
+        assert!(plan.extra_copies.is_empty() && plan.missing.is_empty());
+
+        let report = apply_reorganize(&plan, device, connection.as_ref());
+        assert!(report.failed.is_empty());
+        assert!(new_path.is_file());
+        assert!(new_path
+            .with_extension("sdr")
+            .join("metadata.epub.lua")
+            .is_file());
+        assert!(!device_folder.join("Fandom 1").exists());
+    }
+
+    #[test]
+    fn puts_metadata_back_when_the_book_cant_move() {
+        let test_folder = tempfile::tempdir().unwrap();
+        let device_folder = test_folder.path().join("device");
+        let old_path = add_book(&device_folder);
+        let config = refiling_config(&device_folder);
+        let device = &config.devices[0];
+        let library = test_library();
+
+        let connection = connect(device).unwrap();
+        let refiled_works = get_refiled_works(&library, &config, &mut TagResolver::default());
+        let plan = plan_reorganize(
+            &library,
+            &config,
+            &refiled_works,
+            device,
+            connection.as_ref(),
+        )
+        .unwrap();
+        // Something turns up where the book goes, after the plan was made
+        let new_path = device_folder.join("Fandom 2").join("Some Work.epub");
+        fs::create_dir_all(new_path.join("in the way")).unwrap();
+
+        let report = apply_reorganize(&plan, device, connection.as_ref());
+        assert_eq!(report.failed.len(), 1);
+        assert!(old_path.is_file());
+        assert!(old_path
+            .with_extension("sdr")
+            .join("metadata.epub.lua")
+            .is_file());
+    }
+
+    #[test]
+    fn moves_metadata_left_behind() {
+        let test_folder = tempfile::tempdir().unwrap();
+        let device_folder = test_folder.path().join("device");
+        let old_path = add_book(&device_folder);
+        let new_path = device_folder.join("Fandom 2").join("Some Work.epub");
+        fs::create_dir_all(device_folder.join("Fandom 2")).unwrap();
+        fs::rename(&old_path, &new_path).unwrap();
+        let config = refiling_config(&device_folder);
+        let device = &config.devices[0];
+        let library = test_library();
+
+        let connection = connect(device).unwrap();
+        let refiled_works = get_refiled_works(&library, &config, &mut TagResolver::default());
+        let plan = plan_reorganize(
+            &library,
+            &config,
+            &refiled_works,
+            device,
+            connection.as_ref(),
+        )
+        .unwrap();
+        assert!(plan.moves.is_empty());
+        assert_eq!(
+            plan.sidecars,
+            vec![(
+                0,
+                old_path.with_extension("sdr"),
+                new_path.with_extension("sdr")
+            )]
+        );
+
+        let report = apply_reorganize(&plan, device, connection.as_ref());
+        assert!(report.failed.is_empty());
+        assert!(new_path
+            .with_extension("sdr")
+            .join("metadata.epub.lua")
+            .is_file());
+        assert!(!device_folder.join("Fandom 1").exists());
+    }
+
+    #[test]
+    fn moves_books_the_layout_renamed() {
+        let test_folder = tempfile::tempdir().unwrap();
+        let device_folder = test_folder.path().join("device");
+        let old_path = add_book(&device_folder);
+        let config: Config = toml::from_str(&format!(
+            r#"
+            download_path = "downloads"
+
+            [[devices]]
+            name = "Kobo"
+            kind = "local"
+            download_folder = '{}'
+            uses_koreader = true
+            layout = {{ filename = "{{title}} - {{author}}" }}
+
+            [fandom_map]
+
+            [fandom_filter]
+            "#,
+            device_folder.display()
+        ))
+        .unwrap();
+        let device = &config.devices[0];
+        let mut library = test_library();
+        library.entries[0]
+            .remote_paths
+            .insert("Kobo".to_owned(), vec![old_path.clone()]);
+
+        let refiled_works = get_refiled_works(&library, &config, &mut TagResolver::default());
+        assert_eq!(refiled_works.len(), 1);
+        assert_eq!(refiled_works[0].1.filtered_fandom, "Fandom 1");
+
+        let connection = connect(device).unwrap();
+        let plan = plan_reorganize(
+            &library,
+            &config,
+            &refiled_works,
+            device,
+            connection.as_ref(),
+        )
+        .unwrap();
+        let new_path = device_folder
+            .join("Fandom 1")
+            .join("Some Work - Some Author.epub");
+        assert_eq!(plan.moves, vec![(0, old_path, new_path.clone())]);
+        assert_eq!(plan.new_paths, vec![(0, vec![new_path.clone()])]);
+
+        let report = apply_reorganize(&plan, device, connection.as_ref());
+        assert!(report.failed.is_empty());
+        assert!(new_path
+            .with_extension("sdr")
+            .join("metadata.epub.lua")
+            .is_file());
+    }
+}
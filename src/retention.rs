@@ -0,0 +1,166 @@
+use crate::config::{Device, RetentionPolicy};
+use crate::koreader::ReadingStatus;
+use crate::library::{Library, LibraryEntry};
+
+use std::collections::HashSet;
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// Indexes of library entries the device's retention policy says should come off it.
+/// `bookmarked_work_ids` is `None` when the bookmarks couldn't be read, then nothing expires on a device that keeps them
+pub fn get_expired_entries(
+    library: &Library,
+    device: &Device,
+    bookmarked_work_ids: Option<&HashSet<String>>,
+    now: u64,
+) -> Vec<usize> {
+    let Some(policy) = &device.retention else {
+        return Vec::new();
+    };
+    let empty_set = HashSet::new();
+    let bookmarked_work_ids = match bookmarked_work_ids {
+        Some(bookmarked_work_ids) => bookmarked_work_ids,
+        None if policy.keep_bookmarked.unwrap_or(true) => return Vec::new(),
+        None => &empty_set,
+    };
+
+    let on_device = |entry: &LibraryEntry| !entry.removed_from_devices.contains(&device.name);
+
+    library
+        .entries
+        .iter()
+        .enumerate()
+        .filter(|(_, entry)| on_device(entry))
+        .filter(|(_, entry)| is_expired(entry, device, policy, bookmarked_work_ids, now))
+        .filter(|(_, entry)| {
+            // Every other part of the series still on the device has to be ready to go as well
+            match &entry.series_id {
+                Some(series_id) if policy.keep_series_until_finished.unwrap_or(true) => library
+                    .entries
+                    .iter()
+                    .filter(|part| part.series_id.as_ref() == Some(series_id) && on_device(part))
+                    .all(|part| is_expired(part, device, policy, bookmarked_work_ids, now)),
+                _ => true,
+            }
+        })
+        .map(|(index, _)| index)
+        .collect()
+}
+
+fn is_expired(
+    entry: &LibraryEntry,
+    device: &Device,
+    policy: &RetentionPolicy,
+    bookmarked_work_ids: &HashSet<String>,
+    now: u64,
+) -> bool {
+    if policy.keep_bookmarked.unwrap_or(true) && bookmarked_work_ids.contains(&entry.work.id) {
+        return false;
+    }
+
+    match entry.reading_progress.get(&device.name) {
+        Some(progress) if progress.status == ReadingStatus::Finished => {
+            progress.finished_on.is_some_and(|finished_on| {
+                finished_on + policy.remove_finished_after_days * SECONDS_PER_DAY <= now
+            })
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ao3::common::DownloadFormat;
+    use crate::koreader::ReadingProgress;
+    use crate::test_utils::{in_series, test_device, test_work};
+    use std::path::Path;
+
+    const NOW: u64 = 100 * SECONDS_PER_DAY;
+
+    fn retention_device() -> Device {
+        test_device("[retention]\nremove_finished_after_days = 7")
+    }
+
+    fn add_work(
+        library: &mut Library,
+        id: &str,
+        series_id: Option<&str>,
+        finished_days_ago: Option<u64>,
+    ) {
+        let mut work = test_work(id, &format!("Work {}", id));
+        if let Some(series_id) = series_id {
+            work = in_series(work, series_id, 1);
+        }
+        library.add_work(
+            &work,
+            series_id.map(str::to_owned).as_ref(),
+            DownloadFormat::EPUB,
+            Path::new("Work.epub"),
+        );
+
+        if let Some(days_ago) = finished_days_ago {
+            library.entries.last_mut().unwrap().reading_progress.insert(
+                "Kindle".to_owned(),
+                ReadingProgress {
+                    percent_finished: 1.0,
+                    status: ReadingStatus::Finished,
+                    last_read: None,
+                    finished_on: Some(NOW - days_ago * SECONDS_PER_DAY),
+                },
+            );
+        }
+    }
+
+    #[test]
+    fn removes_works_finished_before_the_grace_period() {
+        let mut library = Library::default();
+        add_work(&mut library, "1", None, Some(10));
+        add_work(&mut library, "2", None, Some(3));
+        add_work(&mut library, "3", None, None);
+
+        assert_eq!(
+            get_expired_entries(&library, &retention_device(), Some(&HashSet::new()), NOW),
+            vec![0]
+        );
+    }
+
+    #[test]
+    fn keeps_bookmarked_works() {
+        let mut library = Library::default();
+        add_work(&mut library, "1", None, Some(10));
+
+        assert!(get_expired_entries(
+            &library,
+            &retention_device(),
+            Some(&HashSet::from(["1".to_owned()])),
+            NOW
+        )
+        .is_empty());
+    }
+
+    #[test]
+    fn keeps_everything_without_bookmarks() {
+        let mut library = Library::default();
+        add_work(&mut library, "1", None, Some(10));
+        let mut device = retention_device();
+
+        assert!(get_expired_entries(&library, &device, None, NOW).is_empty());
+        device.retention.as_mut().unwrap().keep_bookmarked = Some(false);
+        assert_eq!(get_expired_entries(&library, &device, None, NOW), vec![0]);
+    }
+
+    #[test]
+    fn keeps_series_until_every_part_is_finished() {
+        let mut library = Library::default();
+        add_work(&mut library, "1", Some("10"), Some(10));
+        add_work(&mut library, "2", Some("10"), Some(2));
+        add_work(&mut library, "3", Some("20"), Some(10));
+        add_work(&mut library, "4", Some("20"), Some(30));
+
+        assert_eq!(
+            get_expired_entries(&library, &retention_device(), Some(&HashSet::new()), NOW),
+            vec![2, 3]
+        );
+    }
+}
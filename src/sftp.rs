@@ -1,159 +1,536 @@
-use crate::ao3::common::DownloadFormat;
-use crate::ao3::series::Series;
-use crate::ao3::work::Work;
-use crate::config::{Config, Device};
-
-use indicatif::{ProgressBar, ProgressStyle};
-use ssh2::{Session, Sftp};
-use std::path::Path;
+use crate::config::Device;
+use crate::device::{
+    shell_quote, DeviceError, DEFAULT_CONNECT_TIMEOUT_SECS, DEFAULT_IO_TIMEOUT_SECS,
+};
+use crate::transport::{copy_to_remote, RemoteStat, Transport};
+
+use anyhow::{Error, Result};
+use base64::{engine::general_purpose::STANDARD_NO_PAD, Engine};
+use sha2::{Digest, Sha256};
+use ssh2::{
+    CheckResult, ErrorCode, FileStat, HostKeyType, KnownHostFileKind, OpenFlags, OpenType, Session,
+    Sftp,
+};
+use std::path::{Path, PathBuf};
 use std::{
-    cmp::min,
-    fs::File,
-    io::{Read, Write},
-    net::TcpStream,
+    env,
+    fs::create_dir_all,
+    io::{self, BufRead, Read, Seek, SeekFrom, Write},
+    net::{TcpStream, ToSocketAddrs},
+    time::Duration,
 };
 
-pub fn upload_work(
-    work: &Work,
-    device: &Device,
-    config: &Config,
-    download_format: DownloadFormat,
-    existing_sftp: Option<&Sftp>,
-    series_id: Option<&String>,
-) {
-    let using_existing_connection = existing_sftp.is_some();
-
-    let sftp = if using_existing_connection {
-        existing_sftp.unwrap()
-    } else {
-        &create_sftp_connection(device)
-    };
-
-    let filename = work.get_filename(download_format, series_id);
-    let file_path = if let Some(unwrapped_series_id) = series_id {
-        Path::new(&config.download_path)
-            .join(
-                &work
-                    .get_series_link(unwrapped_series_id)
-                    .unwrap()
-                    .series_name,
-            )
-            .join(&filename)
-    } else {
-        Path::new(&config.download_path).join(&filename)
-    };
-
-    let mut file = File::open(file_path).unwrap();
-    let mut file_contents = Vec::new();
-    file.read_to_end(&mut file_contents).unwrap();
-    
-    println!("Starting to upload file: {}", &filename);
-    let file_length = file_contents.len();
-    println!("file is {} bytes", file_length);
-
-    let remote_download_folder = Path::new(&device.download_folder);
-    let remote_file_path = if let Some(unwrapped_series_id) = series_id {
-        remote_download_folder
-            .join(&work.filtered_fandom)
-            .join(
-                &work
-                    .get_series_link(unwrapped_series_id)
-                    .unwrap()
-                    .series_name,
-            )
-            .join(&filename)
-    } else {
-        remote_download_folder
-            .join(&work.filtered_fandom)
-            .join(&filename)
-    };
+// Status codes from libssh2, see https://libssh2.org/libssh2_sftp_last_error.html
+const SFTP_NO_SUCH_FILE: i32 = 2;
+const SFTP_PERMISSION_DENIED: i32 = 3;
+const SFTP_NO_SPACE_ON_FILESYSTEM: i32 = 14;
+const SFTP_QUOTA_EXCEEDED: i32 = 15;
+const SESSION_AUTHENTICATION_FAILED: i32 = -18;
 
-    if !using_existing_connection {
-        create_missing_folders_on_remote(
-            sftp,
-            remote_file_path.parent().unwrap(),
-            remote_download_folder,
-        );
-    }
+const DEFAULT_KEEPALIVE_SECS: u32 = 15;
+const DEFAULT_SSH_PORT: u16 = 22;
 
-    let mut remote_file = sftp.create(Path::new(&remote_file_path)).unwrap();
+/// Keeps the `Session` alive alongside the `Sftp` handle so keepalives can be sent while uploading
+pub struct SftpConnection {
+    pub session: Session,
+    pub sftp: Sftp,
+}
 
-    let chunk_size = 15000;
+impl DeviceError {
+    pub fn from_ssh(error: ssh2::Error, remote_path: &Path) -> DeviceError {
+        match error.code() {
+            ErrorCode::SFTP(SFTP_PERMISSION_DENIED) => {
+                DeviceError::PermissionDenied(remote_path.to_owned())
+            }
+            ErrorCode::SFTP(SFTP_NO_SPACE_ON_FILESYSTEM) | ErrorCode::SFTP(SFTP_QUOTA_EXCEEDED) => {
+                DeviceError::DiskFull(remote_path.to_owned())
+            }
+            _ => DeviceError::Ssh(error),
+        }
+    }
+}
 
-    let pb = ProgressBar::new(file_length.try_into().unwrap());
-    pb.set_style(
-        ProgressStyle::with_template(
-            "{msg} {spinner:.green} [{elapsed_precise}] [{bar:.cyan/blue}] {bytes}/{total_bytes} ({eta})"
-        )
-            .unwrap()
-            .progress_chars("##-")
+/// Returns `None` when the device can't hash the file, e.g. when `sha256sum` isn't installed
+fn hash_remote_file(session: &Session, remote_file_path: &Path) -> Option<String> {
+    let command = format!(
+        "sha256sum {}",
+        shell_quote(&remote_file_path.to_string_lossy())
     );
+    match run_remote_command(session, &command) {
+        Ok((0, output)) => output.split_whitespace().next().map(str::to_owned),
+        _ => None,
+    }
+}
+
+/// Runs a command over its own channel, returning the exit status and everything written to stdout and stderr.
+/// stderr is merged into stdout so a command filling the stderr window can't stall while we wait on stdout
+fn run_remote_command(session: &Session, command: &str) -> Result<(i32, String), DeviceError> {
+    let mut channel = session.channel_session().map_err(DeviceError::Ssh)?;
+    channel
+        .handle_extended_data(ssh2::ExtendedData::Merge)
+        .map_err(DeviceError::Ssh)?;
+    channel.exec(command).map_err(DeviceError::Ssh)?;
+    let mut output = String::new();
+    channel
+        .read_to_string(&mut output)
+        .map_err(DeviceError::Io)?;
+    channel.wait_close().map_err(DeviceError::Ssh)?;
+    let exit_status = channel.exit_status().map_err(DeviceError::Ssh)?;
+    Ok((exit_status, output))
+}
 
-    for (i, chunk) in file_contents.chunks(chunk_size).enumerate() {
-        remote_file.write_all(chunk).unwrap();
-        pb.set_position(min(i * chunk_size, file_length).try_into().unwrap());
+/// Sends keepalives between writes so the session isn't dropped during long uploads, and keeps the SFTP status of
+/// failed writes
+struct SftpWriter<'a> {
+    file: ssh2::File,
+    session: &'a Session,
+}
+
+impl Write for SftpWriter<'_> {
+    fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+        let bytes_written = self.file.write(buffer).map_err(with_sftp_status)?;
+        self.session.keepalive_send().map_err(io::Error::other)?;
+        Ok(bytes_written)
     }
 
-    pb.finish_with_message("Finished writing file\n");
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
 }
 
-pub fn upload_series(
-    series: &Series,
-    device: &Device,
-    config: &Config,
-    download_format: DownloadFormat,
-) {
-    let sftp = create_sftp_connection(device);
-
-    let remote_series_folder = Path::new(&device.download_folder)
-        .join(&series.filtered_fandom)
-        .join(&series.title);
-
-    create_missing_folders_on_remote(
-        &sftp,
-        &remote_series_folder,
-        Path::new(&device.download_folder),
-    );
+/// `ssh2::File` turns a failed write's `ssh2::Error` into an `io::Error` holding only its message, which libssh2 takes
+/// from a fixed table per status. Only writes to an SFTP file come through here, so the status is recovered from it
+/// once and kept as an `ssh2::Error` for `DeviceError::from_io` to match on
+fn with_sftp_status(error: io::Error) -> io::Error {
+    let message = error.to_string();
+    let status = [
+        SFTP_PERMISSION_DENIED,
+        SFTP_NO_SPACE_ON_FILESYSTEM,
+        SFTP_QUOTA_EXCEEDED,
+    ]
+    .into_iter()
+    .map(|code| ssh2::Error::from_errno(ErrorCode::SFTP(code)))
+    .find(|sftp_error| sftp_error.message() == message);
+    match status {
+        Some(sftp_error) => io::Error::new(error.kind(), sftp_error),
+        None => error,
+    }
+}
 
-    for work in &series.works {
-        upload_work(
-            work,
-            device,
-            config,
-            download_format,
-            Some(&sftp),
-            Some(&series.id),
-        );
+fn to_remote_stat(stat: &FileStat) -> RemoteStat {
+    RemoteStat {
+        size: stat.size,
+        mtime: stat.mtime,
+        is_dir: stat.is_dir(),
     }
 }
 
-fn create_missing_folders_on_remote(
-    sftp: &Sftp,
-    path_to_create: &Path,
-    remote_download_folder: &Path,
-) {
-    let remote_download_folder_num_ancestors = remote_download_folder.ancestors().count();
-    let remote_file_ancestors = path_to_create.ancestors().collect::<Vec<&Path>>();
-    let remote_file_iterator = remote_file_ancestors
-        .iter()
-        .rev()
-        .skip(remote_download_folder_num_ancestors);
-
-    for path in remote_file_iterator {
-        if sftp.lstat(path).is_err() {
-            let _ = sftp.mkdir(path, 0); // TODO handle this error
+impl Transport for SftpConnection {
+    fn stat(&self, path: &Path) -> Result<Option<RemoteStat>, DeviceError> {
+        match self.sftp.stat(path) {
+            Ok(stat) => Ok(Some(to_remote_stat(&stat))),
+            Err(error) if error.code() == ErrorCode::SFTP(SFTP_NO_SUCH_FILE) => Ok(None),
+            Err(error) => Err(DeviceError::from_ssh(error, path)),
         }
     }
+
+    fn create_folder(&self, path: &Path) -> Result<(), DeviceError> {
+        self.sftp
+            .mkdir(path, 0o755)
+            .map_err(|error| DeviceError::from_ssh(error, path))
+    }
+
+    fn write_file(
+        &self,
+        path: &Path,
+        mut contents: Box<dyn BufRead + Send>,
+        _length: u64,
+        resume_from: u64,
+    ) -> Result<(), DeviceError> {
+        let mut file = if resume_from == 0 {
+            self.sftp.create(path)
+        } else {
+            self.sftp
+                .open_mode(path, OpenFlags::WRITE, 0o644, OpenType::File)
+        }
+        .map_err(|error| DeviceError::from_ssh(error, path))?;
+        file.seek(SeekFrom::Start(resume_from))
+            .map_err(DeviceError::Io)?;
+        let mut remote_file = SftpWriter {
+            file,
+            session: &self.session,
+        };
+        copy_to_remote(&mut contents, &mut remote_file, path)
+    }
+
+    fn set_modified(&self, path: &Path, modified: u64) -> Result<(), DeviceError> {
+        self.sftp
+            .setstat(
+                path,
+                FileStat {
+                    size: None,
+                    uid: None,
+                    gid: None,
+                    perm: None,
+                    atime: Some(modified),
+                    mtime: Some(modified),
+                },
+            )
+            .map_err(|error| DeviceError::from_ssh(error, path))
+    }
+
+    fn read_to_string(&self, path: &Path) -> Result<Option<String>, DeviceError> {
+        let mut file = match self.sftp.open(path) {
+            Ok(file) => file,
+            Err(error) if error.code() == ErrorCode::SFTP(SFTP_NO_SUCH_FILE) => return Ok(None),
+            Err(error) => return Err(DeviceError::from_ssh(error, path)),
+        };
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)
+            .map_err(DeviceError::Io)?;
+        Ok(Some(contents))
+    }
+
+    fn read_range(&self, path: &Path, offset: u64, length: u64) -> Result<Vec<u8>, DeviceError> {
+        let mut file = self
+            .sftp
+            .open(path)
+            .map_err(|error| DeviceError::from_ssh(error, path))?;
+        file.seek(SeekFrom::Start(offset))
+            .map_err(DeviceError::Io)?;
+        let mut contents = Vec::new();
+        file.take(length)
+            .read_to_end(&mut contents)
+            .map_err(DeviceError::Io)?;
+        Ok(contents)
+    }
+
+    fn read_folder(&self, path: &Path) -> Result<Vec<(PathBuf, RemoteStat)>, DeviceError> {
+        Ok(self
+            .sftp
+            .readdir(path)
+            .map_err(|error| DeviceError::from_ssh(error, path))?
+            .iter()
+            .map(|(path, stat)| (path.clone(), to_remote_stat(stat)))
+            .collect())
+    }
+
+    fn delete_file(&self, path: &Path) -> Result<(), DeviceError> {
+        match self.sftp.unlink(path) {
+            Err(error) if error.code() != ErrorCode::SFTP(SFTP_NO_SUCH_FILE) => {
+                Err(DeviceError::from_ssh(error, path))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn delete_folder(&self, path: &Path) -> Result<(), DeviceError> {
+        self.sftp
+            .rmdir(path)
+            .map_err(|error| DeviceError::from_ssh(error, path))
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<(), DeviceError> {
+        self.sftp
+            .rename(from, to, None)
+            .map_err(|error| DeviceError::from_ssh(error, from))
+    }
+
+    fn hash_file(&self, path: &Path) -> Option<String> {
+        hash_remote_file(&self.session, path)
+    }
+
+    fn run_command(&self, command: &str) -> Result<(i32, String), DeviceError> {
+        run_remote_command(&self.session, command)
+    }
 }
 
-pub fn create_sftp_connection(device: &Device) -> Sftp {
-    let tcp = TcpStream::connect((device.ip.clone(), device.port)).unwrap();
-    let mut session = Session::new().unwrap();
+pub fn create_sftp_connection(device: &Device) -> Result<SftpConnection, DeviceError> {
+    let connect_timeout = Duration::from_secs(
+        device
+            .connect_timeout_secs
+            .unwrap_or(DEFAULT_CONNECT_TIMEOUT_SECS),
+    );
+    let io_timeout = Duration::from_secs(device.io_timeout_secs.unwrap_or(DEFAULT_IO_TIMEOUT_SECS));
+
+    if device.ip.is_empty() {
+        return Err(DeviceError::Unsupported("SFTP without an ip".to_owned()));
+    }
+    let address = (device.ip.as_str(), device.port.unwrap_or(DEFAULT_SSH_PORT))
+        .to_socket_addrs()
+        .map_err(DeviceError::Unreachable)?
+        .next()
+        .ok_or_else(|| {
+            DeviceError::Unreachable(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("could not resolve {}", device.ip),
+            ))
+        })?;
+    let tcp =
+        TcpStream::connect_timeout(&address, connect_timeout).map_err(DeviceError::Unreachable)?;
+    let mut session = Session::new().map_err(DeviceError::Ssh)?;
+    session.set_timeout(io_timeout.as_millis().try_into().unwrap_or(u32::MAX));
+    session.set_keepalive(
+        false,
+        device.keepalive_secs.unwrap_or(DEFAULT_KEEPALIVE_SECS),
+    );
     session.set_tcp_stream(tcp);
-    session.handshake().unwrap();
+    session.handshake().map_err(DeviceError::Ssh)?;
+    verify_host_key(&session, device).map_err(DeviceError::HostKeyRejected)?;
     session
         .userauth_password(&device.username, &device.password)
-        .unwrap();
+        .map_err(|error| match error.code() {
+            ErrorCode::Session(SESSION_AUTHENTICATION_FAILED) => DeviceError::AuthFailed(error),
+            _ => DeviceError::Ssh(error),
+        })?;
     session.set_blocking(true);
-    session.sftp().unwrap()
+    let sftp = session.sftp().map_err(DeviceError::Ssh)?;
+    Ok(SftpConnection { session, sftp })
+}
+
+fn verify_host_key(session: &Session, device: &Device) -> Result<()> {
+    let (host_key, host_key_type) = session
+        .host_key()
+        .ok_or_else(|| Error::msg(format!("{} did not send a host key", device.name)))?;
+    check_host_key(
+        session,
+        device,
+        host_key,
+        host_key_type,
+        &get_known_hosts_path()?,
+    )
+}
+
+/// Checks the key the device sent against its `host_key_fingerprint`, or else the known_hosts file
+fn check_host_key(
+    session: &Session,
+    device: &Device,
+    host_key: &[u8],
+    host_key_type: HostKeyType,
+    known_hosts_path: &Path,
+) -> Result<()> {
+    let fingerprint = host_key_fingerprint(host_key);
+
+    if let Some(pinned_fingerprint) = &device.host_key_fingerprint {
+        if pinned_fingerprint.trim() != fingerprint {
+            return Err(Error::msg(format!(
+                "Host key for {} has changed! Expected {} but the device sent {}. \
+                 Update host_key_fingerprint if you trust the new key",
+                device.name,
+                pinned_fingerprint.trim(),
+                fingerprint
+            )));
+        }
+        return Ok(());
+    }
+
+    let mut known_hosts = session.known_hosts()?;
+    if known_hosts_path.exists() {
+        known_hosts.read_file(known_hosts_path, KnownHostFileKind::OpenSSH)?;
+    }
+
+    let port = device.port.unwrap_or(DEFAULT_SSH_PORT);
+    match known_hosts.check_port(&device.ip, port, host_key) {
+        CheckResult::Match => Ok(()),
+        CheckResult::NotFound if device.trust_on_first_use.unwrap_or(false) => {
+            println!(
+                "Trusting new host key for {} ({}) and saving it to {}",
+                device.name,
+                fingerprint,
+                known_hosts_path.display()
+            );
+            // known_hosts only uses the bare host name for the default port
+            let known_hosts_entry = if port == DEFAULT_SSH_PORT {
+                device.ip.clone()
+            } else {
+                format!("[{}]:{}", device.ip, port)
+            };
+            known_hosts.add(
+                &known_hosts_entry,
+                host_key,
+                &device.name,
+                host_key_type.into(),
+            )?;
+            if let Some(ssh_folder) = known_hosts_path.parent() {
+                create_dir_all(ssh_folder)?;
+            }
+            known_hosts.write_file(known_hosts_path, KnownHostFileKind::OpenSSH)?;
+            Ok(())
+        }
+        CheckResult::NotFound => Err(Error::msg(format!(
+            "Host key for {} ({}) is not in {}. Set trust_on_first_use = true \
+             or host_key_fingerprint = \"{}\" to trust it",
+            device.name,
+            device.ip,
+            known_hosts_path.display(),
+            fingerprint
+        ))),
+        CheckResult::Mismatch => Err(Error::msg(format!(
+            "Host key for {} ({}) has changed! The device sent {} which does not match {}. \
+             Someone could be impersonating the device, remove the old entry if you trust the new key",
+            device.name,
+            device.ip,
+            fingerprint,
+            known_hosts_path.display()
+        ))),
+        CheckResult::Failure => Err(Error::msg(format!(
+            "Failed to check the host key for {}",
+            device.name
+        ))),
+    }
+}
+
+/// Formats the host key the same way as `ssh-keygen -l`, e.g. `SHA256:47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU`
+fn host_key_fingerprint(host_key: &[u8]) -> String {
+    format!(
+        "SHA256:{}",
+        STANDARD_NO_PAD.encode(Sha256::digest(host_key))
+    )
+}
+
+fn get_known_hosts_path() -> Result<PathBuf> {
+    let home = env::var_os("HOME")
+        .or_else(|| env::var_os("USERPROFILE"))
+        .ok_or_else(|| Error::msg("Could not find the home folder to read known_hosts from"))?;
+    Ok(PathBuf::from(home).join(".ssh").join("known_hosts"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::test_device;
+    use std::fs;
+
+    #[test]
+    fn keeps_the_sftp_status_of_failed_writes() {
+        let path = Path::new("/fanfics/Some Work.epub");
+        let write_error = |code| io::Error::from(ssh2::Error::from_errno(ErrorCode::SFTP(code)));
+
+        assert!(matches!(
+            DeviceError::from_io(with_sftp_status(write_error(SFTP_QUOTA_EXCEEDED)), path),
+            DeviceError::DiskFull(_)
+        ));
+        assert!(matches!(
+            DeviceError::from_io(with_sftp_status(write_error(SFTP_PERMISSION_DENIED)), path),
+            DeviceError::PermissionDenied(_)
+        ));
+        // SSH_FX_FAILURE
+        assert!(matches!(
+            DeviceError::from_io(with_sftp_status(write_error(4)), path),
+            DeviceError::Io(_)
+        ));
+    }
+
+    const HOST_KEY: &str = "AAAAC3NzaC1lZDI1NTE5AAAAIOMqqnkVzrm0SdG6UOoqKLsabgH5C9okWi0dh2l9GKJl";
+    const HOST_KEY_FINGERPRINT: &str = "SHA256:+DiY3wvvV6TuJJhbpZisF/zLDA0zPMSvHdkr4UvCOqU";
+    /// The same type of key with the last byte changed
+    const OTHER_HOST_KEY: &str =
+        "AAAAC3NzaC1lZDI1NTE5AAAAIOMqqnkVzrm0SdG6UOoqKLsabgH5C9okWi0dh2l9GKJk";
+
+    fn check_test_host_key(device: &Device, known_hosts_path: &Path) -> Result<()> {
+        let host_key = base64::engine::general_purpose::STANDARD
+            .decode(HOST_KEY)
+            .unwrap();
+        check_host_key(
+            &Session::new().unwrap(),
+            device,
+            &host_key,
+            HostKeyType::Ed25519,
+            known_hosts_path,
+        )
+    }
+
+    #[test]
+    fn fingerprints_host_keys_like_ssh_keygen() {
+        let host_key = base64::engine::general_purpose::STANDARD
+            .decode(HOST_KEY)
+            .unwrap();
+        assert_eq!(host_key_fingerprint(&host_key), HOST_KEY_FINGERPRINT);
+    }
+
+    #[test]
+    fn checks_pinned_fingerprints() {
+        let known_hosts_path = Path::new("/nonexistent/known_hosts");
+        let pinned = test_device(&format!(
+            "host_key_fingerprint = \"{}\"",
+            HOST_KEY_FINGERPRINT
+        ));
+        assert!(check_test_host_key(&pinned, known_hosts_path).is_ok());
+
+        let changed = test_device("host_key_fingerprint = \"SHA256:somethingelse\"");
+        let error = check_test_host_key(&changed, known_hosts_path).unwrap_err();
+        assert!(error.to_string().contains("has changed"));
+    }
+
+    #[test]
+    fn checks_host_keys_against_known_hosts() {
+        let test_folder = tempfile::tempdir().unwrap();
+        let known_hosts_path = test_folder.path().join("known_hosts");
+        let device = test_device("");
+
+        fs::write(
+            &known_hosts_path,
+            format!("127.0.0.1 ssh-ed25519 {}\n", HOST_KEY),
+        )
+        .unwrap();
+        assert!(check_test_host_key(&device, &known_hosts_path).is_ok());
+
+        fs::write(
+            &known_hosts_path,
+            format!("127.0.0.1 ssh-ed25519 {}\n", OTHER_HOST_KEY),
+        )
+        .unwrap();
+        let error = check_test_host_key(&device, &known_hosts_path).unwrap_err();
+        assert!(error.to_string().contains("has changed"));
+    }
+
+    #[test]
+    fn trusts_unknown_hosts_only_when_asked() {
+        let test_folder = tempfile::tempdir().unwrap();
+        let known_hosts_path = test_folder.path().join(".ssh").join("known_hosts");
+        let device = Device {
+            port: Some(2222),
+            ..test_device("")
+        };
+
+        let error = check_test_host_key(&device, &known_hosts_path).unwrap_err();
+        assert!(error.to_string().contains(HOST_KEY_FINGERPRINT));
+        assert!(!known_hosts_path.exists());
+
+        let trusting_device = Device {
+            trust_on_first_use: Some(true),
+            ..device.clone()
+        };
+        assert!(check_test_host_key(&trusting_device, &known_hosts_path).is_ok());
+        assert!(fs::read_to_string(&known_hosts_path)
+            .unwrap()
+            .starts_with(&format!("[127.0.0.1]:2222 ssh-ed25519 {}", HOST_KEY)));
+        // Now known, so it no longer needs trusting
+        assert!(check_test_host_key(&device, &known_hosts_path).is_ok());
+    }
+
+    #[test]
+    fn connects_to_port_22_unless_told_otherwise() {
+        let test_folder = tempfile::tempdir().unwrap();
+        let known_hosts_path = test_folder.path().join("known_hosts");
+        let device = Device {
+            port: None,
+            trust_on_first_use: Some(true),
+            ..test_device("")
+        };
+
+        check_test_host_key(&device, &known_hosts_path).unwrap();
+        assert!(fs::read_to_string(&known_hosts_path)
+            .unwrap()
+            .starts_with(&format!("127.0.0.1 ssh-ed25519 {}", HOST_KEY)));
+
+        let without_ip = Device {
+            ip: String::new(),
+            ..device
+        };
+        assert!(matches!(
+            create_sftp_connection(&without_ip),
+            Err(DeviceError::Unsupported(_))
+        ));
+    }
 }
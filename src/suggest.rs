@@ -0,0 +1,305 @@
+use crate::ao3::common::{map_fandom, normalize_fandom};
+use crate::config::Config;
+use crate::library::Library;
+
+use anyhow::{Error, Result};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+use toml_edit::{Array, DocumentMut};
+
+/// A fandom has to be on at least this many works before it's suggested for `fandom_filter`
+const MIN_FILTER_WORKS: usize = 2;
+/// AO3 ends franchise wide fandom tags with these
+const FANDOM_SUFFIXES: [&str; 3] = [" - All Media Types", " & Related Fandoms", " Series"];
+
+/// Config additions for fandoms in the library that look like duplicates, see `suggest_fandom_rules`
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct FandomSuggestions {
+    pub fandom_map: BTreeMap<String, String>,
+    /// Each fandom on the left is only ever downloaded together with the broader ones on the right
+    pub fandom_filter: BTreeMap<String, Vec<String>>,
+}
+
+impl FandomSuggestions {
+    pub fn is_empty(&self) -> bool {
+        self.fandom_map.is_empty() && self.fandom_filter.is_empty()
+    }
+}
+
+impl std::fmt::Display for FandomSuggestions {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", toml::to_string(self).map_err(|_| std::fmt::Error)?)
+    }
+}
+
+/// The name without AO3's naming conventions, so "逆転裁判 | Gyakuten Saiban | Ace Attorney" and
+/// "Ace Attorney (Video Games)" are both "Ace Attorney"
+fn get_base_name(fandom: &str) -> &str {
+    // Translations are separated by " | " with the English name last
+    let mut name = fandom.rsplit(" | ").next().unwrap().trim();
+    // Media types like " (Video Game)" or " (TV 2010)"
+    if let Some(index) = name.strip_suffix(')').and_then(|name| name.rfind(" (")) {
+        if index > 0 {
+            name = &name[..index];
+        }
+    }
+    for suffix in FANDOM_SUFFIXES {
+        name = name.strip_suffix(suffix).unwrap_or(name);
+    }
+    name.trim()
+}
+
+/// "persona" is a word prefix of "persona 5" and "persona: dancing all night", but not of "personal"
+fn is_word_prefix(prefix: &str, name: &str) -> bool {
+    name.len() > prefix.len()
+        && name.starts_with(prefix)
+        && name[prefix.len()..].starts_with([' ', ':'])
+}
+
+/// Groups the fandoms of every work in the library that `fandom_map` doesn't cover yet by their base name,
+/// joining names that start with another one (e.g. "Persona 5 Royal" and "Persona 5") and folders
+/// `fandom_map` already maps to. Every name in a group is mapped to the shortest one.
+/// A fandom that only ever comes with a broader one that is on more works is suggested for `fandom_filter`
+pub fn suggest_fandom_rules(library: &Library, config: &Config) -> FandomSuggestions {
+    let rules = config.fandom_map.rules();
+    let mut suggestions = FandomSuggestions::default();
+
+    // Each work once, even when it's in the library both on its own and as part of a series
+    let mut seen_works = HashSet::new();
+    let works: Vec<&Vec<String>> = library
+        .entries
+        .iter()
+        .filter(|entry| seen_works.insert(&entry.work.id))
+        .map(|entry| &entry.work.fandoms)
+        .collect();
+
+    let mut folders: BTreeMap<String, &String> = BTreeMap::new();
+    for folder in rules.iter().map(|rule| &rule.fandom) {
+        folders
+            .entry(normalize_fandom(get_base_name(folder)))
+            .or_insert(folder);
+    }
+    let mut groups: BTreeMap<String, Vec<&String>> = BTreeMap::new();
+    for fandom in works.iter().flat_map(|fandoms| fandoms.iter()) {
+        if map_fandom(fandom, rules).is_some() {
+            continue;
+        }
+        let members = groups
+            .entry(normalize_fandom(get_base_name(fandom)))
+            .or_default();
+        if !members.contains(&fandom) {
+            members.push(fandom);
+        }
+    }
+
+    // Prefixes are transitive, so the shortest one is where the whole chain ends up
+    let names: Vec<&String> = folders.keys().chain(groups.keys()).collect();
+    let mut merged_groups: BTreeMap<&String, Vec<&String>> = BTreeMap::new();
+    for (name, members) in &groups {
+        let root = names
+            .iter()
+            .filter(|prefix| is_word_prefix(prefix, name))
+            .min_by_key(|prefix| prefix.len())
+            .copied()
+            .unwrap_or(name);
+        merged_groups.entry(root).or_default().extend(members);
+    }
+
+    for (root, members) in merged_groups {
+        let folder = match folders.get(root) {
+            Some(folder) => folder.to_string(),
+            None if members.len() > 1 => members
+                .iter()
+                .map(|fandom| get_base_name(fandom))
+                .filter(|name| &normalize_fandom(name) == root)
+                .min_by_key(|name| (name.len(), *name))
+                .unwrap()
+                .to_owned(),
+            None => continue,
+        };
+        for fandom in members {
+            if *fandom != folder {
+                suggestions
+                    .fandom_map
+                    .insert(fandom.clone(), folder.clone());
+            }
+        }
+    }
+
+    let get_folder = |fandom: &String| {
+        map_fandom(fandom, rules)
+            .map(|rule| rule.fandom.clone())
+            .or_else(|| suggestions.fandom_map.get(fandom).cloned())
+            .unwrap_or_else(|| fandom.clone())
+    };
+    let mut counts: HashMap<String, usize> = HashMap::new();
+    let mut together: HashMap<(String, String), usize> = HashMap::new();
+    for fandoms in &works {
+        let mut work_folders: Vec<String> = Vec::new();
+        for folder in fandoms.iter().map(get_folder) {
+            if !work_folders.contains(&folder) {
+                work_folders.push(folder);
+            }
+        }
+        for folder in &work_folders {
+            *counts.entry(folder.clone()).or_default() += 1;
+            for other in work_folders.iter().filter(|other| *other != folder) {
+                *together.entry((folder.clone(), other.clone())).or_default() += 1;
+            }
+        }
+    }
+
+    let is_filtered = |fandom: &String, removed: &String| {
+        config
+            .fandom_filter
+            .get(fandom)
+            .is_some_and(|removed_fandoms| removed_fandoms.contains(removed))
+    };
+    for ((fandom, other), count) in together {
+        if count >= MIN_FILTER_WORKS
+            && count == counts[&fandom]
+            && counts[&other] > count
+            && !is_filtered(&fandom, &other)
+            && !is_filtered(&other, &fandom)
+        {
+            suggestions
+                .fandom_filter
+                .entry(fandom)
+                .or_default()
+                .push(other);
+        }
+    }
+    for removed_fandoms in suggestions.fandom_filter.values_mut() {
+        removed_fandoms.sort();
+    }
+
+    suggestions
+}
+
+/// Adds the suggestions to the config file, keeping its comments and layout
+pub fn write_suggestions(suggestions: &FandomSuggestions, config_path: &Path) -> Result<()> {
+    let mut document: DocumentMut = fs::read_to_string(config_path)?.parse()?;
+
+    let fandom_map = document
+        .entry("fandom_map")
+        .or_insert(toml_edit::table())
+        .as_table_like_mut()
+        .ok_or_else(|| Error::msg("fandom_map in the config is not a table"))?;
+    for (fandom, folder) in &suggestions.fandom_map {
+        fandom_map.insert(fandom, toml_edit::value(folder));
+    }
+
+    let fandom_filter = document
+        .entry("fandom_filter")
+        .or_insert(toml_edit::table())
+        .as_table_like_mut()
+        .ok_or_else(|| Error::msg("fandom_filter in the config is not a table"))?;
+    for (fandom, removed_fandoms) in &suggestions.fandom_filter {
+        let removed = fandom_filter
+            .entry(fandom)
+            .or_insert(toml_edit::value(Array::new()))
+            .as_array_mut()
+            .ok_or_else(|| Error::msg(format!("fandom_filter for {} is not a list", fandom)))?;
+        for removed_fandom in removed_fandoms {
+            removed.push(removed_fandom);
+        }
+    }
+
+    fs::write(config_path, document.to_string())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ao3::common::DownloadFormat;
+    use crate::ao3::work::Work;
+
+    fn test_library(works: &[&[&str]]) -> Library {
+        let mut library = Library::default();
+        for (id, fandoms) in works.iter().enumerate() {
+            let work = Work {
+                id: id.to_string(),
+                title: format!("Work {}", id),
+                fandoms: fandoms.iter().map(|fandom| fandom.to_string()).collect(),
+                ..Default::default()
+            };
+            library.add_work(&work, None, DownloadFormat::EPUB, Path::new("Work.epub"));
+        }
+        library
+    }
+
+    #[test]
+    fn suggests_duplicates_and_filters() {
+        let config: Config = toml::from_str(
+            r#"
+            download_path = "downloads"
+            devices = []
+
+            [fandom_map]
+            "Fallout 4" = "Fallout"
+
+            [fandom_filter]
+            "#,
+        )
+        .unwrap();
+        let library = test_library(&[
+            &["Cyberpunk 2077 (Video Game)"],
+            &["Cyberpunk 2077"],
+            &["Persona 5", "Shin Megami Tensei Series"],
+            &["Persona 5 Royal", "Shin Megami Tensei Series"],
+            &["Shin Megami Tensei Series"],
+            &["逆転裁判 | Gyakuten Saiban | Ace Attorney"],
+            &["Ace Attorney (Video Games)"],
+            &["Fallout (Video Games)"],
+            &["Personal Fandom"],
+        ]);
+
+        assert_eq!(
+            suggest_fandom_rules(&library, &config),
+            FandomSuggestions {
+                fandom_map: BTreeMap::from(
+                    [
+                        ("Ace Attorney (Video Games)", "Ace Attorney"),
+                        ("Cyberpunk 2077 (Video Game)", "Cyberpunk 2077"),
+                        ("Fallout (Video Games)", "Fallout"),
+                        ("Persona 5 Royal", "Persona 5"),
+                        ("逆転裁判 | Gyakuten Saiban | Ace Attorney", "Ace Attorney"),
+                    ]
+                    .map(|(fandom, folder)| (fandom.to_owned(), folder.to_owned()))
+                ),
+                fandom_filter: BTreeMap::from([(
+                    "Persona 5".to_owned(),
+                    vec!["Shin Megami Tensei Series".to_owned()]
+                )]),
+            }
+        );
+    }
+
+    #[test]
+    fn writes_suggestions_into_config() {
+        let test_folder = tempfile::tempdir().unwrap();
+        let config_path = test_folder.path().join("config.toml");
+        fs::write(
+            &config_path,
+            "download_path = \"downloads\"\n\n# maps fandom names\n[fandom_map]\n\"Fallout 4\" = \"Fallout\"\n\n[fandom_filter]\n\"Persona\" = [\"Shin Megami Tensei\"]\n",
+        )
+        .unwrap();
+        let suggestions = FandomSuggestions {
+            fandom_map: BTreeMap::from([("Persona 5".to_owned(), "Persona".to_owned())]),
+            fandom_filter: BTreeMap::from([
+                ("Persona".to_owned(), vec!["Megami Tensei".to_owned()]),
+                ("Fallout".to_owned(), vec!["Wasteland".to_owned()]),
+            ]),
+        };
+
+        write_suggestions(&suggestions, &config_path).unwrap();
+
+        assert_eq!(
+            fs::read_to_string(&config_path).unwrap(),
+            "download_path = \"downloads\"\n\n# maps fandom names\n[fandom_map]\n\"Fallout 4\" = \"Fallout\"\n\"Persona 5\" = \"Persona\"\n\n[fandom_filter]\n\"Persona\" = [\"Shin Megami Tensei\", \"Megami Tensei\"]\nFallout = [\"Wasteland\"]\n"
+        );
+    }
+}
@@ -0,0 +1,609 @@
+use crate::ao3::common::DownloadFormat;
+use crate::config::{Config, Device};
+use crate::device::{
+    delete_remote_book, get_modified_secs, list_remote_files, run_device_hooks, upload_work,
+    DeviceError, UploadOutcome, UploadReport,
+};
+use crate::filters::{check_device_filters, FilterReason};
+use crate::layout::Layout;
+use crate::library::Library;
+use crate::retention::get_expired_entries;
+use crate::transport::{RemoteStat, Transport};
+
+use enum_iterator::all;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::metadata;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// What a sync would do to a device, so it can be reviewed before anything is changed
+#[derive(Debug, Default)]
+pub struct SyncPlan {
+    pub device: String,
+    /// Library entries (by index) that aren't on the device yet, with where they'll be uploaded to
+    pub missing: Vec<(usize, PathBuf)>,
+    /// Library entries whose file on the device differs from the local copy
+    pub changed: Vec<(usize, PathBuf)>,
+    /// Library entries on the device at a path the work no longer goes at, e.g. since it was completed,
+    /// with where the copy moves to
+    pub moved: Vec<(usize, PathBuf, PathBuf)>,
+    pub unchanged: Vec<PathBuf>,
+    /// Works on the device that are no longer in the library
+    pub removed: Vec<PathBuf>,
+    /// Library entries the device's retention policy says to take off it
+    pub expired: Vec<(usize, PathBuf)>,
+    /// Library entries whose downloaded file couldn't be found locally
+    pub missing_locally: Vec<PathBuf>,
+    /// Library entries the device's filters keep off it, copies already on the device are left alone
+    pub filtered: Vec<(PathBuf, FilterReason)>,
+}
+
+impl std::fmt::Display for SyncPlan {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{}: {} to upload, {} changed, {} to move, {} unchanged, {} removed locally, {} finished, {} filtered out",
+            self.device,
+            self.missing.len(),
+            self.changed.len(),
+            self.moved.len(),
+            self.unchanged.len(),
+            self.removed.len(),
+            self.expired.len(),
+            self.filtered.len()
+        )?;
+        for (_, path) in &self.missing {
+            write!(f, "\n  + {}", path.display())?;
+        }
+        for (_, path) in &self.changed {
+            write!(f, "\n  ~ {}", path.display())?;
+        }
+        for (_, from, to) in &self.moved {
+            write!(f, "\n  > {} -> {}", from.display(), to.display())?;
+        }
+        for path in &self.removed {
+            write!(f, "\n  - {}", path.display())?;
+        }
+        for (_, path) in &self.expired {
+            write!(f, "\n  x {}", path.display())?;
+        }
+        for (path, reason) in &self.filtered {
+            write!(f, "\n  . {} skipped: {}", path.display(), reason)?;
+        }
+        for path in &self.missing_locally {
+            write!(
+                f,
+                "\n  ! {} is in the library but not on disk",
+                path.display()
+            )?;
+        }
+        Ok(())
+    }
+}
+
+pub fn plan_sync(
+    library: &Library,
+    config: &Config,
+    device: &Device,
+    connection: &dyn Transport,
+    bookmarked_work_ids: Option<&HashSet<String>>,
+) -> Result<SyncPlan, DeviceError> {
+    let remote_download_folder = Path::new(&device.download_folder);
+    // A device that has never been synced won't have the download folder yet
+    let remote_files = if connection.exists(remote_download_folder)? {
+        list_remote_files(connection, remote_download_folder)?
+    } else {
+        Vec::new()
+    };
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_secs());
+    let expired_entries = get_expired_entries(library, device, bookmarked_work_ids, now);
+
+    Ok(compare_library_with_device(
+        library,
+        config,
+        device,
+        remote_files,
+        connection.preserves_mtime(),
+        &expired_entries,
+    ))
+}
+
+/// Sent emails can't be listed or taken back, so an email device's plan only has the works that haven't been emailed
+/// to it yet, or have been updated since, going by what the library recorded when they were sent
+pub fn plan_email_sync(library: &Library, config: &Config, device: &Device) -> SyncPlan {
+    let layout = Layout::new(config, Some(device));
+    let mut plan = SyncPlan {
+        device: device.name.clone(),
+        ..Default::default()
+    };
+
+    for (index, entry) in library.entries.iter().enumerate() {
+        let Some(download_format) = entry.get_device_format(device) else {
+            continue;
+        };
+        if entry.removed_from_devices.contains(&device.name) {
+            continue;
+        }
+        let filename = PathBuf::from(entry.get_filename(&layout, download_format));
+        if let Err(reason) = check_device_filters(&entry.work, device) {
+            plan.filtered.push((filename, reason));
+            continue;
+        }
+
+        let local_path = entry.get_local_path(config, download_format);
+        let Ok(local_metadata) = metadata(&local_path) else {
+            plan.missing_locally.push(local_path);
+            continue;
+        };
+        match entry.emailed.get(&device.name) {
+            None => plan.missing.push((index, filename)),
+            Some(sent_modified) if Some(*sent_modified) == get_modified_secs(&local_metadata) => {
+                plan.unchanged.push(filename)
+            }
+            Some(_) => plan.changed.push((index, filename)),
+        }
+    }
+
+    plan
+}
+
+fn compare_library_with_device(
+    library: &Library,
+    config: &Config,
+    device: &Device,
+    remote_files: Vec<(PathBuf, RemoteStat)>,
+    preserves_mtime: bool,
+    expired_entries: &[usize],
+) -> SyncPlan {
+    let remote_download_folder = Path::new(&device.download_folder);
+    let layout = Layout::new(config, Some(device));
+    let mut remote_files: HashMap<PathBuf, RemoteStat> = remote_files.into_iter().collect();
+    let mut plan = SyncPlan {
+        device: device.name.clone(),
+        ..Default::default()
+    };
+
+    for (index, entry) in library.entries.iter().enumerate() {
+        let Some(download_format) = entry.get_device_format(device) else {
+            continue;
+        };
+        if entry.removed_from_devices.contains(&device.name) {
+            continue;
+        }
+
+        // Crossovers can have a copy in more than one fandom folder
+        let remote_paths: Vec<PathBuf> = entry
+            .get_remote_paths(&layout, download_format)
+            .into_iter()
+            .map(|remote_path| remote_download_folder.join(remote_path))
+            .collect();
+        // Copies put on the device before the layout, or the work's status or rating, changed
+        let renamed_paths = entry.get_renamed_paths(device, &remote_paths);
+        if let Err(reason) = check_device_filters(&entry.work, device) {
+            for remote_path in remote_paths.iter().chain(&renamed_paths) {
+                remote_files.remove(remote_path);
+            }
+            plan.filtered
+                .push((remote_paths.into_iter().next().unwrap(), reason));
+            continue;
+        }
+        if expired_entries.contains(&index) {
+            for remote_path in remote_paths.into_iter().chain(renamed_paths) {
+                if remote_files.remove(&remote_path).is_some() {
+                    plan.expired.push((index, remote_path));
+                }
+            }
+            continue;
+        }
+
+        let local_path = entry.get_local_path(config, download_format);
+        let Ok(local_metadata) = metadata(&local_path) else {
+            for remote_path in remote_paths.iter().chain(&renamed_paths) {
+                remote_files.remove(remote_path);
+            }
+            plan.missing_locally.push(local_path);
+            continue;
+        };
+
+        let renamed_copies: Vec<PathBuf> = renamed_paths
+            .into_iter()
+            .filter(|path| remote_files.contains_key(path))
+            .collect();
+        let mut renamed_copies = renamed_copies.into_iter();
+        for remote_path in remote_paths {
+            match remote_files.remove(&remote_path) {
+                None => match renamed_copies.next() {
+                    Some(renamed_path) => {
+                        remote_files.remove(&renamed_path);
+                        plan.moved.push((index, renamed_path, remote_path));
+                    }
+                    None => plan.missing.push((index, remote_path)),
+                },
+                // Without modification times a matching size is all there is to go on
+                Some(remote_stat)
+                    if remote_stat.size == Some(local_metadata.len())
+                        && (!preserves_mtime
+                            || (remote_stat.mtime.is_some()
+                                && remote_stat.mtime == get_modified_secs(&local_metadata))) =>
+                {
+                    plan.unchanged.push(remote_path)
+                }
+                Some(_) => plan.changed.push((index, remote_path)),
+            }
+        }
+    }
+
+    // Only books we could have put there are candidates for removal, anything else on the device is left alone
+    let book_extensions: Vec<String> = all::<DownloadFormat>()
+        .map(|format| format.to_string().to_lowercase())
+        .collect();
+    plan.removed = remote_files
+        .into_keys()
+        .filter(|path| {
+            path.extension().is_some_and(|extension| {
+                book_extensions.contains(&extension.to_string_lossy().to_string())
+            })
+        })
+        .collect();
+    plan.removed.sort();
+
+    plan
+}
+
+/// The planned paths already carry the format picked for the device, so the filename comes from there
+fn get_work_name(remote_path: &Path) -> String {
+    remote_path
+        .file_name()
+        .map_or_else(String::new, |name| name.to_string_lossy().to_string())
+}
+
+pub fn apply_sync(
+    plan: &SyncPlan,
+    library: &mut Library,
+    config: &Config,
+    device: &Device,
+    connection: &mut Box<dyn Transport>,
+    delete_removed: bool,
+) -> UploadReport {
+    let mut report = UploadReport::new(device);
+    let mut uploaded_entries = HashSet::new();
+
+    let uploads = plan.missing.iter().chain(&plan.changed);
+    let moves = plan.moved.iter().map(|(index, _, to)| (index, to));
+    for (index, remote_path) in uploads.map(|(index, path)| (index, path)).chain(moves) {
+        // upload_work moves renamed copies and takes care of every copy of a crossover at once
+        if !uploaded_entries.insert(*index) {
+            continue;
+        }
+        let entry = &mut library.entries[*index];
+        let work_name = get_work_name(remote_path);
+
+        match upload_work(entry, device, config, Some(connection)) {
+            Ok(UploadOutcome::Uploaded) => report.uploaded.push(work_name),
+            Ok(UploadOutcome::Skipped) => report.skipped.push(work_name),
+            Err(error) => {
+                eprintln!(
+                    "Failed to upload {} to {}: {}",
+                    work_name, device.name, error
+                );
+                report.failed.push((work_name, error));
+            }
+        }
+    }
+
+    // A crossover only counts as removed from the device once every copy of it is gone
+    let mut expired: BTreeMap<usize, Vec<&PathBuf>> = BTreeMap::new();
+    for (index, remote_path) in &plan.expired {
+        expired.entry(*index).or_default().push(remote_path);
+    }
+    for (index, remote_paths) in expired {
+        let mut is_removed = true;
+        for remote_path in remote_paths {
+            let work_name = get_work_name(remote_path);
+            match delete_remote_book(connection.as_ref(), device, remote_path) {
+                Ok(()) => report.deleted.push(work_name),
+                Err(error) => {
+                    eprintln!(
+                        "Failed to delete {} from {}: {}",
+                        work_name, device.name, error
+                    );
+                    report.failed.push((work_name, error));
+                    is_removed = false;
+                }
+            }
+        }
+        if is_removed {
+            let entry = &mut library.entries[index];
+            entry.remote_paths.remove(&device.name);
+            if !entry.removed_from_devices.contains(&device.name) {
+                entry.removed_from_devices.push(device.name.clone());
+            }
+        }
+    }
+
+    if delete_removed {
+        for remote_path in &plan.removed {
+            let work_name = remote_path.display().to_string();
+            match delete_remote_book(connection.as_ref(), device, remote_path) {
+                Ok(()) => report.deleted.push(work_name),
+                Err(error) => {
+                    eprintln!(
+                        "Failed to delete {} from {}: {}",
+                        work_name, device.name, error
+                    );
+                    report.failed.push((work_name, error));
+                }
+            }
+        }
+    }
+
+    run_device_hooks(connection.as_ref(), device, &report);
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ao3::work::Work;
+    use crate::test_utils::{test_config, test_device, test_local_device, test_work};
+    use crate::transport::connect;
+    use std::fs::{create_dir_all, remove_dir, File};
+
+    fn remote_stat(size: u64, mtime: u64) -> RemoteStat {
+        RemoteStat {
+            size: Some(size),
+            mtime: Some(mtime),
+            is_dir: false,
+        }
+    }
+
+    #[test]
+    fn compares_library_with_device() {
+        let test_folder = tempfile::tempdir().unwrap();
+        let download_path = test_folder.path();
+        let config = test_config(download_path);
+        let device = test_device("");
+
+        let mut library = Library::default();
+        for (id, title) in [("1", "Missing"), ("2", "Changed"), ("3", "Unchanged")] {
+            let local_path = download_path.join(format!("{}.epub", title));
+            library.add_work(
+                &test_work(id, title),
+                None,
+                DownloadFormat::EPUB,
+                &local_path,
+            );
+            File::create(&local_path).unwrap();
+        }
+        library.add_work(
+            &test_work("4", "Not Downloaded"),
+            None,
+            DownloadFormat::EPUB,
+            &download_path.join("Not Downloaded.epub"),
+        );
+
+        let unchanged_modified =
+            get_modified_secs(&metadata(download_path.join("Unchanged.epub")).unwrap()).unwrap();
+        let remote_files = vec![
+            (
+                PathBuf::from("/fanfics/Fandom 1/Changed.epub"),
+                remote_stat(10, 0),
+            ),
+            (
+                PathBuf::from("/fanfics/Fandom 1/Unchanged.epub"),
+                remote_stat(0, unchanged_modified),
+            ),
+            (
+                PathBuf::from("/fanfics/Fandom 2/Deleted.epub"),
+                remote_stat(10, 0),
+            ),
+            (PathBuf::from("/fanfics/notes.txt"), remote_stat(10, 0)),
+        ];
+
+        let plan = compare_library_with_device(&library, &config, &device, remote_files, true, &[]);
+
+        assert_eq!(
+            plan.missing,
+            vec![(0, PathBuf::from("/fanfics/Fandom 1/Missing.epub"))]
+        );
+        assert_eq!(
+            plan.changed,
+            vec![(1, PathBuf::from("/fanfics/Fandom 1/Changed.epub"))]
+        );
+        assert_eq!(
+            plan.unchanged,
+            vec![PathBuf::from("/fanfics/Fandom 1/Unchanged.epub")]
+        );
+        assert_eq!(
+            plan.removed,
+            vec![PathBuf::from("/fanfics/Fandom 2/Deleted.epub")]
+        );
+        assert_eq!(
+            plan.missing_locally,
+            vec![download_path.join("Not Downloaded.epub")]
+        );
+    }
+
+    #[test]
+    fn emails_works_not_sent_yet() {
+        let test_folder = tempfile::tempdir().unwrap();
+        let download_path = test_folder.path();
+        let config = test_config(download_path);
+        let device = test_device("kind = \"email\"\nkindle_email = \"someone@kindle.com\"");
+
+        let mut library = Library::default();
+        for (id, title) in [("1", "New"), ("2", "Updated"), ("3", "Sent")] {
+            let local_path = download_path.join(format!("{}.epub", title));
+            library.add_work(
+                &test_work(id, title),
+                None,
+                DownloadFormat::EPUB,
+                &local_path,
+            );
+            File::create(&local_path).unwrap();
+        }
+        let sent_modified =
+            get_modified_secs(&metadata(download_path.join("Sent.epub")).unwrap()).unwrap();
+        library.entries[1]
+            .emailed
+            .insert("Kindle".to_owned(), sent_modified - 60);
+        library.entries[2]
+            .emailed
+            .insert("Kindle".to_owned(), sent_modified);
+
+        let plan = plan_email_sync(&library, &config, &device);
+
+        assert_eq!(plan.missing, vec![(0, PathBuf::from("New.epub"))]);
+        assert_eq!(plan.changed, vec![(1, PathBuf::from("Updated.epub"))]);
+        assert_eq!(plan.unchanged, vec![PathBuf::from("Sent.epub")]);
+    }
+
+    #[test]
+    fn filtered_works_are_left_alone() {
+        let test_folder = tempfile::tempdir().unwrap();
+        let download_path = test_folder.path();
+        let config = test_config(download_path);
+        let mut device = test_device("");
+        device.filters = Some(toml::from_str("blocked_fandoms = [\"Fandom 1\"]").unwrap());
+
+        let mut library = Library::default();
+        for (id, title) in [("1", "Missing"), ("2", "Uploaded Before")] {
+            let local_path = download_path.join(format!("{}.epub", title));
+            library.add_work(
+                &test_work(id, title),
+                None,
+                DownloadFormat::EPUB,
+                &local_path,
+            );
+            File::create(&local_path).unwrap();
+        }
+        let remote_files = vec![(
+            PathBuf::from("/fanfics/Fandom 1/Uploaded Before.epub"),
+            remote_stat(10, 0),
+        )];
+
+        let plan = compare_library_with_device(&library, &config, &device, remote_files, true, &[]);
+
+        assert!(plan.missing.is_empty());
+        assert!(plan.removed.is_empty());
+        assert_eq!(
+            plan.filtered,
+            vec![
+                (
+                    PathBuf::from("/fanfics/Fandom 1/Missing.epub"),
+                    FilterReason::FandomBlocked("Fandom 1".to_owned())
+                ),
+                (
+                    PathBuf::from("/fanfics/Fandom 1/Uploaded Before.epub"),
+                    FilterReason::FandomBlocked("Fandom 1".to_owned())
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn moves_copies_the_layout_renamed() {
+        let test_folder = tempfile::tempdir().unwrap();
+        let download_path = test_folder.path();
+        let config = test_config(download_path);
+        let device = test_device("");
+
+        let mut library = Library::default();
+        let local_path = download_path.join("Some Work.epub");
+        library.add_work(
+            &test_work("12", "Some Work"),
+            None,
+            DownloadFormat::EPUB,
+            &local_path,
+        );
+        File::create(&local_path).unwrap();
+        let old_path = PathBuf::from("/fanfics/Fandom 1/In Progress/Some Work.epub");
+        library.entries[0]
+            .remote_paths
+            .insert("Kindle".to_owned(), vec![old_path.clone()]);
+        let remote_files = vec![(old_path.clone(), remote_stat(0, 0))];
+
+        let plan = compare_library_with_device(&library, &config, &device, remote_files, true, &[]);
+
+        assert_eq!(
+            plan.moved,
+            vec![(
+                0,
+                old_path,
+                PathBuf::from("/fanfics/Fandom 1/Some Work.epub")
+            )]
+        );
+        assert!(plan.missing.is_empty());
+        assert!(plan.removed.is_empty());
+    }
+
+    #[test]
+    fn removes_expired_crossovers_once_every_copy_is_gone() {
+        let test_folder = tempfile::tempdir().unwrap();
+        let download_path = test_folder.path().join("downloads");
+        let config = test_config(&download_path);
+        let device_folder = test_folder.path().join("device");
+        let device = test_local_device(&device_folder, "");
+        let mut connection = connect(&device).unwrap();
+
+        let mut library = Library::default();
+        let crossover = Work {
+            crossover_fandoms: vec!["Fandom 2".to_owned()],
+            ..test_work("12", "Some Work")
+        };
+        library.add_work(
+            &crossover,
+            None,
+            DownloadFormat::EPUB,
+            &download_path.join("Some Work.epub"),
+        );
+        let copies = vec![
+            device_folder.join("Fandom 1/Some Work.epub"),
+            device_folder.join("Fandom 2/Some Work.epub"),
+        ];
+        library.entries[0]
+            .remote_paths
+            .insert("Kobo".to_owned(), copies.clone());
+        create_dir_all(device_folder.join("Fandom 1")).unwrap();
+        File::create(&copies[0]).unwrap();
+        // A folder where the second copy should be can't be deleted as a book
+        create_dir_all(&copies[1]).unwrap();
+        let plan = SyncPlan {
+            expired: copies.iter().map(|copy| (0, copy.clone())).collect(),
+            ..Default::default()
+        };
+
+        let report = apply_sync(
+            &plan,
+            &mut library,
+            &config,
+            &device,
+            &mut connection,
+            false,
+        );
+        assert_eq!(report.deleted, vec!["Some Work.epub".to_owned()]);
+        assert_eq!(report.failed.len(), 1);
+        assert!(library.entries[0].removed_from_devices.is_empty());
+        assert!(library.entries[0].remote_paths.contains_key("Kobo"));
+
+        remove_dir(&copies[1]).unwrap();
+        File::create(&copies[1]).unwrap();
+        let report = apply_sync(
+            &plan,
+            &mut library,
+            &config,
+            &device,
+            &mut connection,
+            false,
+        );
+        assert_eq!(report.deleted.len(), 2);
+        assert_eq!(
+            library.entries[0].removed_from_devices,
+            vec!["Kobo".to_owned()]
+        );
+        assert!(!library.entries[0].remote_paths.contains_key("Kobo"));
+    }
+}
@@ -0,0 +1,69 @@
+//! Fixtures shared by the tests of every module
+
+use crate::ao3::work::{SeriesLink, Work};
+use crate::config::{Config, Device};
+
+use std::fs;
+use std::path::Path;
+
+/// A work by "Some Author" in "Fandom 1", not in any series
+pub fn test_work(id: &str, title: &str) -> Work {
+    Work {
+        id: id.to_owned(),
+        title: title.to_owned(),
+        author: "Some Author".to_owned(),
+        fandoms: vec!["Fandom 1".to_owned()],
+        filtered_fandom: "Fandom 1".to_owned(),
+        ..Default::default()
+    }
+}
+
+/// Adds the work to "Some Series" as part `part_in_series`
+pub fn in_series(mut work: Work, series_id: &str, part_in_series: u8) -> Work {
+    work.series.insert(
+        series_id.to_owned(),
+        SeriesLink {
+            series_id: series_id.to_owned(),
+            series_name: "Some Series".to_owned(),
+            part_in_series,
+        },
+    );
+    work
+}
+
+/// An SFTP device called "Kindle" that keeps books in `/fanfics`, with `extra_toml` added to its table
+pub fn test_device(extra_toml: &str) -> Device {
+    toml::from_str(&format!(
+        r#"
+        name = "Kindle"
+        ip = "127.0.0.1"
+        port = 22
+        username = "root"
+        password = "root"
+        download_folder = "/fanfics"
+        {}
+        "#,
+        extra_toml
+    ))
+    .unwrap()
+}
+
+/// A device mounted at `download_folder`, with its `mount_marker` created next to it
+pub fn test_local_device(download_folder: &Path, extra_toml: &str) -> Device {
+    let mount_marker = download_folder.with_file_name(".kobo");
+    fs::create_dir_all(&mount_marker).unwrap();
+    toml::from_str(&format!(
+        "name = \"Kobo\"\nkind = \"local\"\ndownload_folder = '{}'\nmount_marker = '{}'\n{}",
+        download_folder.display(),
+        mount_marker.display(),
+        extra_toml
+    ))
+    .unwrap()
+}
+
+pub fn test_config(download_path: &Path) -> Config {
+    Config {
+        download_path: download_path.to_string_lossy().to_string(),
+        ..Default::default()
+    }
+}
@@ -0,0 +1,284 @@
+use crate::config::{Device, DeviceKind};
+use crate::device::{get_modified_secs, DeviceError};
+use crate::sftp::create_sftp_connection;
+use crate::webdav::create_webdav_connection;
+
+use sha2::{Digest, Sha256};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::{Duration, UNIX_EPOCH};
+
+/// What a device's file listing tells us about each entry
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct RemoteStat {
+    pub size: Option<u64>,
+    /// Unix timestamp in whole seconds
+    pub mtime: Option<u64>,
+    pub is_dir: bool,
+}
+
+/// The file operations uploading, syncing and deleting need, so they work the same over SFTP or on a mounted device
+pub trait Transport {
+    /// `None` if nothing exists at `path`
+    fn stat(&self, path: &Path) -> Result<Option<RemoteStat>, DeviceError>;
+    fn exists(&self, path: &Path) -> Result<bool, DeviceError> {
+        Ok(self.stat(path)?.is_some())
+    }
+    /// Creates a single folder, its parent has to exist already
+    fn create_folder(&self, path: &Path) -> Result<(), DeviceError>;
+    /// Writes the `length` bytes of `contents` into the file from `resume_from`, truncating it when starting from 0
+    fn write_file(
+        &self,
+        path: &Path,
+        contents: Box<dyn BufRead + Send>,
+        length: u64,
+        resume_from: u64,
+    ) -> Result<(), DeviceError>;
+    fn set_modified(&self, path: &Path, modified: u64) -> Result<(), DeviceError>;
+    /// `None` if the file doesn't exist
+    fn read_to_string(&self, path: &Path) -> Result<Option<String>, DeviceError>;
+    /// Up to `length` bytes from `offset`, fewer if the file ends first
+    fn read_range(&self, path: &Path, offset: u64, length: u64) -> Result<Vec<u8>, DeviceError>;
+    /// The entries directly inside `path`
+    fn read_folder(&self, path: &Path) -> Result<Vec<(PathBuf, RemoteStat)>, DeviceError>;
+    /// Succeeds if the file is already gone
+    fn delete_file(&self, path: &Path) -> Result<(), DeviceError>;
+    /// Removes an empty folder
+    fn delete_folder(&self, path: &Path) -> Result<(), DeviceError>;
+    /// Moves a file or folder, nothing may exist at `to` and its parent has to exist already
+    fn rename(&self, from: &Path, to: &Path) -> Result<(), DeviceError>;
+    /// sha256 of the file as lowercase hex, `None` when the device can't hash it
+    fn hash_file(&self, path: &Path) -> Option<String>;
+    /// Runs a shell command where the device's files live, returning the exit status and its output
+    fn run_command(&self, command: &str) -> Result<(i32, String), DeviceError>;
+    /// Whether an interrupted upload can carry on from the end of the partial file
+    fn supports_resume(&self) -> bool {
+        true
+    }
+    /// Whether `set_modified` sticks, so a matching modification time shows a file is unchanged.
+    /// Without it a matching size has to do, unless `verify_checksum` is on
+    fn preserves_mtime(&self) -> bool {
+        true
+    }
+}
+
+pub fn connect(device: &Device) -> Result<Box<dyn Transport>, DeviceError> {
+    match device.kind.unwrap_or_default() {
+        DeviceKind::Sftp => Ok(Box::new(create_sftp_connection(device)?)),
+        DeviceKind::WebDav => Ok(Box::new(create_webdav_connection(device)?)),
+        // Works can only be sent one way, there is nothing to list or delete
+        DeviceKind::Email => Err(DeviceError::Unsupported(
+            "reading files from an email device".to_owned(),
+        )),
+        DeviceKind::Local => {
+            let root = PathBuf::from(&device.download_folder);
+            check_mounted(&root, device.mount_marker.as_deref())?;
+            Ok(Box::new(LocalTransport { root }))
+        }
+    }
+}
+
+/// Writes `contents` in the chunks it's buffered in, so `upload_buffer_size` decides how much goes in each write
+pub fn copy_to_remote(
+    contents: &mut dyn BufRead,
+    remote_file: &mut dyn Write,
+    remote_path: &Path,
+) -> Result<(), DeviceError> {
+    loop {
+        let chunk = contents.fill_buf().map_err(DeviceError::Io)?;
+        if chunk.is_empty() {
+            break;
+        }
+        let chunk_length = chunk.len();
+        remote_file
+            .write_all(chunk)
+            .map_err(|error| DeviceError::from_io(error, remote_path))?;
+        contents.consume(chunk_length);
+    }
+    remote_file
+        .flush()
+        .map_err(|error| DeviceError::from_io(error, remote_path))
+}
+
+/// An unplugged device leaves nothing, or an empty mount point, behind.
+/// Without a `mount_marker` the folder has to be a mount point or have something in it
+fn check_mounted(root: &Path, mount_marker: Option<&str>) -> Result<(), DeviceError> {
+    let is_mounted = match mount_marker {
+        Some(mount_marker) => Path::new(mount_marker).exists(),
+        None => {
+            root.is_dir()
+                && (is_mount_point(root)
+                    || fs::read_dir(root).is_ok_and(|mut entries| entries.next().is_some()))
+        }
+    };
+    if is_mounted {
+        return Ok(());
+    }
+    Err(DeviceError::Unreachable(io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("{} is not mounted", root.display()),
+    )))
+}
+
+/// Whether `folder` is on a different filesystem than the folder it's in
+#[cfg(unix)]
+fn is_mount_point(folder: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+
+    match (fs::metadata(folder), fs::metadata(folder.join(".."))) {
+        (Ok(folder), Ok(parent)) => folder.dev() != parent.dev(),
+        _ => false,
+    }
+}
+
+#[cfg(not(unix))]
+fn is_mount_point(_folder: &Path) -> bool {
+    false
+}
+
+/// A device mounted as a folder on this machine, e.g. a Kobo plugged in over USB or a synced folder
+pub struct LocalTransport {
+    root: PathBuf,
+}
+
+impl Transport for LocalTransport {
+    fn stat(&self, path: &Path) -> Result<Option<RemoteStat>, DeviceError> {
+        match fs::metadata(path) {
+            Ok(metadata) => Ok(Some(RemoteStat {
+                size: Some(metadata.len()),
+                mtime: get_modified_secs(&metadata),
+                is_dir: metadata.is_dir(),
+            })),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(error) => Err(DeviceError::from_io(error, path)),
+        }
+    }
+
+    fn create_folder(&self, path: &Path) -> Result<(), DeviceError> {
+        fs::create_dir(path).map_err(|error| DeviceError::from_io(error, path))
+    }
+
+    fn write_file(
+        &self,
+        path: &Path,
+        mut contents: Box<dyn BufRead + Send>,
+        _length: u64,
+        resume_from: u64,
+    ) -> Result<(), DeviceError> {
+        let mut file = if resume_from == 0 {
+            File::create(path)
+        } else {
+            OpenOptions::new().write(true).open(path)
+        }
+        .map_err(|error| DeviceError::from_io(error, path))?;
+        file.seek(SeekFrom::Start(resume_from))
+            .map_err(DeviceError::Io)?;
+        copy_to_remote(&mut contents, &mut file, path)
+    }
+
+    fn set_modified(&self, path: &Path, modified: u64) -> Result<(), DeviceError> {
+        File::options()
+            .write(true)
+            .open(path)
+            .and_then(|file| file.set_modified(UNIX_EPOCH + Duration::from_secs(modified)))
+            .map_err(|error| DeviceError::from_io(error, path))
+    }
+
+    fn read_to_string(&self, path: &Path) -> Result<Option<String>, DeviceError> {
+        match fs::read_to_string(path) {
+            Ok(contents) => Ok(Some(contents)),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(error) => Err(DeviceError::from_io(error, path)),
+        }
+    }
+
+    fn read_range(&self, path: &Path, offset: u64, length: u64) -> Result<Vec<u8>, DeviceError> {
+        let mut file = File::open(path).map_err(|error| DeviceError::from_io(error, path))?;
+        file.seek(SeekFrom::Start(offset))
+            .map_err(DeviceError::Io)?;
+        let mut contents = Vec::new();
+        file.take(length)
+            .read_to_end(&mut contents)
+            .map_err(DeviceError::Io)?;
+        Ok(contents)
+    }
+
+    fn read_folder(&self, path: &Path) -> Result<Vec<(PathBuf, RemoteStat)>, DeviceError> {
+        let mut entries = Vec::new();
+        for entry in fs::read_dir(path).map_err(|error| DeviceError::from_io(error, path))? {
+            let entry_path = entry.map_err(DeviceError::Io)?.path();
+            if let Some(stat) = self.stat(&entry_path)? {
+                entries.push((entry_path, stat));
+            }
+        }
+        Ok(entries)
+    }
+
+    fn delete_file(&self, path: &Path) -> Result<(), DeviceError> {
+        match fs::remove_file(path) {
+            Err(error) if error.kind() != io::ErrorKind::NotFound => {
+                Err(DeviceError::from_io(error, path))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn delete_folder(&self, path: &Path) -> Result<(), DeviceError> {
+        fs::remove_dir(path).map_err(|error| DeviceError::from_io(error, path))
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<(), DeviceError> {
+        fs::rename(from, to).map_err(|error| DeviceError::from_io(error, from))
+    }
+
+    fn hash_file(&self, path: &Path) -> Option<String> {
+        let mut hasher = Sha256::new();
+        io::copy(&mut File::open(path).ok()?, &mut hasher).ok()?;
+        Some(format!("{:x}", hasher.finalize()))
+    }
+
+    fn run_command(&self, command: &str) -> Result<(i32, String), DeviceError> {
+        let output = Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .current_dir(&self.root)
+            .output()
+            .map_err(DeviceError::Io)?;
+        let mut combined_output = String::from_utf8_lossy(&output.stdout).to_string();
+        combined_output.push_str(&String::from_utf8_lossy(&output.stderr));
+        Ok((output.status.code().unwrap_or(-1), combined_output))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::test_local_device;
+
+    #[test]
+    fn refuses_empty_mount_points() {
+        let test_folder = tempfile::tempdir().unwrap();
+        let device_folder = test_folder.path().join("device");
+        fs::create_dir_all(&device_folder).unwrap();
+        let mut device = test_local_device(&device_folder, "");
+        device.mount_marker = None;
+
+        assert!(matches!(connect(&device), Err(DeviceError::Unreachable(_))));
+        fs::create_dir(device_folder.join("Fandom 1")).unwrap();
+        assert!(connect(&device).is_ok());
+    }
+
+    #[test]
+    fn needs_the_mount_marker() {
+        let test_folder = tempfile::tempdir().unwrap();
+        let device_folder = test_folder.path().join("device");
+        let device = test_local_device(&device_folder, "");
+        assert!(connect(&device).is_ok());
+
+        fs::remove_dir(device.mount_marker.as_ref().unwrap()).unwrap();
+        fs::create_dir_all(device_folder.join("Fandom 1")).unwrap();
+        assert!(matches!(connect(&device), Err(DeviceError::Unreachable(_))));
+    }
+}
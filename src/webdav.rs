@@ -0,0 +1,704 @@
+use crate::config::Device;
+use crate::device::{DeviceError, DEFAULT_CONNECT_TIMEOUT_SECS, DEFAULT_IO_TIMEOUT_SECS};
+use crate::koreader::parse_date;
+use crate::transport::{RemoteStat, Transport};
+
+use reqwest::blocking::{Body, Client, RequestBuilder, Response};
+use reqwest::header::RANGE;
+use reqwest::{Method, StatusCode, Url};
+use sha2::{Digest, Sha256};
+use std::io::{self, BufRead};
+use std::path::{Component, Path, PathBuf};
+use std::time::Duration;
+
+const PROPFIND_BODY: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<d:propfind xmlns:d="DAV:">
+  <d:prop><d:resourcetype/><d:getcontentlength/><d:getlastmodified/></d:prop>
+</d:propfind>"#;
+
+/// A WebDAV share, with device paths relative to `base_url`
+pub struct WebDavConnection {
+    client: Client,
+    base_url: Url,
+    username: String,
+    password: String,
+}
+
+pub fn create_webdav_connection(device: &Device) -> Result<WebDavConnection, DeviceError> {
+    let base_url = device
+        .url
+        .as_deref()
+        .ok_or_else(|| DeviceError::Unsupported("WebDAV without a url".to_owned()))
+        .and_then(|url| {
+            Url::parse(url).map_err(|error| DeviceError::Unsupported(error.to_string()))
+        })?;
+    let client = Client::builder()
+        .connect_timeout(Duration::from_secs(
+            device
+                .connect_timeout_secs
+                .unwrap_or(DEFAULT_CONNECT_TIMEOUT_SECS),
+        ))
+        .timeout(Duration::from_secs(
+            device.io_timeout_secs.unwrap_or(DEFAULT_IO_TIMEOUT_SECS),
+        ))
+        .build()
+        .map_err(DeviceError::Http)?;
+
+    let connection = WebDavConnection {
+        client,
+        base_url,
+        username: device.username.clone(),
+        password: device.password.clone(),
+    };
+    // Fail early on a wrong url or password instead of on the first upload
+    connection.propfind(Path::new("/"), 0)?;
+    Ok(connection)
+}
+
+impl WebDavConnection {
+    fn url(&self, path: &Path) -> Url {
+        let mut url = self.base_url.clone();
+        if let Ok(mut segments) = url.path_segments_mut() {
+            segments.pop_if_empty();
+            for component in path.components() {
+                if let Component::Normal(segment) = component {
+                    segments.push(&segment.to_string_lossy());
+                }
+            }
+        }
+        url
+    }
+
+    fn request(&self, method: Method, path: &Path) -> RequestBuilder {
+        self.client
+            .request(method, self.url(path))
+            .basic_auth(&self.username, Some(&self.password))
+    }
+
+    fn send(&self, request: RequestBuilder, path: &Path) -> Result<Response, DeviceError> {
+        let response = request.send().map_err(DeviceError::Http)?;
+        if response.status().is_success() {
+            Ok(response)
+        } else {
+            Err(status_error(response.status(), path))
+        }
+    }
+
+    fn propfind(&self, path: &Path, depth: u8) -> Result<Vec<(PathBuf, RemoteStat)>, DeviceError> {
+        let request = self
+            .request(Method::from_bytes(b"PROPFIND").unwrap(), path)
+            .header("Depth", depth.to_string())
+            .header("Content-Type", "application/xml")
+            .body(PROPFIND_BODY);
+        let body = self
+            .send(request, path)?
+            .text()
+            .map_err(DeviceError::Http)?;
+        parse_propfind(&body, self.base_url.path())
+            .map_err(|error| DeviceError::Io(io::Error::new(io::ErrorKind::InvalidData, error)))
+    }
+
+    fn get(&self, path: &Path) -> Result<Option<Vec<u8>>, DeviceError> {
+        match self.send(self.request(Method::GET, path), path) {
+            Ok(response) => Ok(Some(response.bytes().map_err(DeviceError::Http)?.to_vec())),
+            Err(DeviceError::HttpStatus(404, _)) => Ok(None),
+            Err(error) => Err(error),
+        }
+    }
+}
+
+fn status_error(status: StatusCode, path: &Path) -> DeviceError {
+    match status {
+        StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => {
+            DeviceError::PermissionDenied(path.to_owned())
+        }
+        StatusCode::INSUFFICIENT_STORAGE => DeviceError::DiskFull(path.to_owned()),
+        _ => DeviceError::HttpStatus(status.as_u16(), path.to_owned()),
+    }
+}
+
+impl Transport for WebDavConnection {
+    fn stat(&self, path: &Path) -> Result<Option<RemoteStat>, DeviceError> {
+        match self.propfind(path, 0) {
+            Ok(entries) => Ok(entries.into_iter().next().map(|(_, stat)| stat)),
+            Err(DeviceError::HttpStatus(404, _)) => Ok(None),
+            Err(error) => Err(error),
+        }
+    }
+
+    fn create_folder(&self, path: &Path) -> Result<(), DeviceError> {
+        self.send(
+            self.request(Method::from_bytes(b"MKCOL").unwrap(), path),
+            path,
+        )
+        .map(|_| ())
+    }
+
+    /// Streams `contents` as the body of a single `PUT`, WebDAV can't append to a file
+    fn write_file(
+        &self,
+        path: &Path,
+        contents: Box<dyn BufRead + Send>,
+        length: u64,
+        resume_from: u64,
+    ) -> Result<(), DeviceError> {
+        if resume_from != 0 {
+            return Err(DeviceError::Unsupported("resuming uploads".to_owned()));
+        }
+        let request = self
+            .request(Method::PUT, path)
+            .body(Body::sized(contents, length));
+        self.send(request, path).map(|_| ())
+    }
+
+    /// Most servers treat the modification time as read only and refuse to set it, see `preserves_mtime`
+    fn set_modified(&self, path: &Path, modified: u64) -> Result<(), DeviceError> {
+        let body = format!(
+            r#"<?xml version="1.0" encoding="utf-8"?>
+<d:propertyupdate xmlns:d="DAV:">
+  <d:set><d:prop><d:getlastmodified>{}</d:getlastmodified></d:prop></d:set>
+</d:propertyupdate>"#,
+            format_http_date(modified)
+        );
+        let request = self
+            .request(Method::from_bytes(b"PROPPATCH").unwrap(), path)
+            .header("Content-Type", "application/xml")
+            .body(body);
+        let response = self.send(request, path)?;
+        // A 207 has a status for each property, which is where servers refuse to set getlastmodified
+        if response.status() != StatusCode::MULTI_STATUS {
+            return Ok(());
+        }
+        let body = response.text().map_err(DeviceError::Http)?;
+        match get_proppatch_failure(&body)
+            .map_err(|error| DeviceError::Io(io::Error::new(io::ErrorKind::InvalidData, error)))?
+        {
+            Some(status) => Err(status_error(status, path)),
+            None => Ok(()),
+        }
+    }
+
+    fn read_to_string(&self, path: &Path) -> Result<Option<String>, DeviceError> {
+        Ok(self
+            .get(path)?
+            .map(|contents| String::from_utf8_lossy(&contents).to_string()))
+    }
+
+    fn read_range(&self, path: &Path, offset: u64, length: u64) -> Result<Vec<u8>, DeviceError> {
+        if length == 0 {
+            return Ok(Vec::new());
+        }
+        let request = self
+            .request(Method::GET, path)
+            .header(RANGE, format!("bytes={}-{}", offset, offset + length - 1));
+        let response = self.send(request, path)?;
+        // Servers without range support send the whole file
+        let is_partial = response.status() == StatusCode::PARTIAL_CONTENT;
+        let contents = response.bytes().map_err(DeviceError::Http)?;
+        if is_partial {
+            return Ok(contents.to_vec());
+        }
+        Ok(contents
+            .iter()
+            .skip(offset as usize)
+            .take(length as usize)
+            .copied()
+            .collect())
+    }
+
+    fn read_folder(&self, path: &Path) -> Result<Vec<(PathBuf, RemoteStat)>, DeviceError> {
+        // The folder itself is listed alongside its contents
+        Ok(self
+            .propfind(path, 1)?
+            .into_iter()
+            .filter(|(entry_path, _)| entry_path != path)
+            .collect())
+    }
+
+    fn delete_file(&self, path: &Path) -> Result<(), DeviceError> {
+        match self.send(self.request(Method::DELETE, path), path) {
+            Ok(_) | Err(DeviceError::HttpStatus(404, _)) => Ok(()),
+            Err(error) => Err(error),
+        }
+    }
+
+    fn delete_folder(&self, path: &Path) -> Result<(), DeviceError> {
+        self.send(self.request(Method::DELETE, path), path)
+            .map(|_| ())
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<(), DeviceError> {
+        let request = self
+            .request(Method::from_bytes(b"MOVE").unwrap(), from)
+            .header("Destination", self.url(to).as_str())
+            .header("Overwrite", "F");
+        self.send(request, from).map(|_| ())
+    }
+
+    fn hash_file(&self, path: &Path) -> Option<String> {
+        let mut response = self.send(self.request(Method::GET, path), path).ok()?;
+        let mut hasher = Sha256::new();
+        io::copy(&mut response, &mut hasher).ok()?;
+        Some(format!("{:x}", hasher.finalize()))
+    }
+
+    fn run_command(&self, _command: &str) -> Result<(i32, String), DeviceError> {
+        Err(DeviceError::Unsupported("running commands".to_owned()))
+    }
+
+    fn supports_resume(&self) -> bool {
+        false
+    }
+
+    /// Servers set getlastmodified themselves whenever a file is written
+    fn preserves_mtime(&self) -> bool {
+        false
+    }
+}
+
+/// Reads a `207 Multi-Status` response into device paths, relative to the share at `base_path`
+fn parse_propfind(body: &str, base_path: &str) -> Result<Vec<(PathBuf, RemoteStat)>, String> {
+    let document = roxmltree::Document::parse(body).map_err(|error| error.to_string())?;
+    let base_path = percent_decode(base_path);
+    let base_path = base_path.trim_end_matches('/');
+
+    let mut entries = Vec::new();
+    for response in document.descendants().filter(|node| {
+        node.tag_name().name() == "response" && node.tag_name().namespace() == Some("DAV:")
+    }) {
+        let Some(href) = find_dav_element(response, "href").and_then(|href| href.text()) else {
+            continue;
+        };
+        // Servers send either an absolute path or a full url
+        let href = match Url::parse(href.trim()) {
+            Ok(url) => url.path().to_owned(),
+            Err(_) => href.trim().to_owned(),
+        };
+        let href = percent_decode(&href);
+        let path = href.strip_prefix(base_path).unwrap_or(&href);
+        let path = match path.trim_end_matches('/') {
+            "" => "/",
+            path => path,
+        };
+
+        entries.push((
+            PathBuf::from(path),
+            RemoteStat {
+                size: find_dav_element(response, "getcontentlength")
+                    .and_then(|length| length.text())
+                    .and_then(|length| length.trim().parse().ok()),
+                mtime: find_dav_element(response, "getlastmodified")
+                    .and_then(|modified| modified.text())
+                    .and_then(parse_http_date),
+                is_dir: find_dav_element(response, "collection").is_some(),
+            },
+        ));
+    }
+    Ok(entries)
+}
+
+/// The status of the first property a PROPPATCH response says wasn't set, `None` when all of them were
+fn get_proppatch_failure(body: &str) -> Result<Option<StatusCode>, String> {
+    let document = roxmltree::Document::parse(body).map_err(|error| error.to_string())?;
+    Ok(document
+        .descendants()
+        .filter(|node| {
+            node.tag_name().name() == "propstat" && node.tag_name().namespace() == Some("DAV:")
+        })
+        .filter_map(|propstat| {
+            find_dav_element(propstat, "status").and_then(|status| status.text())
+        })
+        // "HTTP/1.1 403 Forbidden"
+        .filter_map(|status| status.split_whitespace().nth(1)?.parse().ok())
+        .filter_map(|code| StatusCode::from_u16(code).ok())
+        .find(|status| !status.is_success()))
+}
+
+fn find_dav_element<'a, 'input>(
+    node: roxmltree::Node<'a, 'input>,
+    name: &str,
+) -> Option<roxmltree::Node<'a, 'input>> {
+    node.descendants().find(|child| {
+        child.tag_name().name() == name && child.tag_name().namespace() == Some("DAV:")
+    })
+}
+
+fn percent_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+    while index < bytes.len() {
+        let hex = (bytes[index] == b'%')
+            .then(|| text.get(index + 1..index + 3))
+            .flatten()
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match hex {
+            Some(byte) => {
+                decoded.push(byte);
+                index += 3;
+            }
+            None => {
+                decoded.push(bytes[index]);
+                index += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).to_string()
+}
+
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// Parses dates like `Sun, 06 Nov 1994 08:49:37 GMT`, the only format WebDAV allows for `getlastmodified`
+fn parse_http_date(date: &str) -> Option<u64> {
+    let parts: Vec<&str> = date.split_whitespace().collect();
+    let [_, day, month, year, time, "GMT"] = parts.as_slice() else {
+        return None;
+    };
+    let month = MONTHS.iter().position(|name| name == month)? + 1;
+    let midnight = parse_date(&format!("{}-{}-{}", year, month, day))?;
+
+    let mut time = time.splitn(3, ':').map(|part| part.parse::<u64>().ok());
+    let (hours, minutes, seconds) = (time.next()??, time.next()??, time.next()??);
+    Some(midnight + hours * 3600 + minutes * 60 + seconds)
+}
+
+fn format_http_date(timestamp: u64) -> String {
+    const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    let days = timestamp / 86400;
+    let seconds_of_day = timestamp % 86400;
+
+    // Civil date from days since the epoch, see http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let days_shifted = days as i64 + 719468;
+    let era = days_shifted.div_euclid(146097);
+    let day_of_era = days_shifted - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        WEEKDAYS[(days % 7) as usize],
+        day,
+        MONTHS[(month - 1) as usize],
+        year,
+        seconds_of_day / 3600,
+        seconds_of_day % 3600 / 60,
+        seconds_of_day % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ao3::common::DownloadFormat;
+    use crate::config::Config;
+    use crate::device::{delete_remote_book, get_modified_secs, upload_work, UploadOutcome};
+    use crate::library::Library;
+    use crate::test_utils::{test_config, test_device, test_work};
+    use crate::transport::connect;
+    use std::collections::HashMap;
+    use std::fs;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    /// Serves the folder at `root` as a share at `/dav/`, one request per connection, logging each request's method,
+    /// path and length. Paths with "broken" in them fail
+    fn webdav_server(listener: TcpListener, root: PathBuf) -> Arc<Mutex<Vec<String>>> {
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let log = requests.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut reader = BufReader::new(stream.unwrap());
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+                let mut headers = HashMap::new();
+                let mut line = String::new();
+                while reader.read_line(&mut line).unwrap() > 0 && line != "\r\n" {
+                    if let Some((name, value)) = line.split_once(':') {
+                        headers.insert(name.to_lowercase(), value.trim().to_owned());
+                    }
+                    line.clear();
+                }
+                let length = headers
+                    .get("content-length")
+                    .map_or(0, |length| length.parse().unwrap());
+                let mut body = vec![0; length];
+                reader.read_exact(&mut body).unwrap();
+
+                let mut parts = request_line.split_whitespace();
+                let (method, href) = (parts.next().unwrap(), parts.next().unwrap());
+                log.lock()
+                    .unwrap()
+                    .push(format!("{} {} {}", method, href, length));
+                let path = root.join(
+                    percent_decode(href)
+                        .trim_start_matches("/dav")
+                        .trim_start_matches('/'),
+                );
+                let depth = headers.get("depth").map(String::as_str);
+                let (status, response) = if href.contains("broken") {
+                    ("500 Internal Server Error", Vec::new())
+                } else {
+                    respond(method, &path, &root, depth, body)
+                };
+
+                let stream = reader.get_mut();
+                write!(
+                    stream,
+                    "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    status,
+                    response.len()
+                )
+                .unwrap();
+                stream.write_all(&response).unwrap();
+            }
+        });
+        requests
+    }
+
+    fn respond(
+        method: &str,
+        path: &Path,
+        root: &Path,
+        depth: Option<&str>,
+        body: Vec<u8>,
+    ) -> (&'static str, Vec<u8>) {
+        match method {
+            "PROPFIND" if !path.exists() => ("404 Not Found", Vec::new()),
+            "PROPFIND" => {
+                let mut paths = vec![path.to_owned()];
+                if depth == Some("1") && path.is_dir() {
+                    paths.extend(
+                        fs::read_dir(path)
+                            .unwrap()
+                            .map(|entry| entry.unwrap().path()),
+                    );
+                }
+                let mut multistatus =
+                    r#"<?xml version="1.0"?><d:multistatus xmlns:d="DAV:">"#.to_owned();
+                for path in paths {
+                    let metadata = fs::metadata(&path).unwrap();
+                    let href = path.strip_prefix(root).unwrap().to_string_lossy();
+                    multistatus.push_str(&format!(
+                        "<d:response><d:href>/dav/{}</d:href><d:propstat><d:prop>{}<d:getlastmodified>{}</d:getlastmodified></d:prop></d:propstat></d:response>",
+                        href.replace(' ', "%20"),
+                        if metadata.is_dir() {
+                            "<d:resourcetype><d:collection/></d:resourcetype>".to_owned()
+                        } else {
+                            format!("<d:getcontentlength>{}</d:getcontentlength>", metadata.len())
+                        },
+                        format_http_date(get_modified_secs(&metadata).unwrap())
+                    ));
+                }
+                multistatus.push_str("</d:multistatus>");
+                ("207 Multi-Status", multistatus.into_bytes())
+            }
+            "MKCOL" => {
+                fs::create_dir(path).unwrap();
+                ("201 Created", Vec::new())
+            }
+            "PUT" => {
+                fs::write(path, body).unwrap();
+                ("201 Created", Vec::new())
+            }
+            "GET" => match fs::read(path) {
+                Ok(contents) => ("200 OK", contents),
+                Err(_) => ("404 Not Found", Vec::new()),
+            },
+            "DELETE" if path.is_dir() => {
+                fs::remove_dir_all(path).unwrap();
+                ("204 No Content", Vec::new())
+            }
+            "DELETE" => match fs::remove_file(path) {
+                Ok(()) => ("204 No Content", Vec::new()),
+                Err(_) => ("404 Not Found", Vec::new()),
+            },
+            // Like most servers, the modification time can't be set
+            "PROPPATCH" => ("207 Multi-Status", REFUSED_PROPPATCH.as_bytes().to_vec()),
+            _ => ("405 Method Not Allowed", Vec::new()),
+        }
+    }
+
+    const REFUSED_PROPPATCH: &str = r#"<?xml version="1.0"?><d:multistatus xmlns:d="DAV:"><d:response><d:href>/dav/</d:href><d:propstat><d:prop><d:getlastmodified/></d:prop><d:status>HTTP/1.1 403 Forbidden</d:status></d:propstat></d:response></d:multistatus>"#;
+
+    /// A share served by `webdav_server` with "fanfics" in it, a downloaded work and a device for the share
+    fn webdav_fixture(
+        test_folder: &Path,
+        extra_toml: &str,
+    ) -> (Arc<Mutex<Vec<String>>>, Config, Device, Library) {
+        let download_path = test_folder.join("downloads");
+        let share = test_folder.join("share");
+        fs::create_dir_all(&download_path).unwrap();
+        fs::create_dir_all(share.join("fanfics")).unwrap();
+        fs::write(download_path.join("Some Work.epub"), "not really an epub").unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let requests = webdav_server(listener, share);
+
+        let device = test_device(&format!(
+            "kind = \"webdav\"\nurl = \"http://127.0.0.1:{}/dav/\"\n{}",
+            port, extra_toml
+        ));
+        let mut library = Library::default();
+        library.add_work(
+            &test_work("12", "Some Work"),
+            None,
+            DownloadFormat::EPUB,
+            &download_path.join("Some Work.epub"),
+        );
+        (requests, test_config(&download_path), device, library)
+    }
+
+    const MULTISTATUS: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<d:multistatus xmlns:d="DAV:">
+  <d:response>
+    <d:href>/dav/fanfics/</d:href>
+    <d:propstat><d:prop><d:resourcetype><d:collection/></d:resourcetype></d:prop></d:propstat>
+  </d:response>
+  <d:response>
+    <d:href>http://nas.local/dav/fanfics/Fandom%201/Some%20Work.epub</d:href>
+    <d:propstat>
+      <d:prop>
+        <d:resourcetype/>
+        <d:getcontentlength>1234</d:getcontentlength>
+        <d:getlastmodified>Thu, 02 May 2024 08:30:15 GMT</d:getlastmodified>
+      </d:prop>
+    </d:propstat>
+  </d:response>
+</d:multistatus>"#;
+
+    #[test]
+    fn parses_propfind_responses() {
+        assert_eq!(
+            parse_propfind(MULTISTATUS, "/dav/").unwrap(),
+            vec![
+                (
+                    PathBuf::from("/fanfics"),
+                    RemoteStat {
+                        size: None,
+                        mtime: None,
+                        is_dir: true,
+                    }
+                ),
+                (
+                    PathBuf::from("/fanfics/Fandom 1/Some Work.epub"),
+                    RemoteStat {
+                        size: Some(1234),
+                        mtime: Some(1714638615),
+                        is_dir: false,
+                    }
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn round_trips_http_dates() {
+        assert_eq!(
+            parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT"),
+            Some(784111777)
+        );
+        assert_eq!(format_http_date(784111777), "Sun, 06 Nov 1994 08:49:37 GMT");
+        assert_eq!(format_http_date(0), "Thu, 01 Jan 1970 00:00:00 GMT");
+    }
+
+    #[test]
+    fn builds_urls_below_the_share() {
+        let connection = WebDavConnection {
+            client: Client::new(),
+            base_url: Url::parse("https://nas.local/dav/").unwrap(),
+            username: String::new(),
+            password: String::new(),
+        };
+
+        assert_eq!(
+            connection
+                .url(Path::new("/fanfics/Fandom 1/#1.epub"))
+                .as_str(),
+            "https://nas.local/dav/fanfics/Fandom%201/%231.epub"
+        );
+    }
+
+    #[test]
+    fn uploads_to_and_deletes_from_a_webdav_share() {
+        let test_folder = tempfile::tempdir().unwrap();
+        let share = test_folder.path().join("share");
+        let (requests, config, device, mut library) =
+            webdav_fixture(test_folder.path(), "verify_checksum = true");
+        let mut upload = || upload_work(&mut library.entries[0], &device, &config, None);
+
+        assert_eq!(upload().unwrap(), UploadOutcome::Uploaded);
+        assert_eq!(upload().unwrap(), UploadOutcome::Skipped);
+        assert_eq!(
+            fs::read_to_string(share.join("fanfics/Fandom 1/Some Work.epub")).unwrap(),
+            "not really an epub"
+        );
+        // Sent as one request of the file's length
+        assert_eq!(
+            requests
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|request| request.starts_with("PUT"))
+                .collect::<Vec<_>>(),
+            vec!["PUT /dav/fanfics/Fandom%201/Some%20Work.epub 18"]
+        );
+
+        let connection = connect(&device).unwrap();
+        assert_eq!(
+            connection.stat(Path::new("/fanfics/Missing.epub")).unwrap(),
+            None
+        );
+        assert!(matches!(
+            connection.stat(Path::new("/fanfics/broken.epub")),
+            Err(DeviceError::HttpStatus(500, _))
+        ));
+
+        let remote_path = Path::new("/fanfics/Fandom 1/Some Work.epub");
+        delete_remote_book(connection.as_ref(), &device, remote_path).unwrap();
+        assert!(fs::read_dir(share.join("fanfics"))
+            .unwrap()
+            .next()
+            .is_none());
+    }
+
+    #[test]
+    fn compares_sizes_when_the_server_refuses_modification_times() {
+        let test_folder = tempfile::tempdir().unwrap();
+        let (requests, config, device, mut library) = webdav_fixture(test_folder.path(), "");
+        let mut upload = || upload_work(&mut library.entries[0], &device, &config, None);
+
+        assert_eq!(upload().unwrap(), UploadOutcome::Uploaded);
+        assert_eq!(upload().unwrap(), UploadOutcome::Skipped);
+        fs::write(
+            test_folder.path().join("downloads/Some Work.epub"),
+            "a longer fake epub than before",
+        )
+        .unwrap();
+        assert_eq!(upload().unwrap(), UploadOutcome::Uploaded);
+        // Not even asked to keep them
+        assert!(!requests
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|request| request.starts_with("PROPPATCH")));
+
+        let connection = connect(&device).unwrap();
+        assert!(matches!(
+            connection.set_modified(Path::new("/fanfics/Fandom 1/Some Work.epub"), 0),
+            Err(DeviceError::PermissionDenied(_))
+        ));
+    }
+}